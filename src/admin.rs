@@ -10,7 +10,7 @@ use std::sync::atomic::Ordering;
 use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::time::Instant;
 
-use crate::config::{get_config, reload_config, VERSION};
+use crate::config::{get_config, get_file_config, reload_config, set_config, VERSION};
 use crate::errors::Error;
 use crate::messages::*;
 use crate::pool::ClientServerMap;
@@ -63,13 +63,33 @@ where
             trace!("UNBAN");
             unban(stream, query_parts).await
         }
+        "KILL" => {
+            trace!("KILL");
+            kill(stream, query_parts).await
+        }
         "RELOAD" => {
             trace!("RELOAD");
             reload(stream, client_server_map).await
         }
+        "RECONNECT" => {
+            trace!("RECONNECT");
+            reconnect(stream, query_parts.get(1).copied()).await
+        }
+        "WAIT_CLOSE" => {
+            trace!("WAIT_CLOSE");
+            wait_close(stream, query_parts.get(1).copied()).await
+        }
         "SET" => {
             trace!("SET");
-            ignore_set(stream).await
+            set(stream, &query, client_server_map).await
+        }
+        "DISABLE" => {
+            trace!("DISABLE");
+            disable(stream, query_parts.get(1).copied(), true).await
+        }
+        "ENABLE" => {
+            trace!("ENABLE");
+            disable(stream, query_parts.get(1).copied(), false).await
         }
         "PAUSE" => {
             trace!("PAUSE");
@@ -302,10 +322,11 @@ where
         for shard in 0..pool.shards() {
             let database_name = &pool.address(shard, 0).database;
             for server in 0..pool.servers(shard) {
-                let address = pool.address(shard, server);
+                let address = &pool.address(shard, server);
                 let pool_state = pool.pool_state(shard, server);
                 let banned = pool.is_banned(address);
                 let paused = pool.paused();
+                let disabled = pool.disabled();
 
                 res.put(data_row(&vec![
                     address.name(),                         // name
@@ -324,7 +345,7 @@ where
                         true => "1".to_string(),
                         false => "0".to_string(),
                     },
-                    match banned {
+                    match disabled || banned {
                         // disabled
                         true => "1".to_string(),
                         false => "0".to_string(),
@@ -352,6 +373,80 @@ where
     custom_protocol_response_ok(stream, "SET").await
 }
 
+/// Change a pgcat setting at runtime, e.g. SET ban_time = 30.
+/// Other settings, like the ones drivers set on connect, are ignored.
+async fn set<T>(
+    stream: &mut T,
+    query: &str,
+    client_server_map: ClientServerMap,
+) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    // SET key = value or SET key TO value.
+    let setting = query.trim().trim_end_matches(';')[3..].trim();
+
+    let (key, value) = match setting.split_once('=') {
+        Some((key, value)) => (key.trim(), value.trim()),
+        None => match setting.split_whitespace().collect::<Vec<&str>>().as_slice() {
+            [key, to, value] if to.eq_ignore_ascii_case("TO") => (*key, *value),
+            _ => return ignore_set(stream).await,
+        },
+    };
+
+    let key = setting_key(key);
+    let value = value.trim_matches('\'');
+
+    let settings: HashMap<String, String> = (&get_config()).into();
+
+    if !settings.contains_key(&key) && !key.starts_with("pools.") {
+        return ignore_set(stream).await;
+    }
+
+    match set_config(&key, value, client_server_map).await {
+        Ok(()) => {
+            let mut res = BytesMut::new();
+
+            res.put(command_complete("SET"));
+
+            // ReadyForQuery
+            res.put_u8(b'Z');
+            res.put_i32(5);
+            res.put_u8(b'I');
+
+            write_all_half(stream, &res).await
+        }
+
+        Err(_) => {
+            error_response(
+                stream,
+                &format!(
+                    "Could not set {} to {}, see the pgcat logs for details",
+                    key, value
+                ),
+            )
+            .await
+        }
+    }
+}
+
+/// Setting names are case insensitive, but the pool and user names
+/// in pools.<pool_name>.users.<username>.<setting> are not.
+fn setting_key(key: &str) -> String {
+    let parts: Vec<&str> = key.split('.').collect();
+    let pools = parts[0].eq_ignore_ascii_case("pools");
+
+    parts
+        .iter()
+        .enumerate()
+        .map(|(i, part)| match i {
+            1 | 3 if pools => part.to_string(),
+            _ => part.to_ascii_lowercase(),
+        })
+        .collect::<Vec<String>>()
+        .join(".")
+}
+
 /// Bans a host from being used
 async fn ban<T>(stream: &mut T, tokens: Vec<&str>) -> Result<(), Error>
 where
@@ -483,7 +578,8 @@ where
                 _ => pool.settings.ban_time,
             };
             let remaining = ban_duration - (now - ban_time.timestamp());
-            if remaining <= 0 {
+            // Replica lag bans last until the replica catches up.
+            if remaining <= 0 && *ban_reason != BanReason::ReplicaLag {
                 continue;
             }
             res.put(data_row(&vec![
@@ -494,7 +590,7 @@ where
                 format!("{:?}", ban_reason),
                 ban_time.to_string(),
                 ban_duration.to_string(),
-                remaining.to_string(),
+                remaining.max(0).to_string(),
             ]));
         }
     }
@@ -532,6 +628,53 @@ where
     write_all_half(stream, &res).await
 }
 
+/// Close the server connections of a pool, or of all pools, so new ones are opened,
+/// e.g. after a failover. Idle connections are closed now, the ones in use when
+/// the clients are done with them.
+async fn reconnect<T>(stream: &mut T, pool_name: Option<&str>) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let pools: Vec<_> = get_all_pools()
+        .into_iter()
+        .filter(|(id, _)| pool_name.map(|name| id.db == name).unwrap_or(true))
+        .collect();
+
+    if let (Some(pool_name), true) = (pool_name, pools.is_empty()) {
+        return error_response(stream, &format!("No pool named {}", pool_name)).await;
+    }
+
+    for (id, pool) in pools {
+        info!("Reconnecting [pool: {}][user: {}]", id.db, id.user);
+        pool.reconnect().await;
+    }
+
+    custom_protocol_response_ok(stream, "RECONNECT").await
+}
+
+/// Wait until the server connections closed by RECONNECT are gone.
+async fn wait_close<T>(stream: &mut T, pool_name: Option<&str>) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    if let Some(pool_name) = pool_name {
+        if !get_all_pools().keys().any(|id| id.db == pool_name) {
+            return error_response(stream, &format!("No pool named {}", pool_name)).await;
+        }
+    }
+
+    while get_server_stats().values().any(|server| {
+        server.closing()
+            && pool_name
+                .map(|name| server.pool_name() == name)
+                .unwrap_or(true)
+    }) {
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    }
+
+    custom_protocol_response_ok(stream, "WAIT_CLOSE").await
+}
+
 /// Shows current configuration.
 async fn show_config<T>(stream: &mut T) -> Result<(), Error>
 where
@@ -540,6 +683,10 @@ where
     let config = &get_config();
     let config: HashMap<String, String> = config.into();
 
+    // Settings changed with SET are marked as modified.
+    let file_config = &get_file_config();
+    let file_config: HashMap<String, String> = file_config.into();
+
     // Configs that cannot be changed without restarting.
     let immutables = ["host", "port", "connect_timeout"];
 
@@ -549,6 +696,7 @@ where
         ("value", DataType::Text),
         ("default", DataType::Text),
         ("changeable", DataType::Text),
+        ("modified", DataType::Text),
     ];
 
     // Response data
@@ -563,7 +711,13 @@ where
             "yes".to_string()
         };
 
-        let row = vec![key, value, "-".to_string(), changeable];
+        let modified = if file_config.get(&key) == Some(&value) {
+            "no".to_string()
+        } else {
+            "yes".to_string()
+        };
+
+        let row = vec![key, value, "-".to_string(), changeable, modified];
 
         res.put(data_row(&row));
     }
@@ -699,8 +853,20 @@ where
         ("bytes_sent", DataType::Numeric),
         ("bytes_received", DataType::Numeric),
         ("age_seconds", DataType::Numeric),
+        ("replica_lag", DataType::Numeric),
     ];
 
+    // Roles can change after a failover, so use the current address names.
+    let mut address_names = HashMap::new();
+    for (_, pool) in get_all_pools() {
+        for shard in 0..pool.shards() {
+            for server in 0..pool.servers(shard) {
+                let address = pool.address(shard, server);
+                address_names.insert(address.id, address.name());
+            }
+        }
+    }
+
     let new_map = get_server_stats();
     let mut res = BytesMut::new();
     res.put(row_description(&columns));
@@ -711,7 +877,10 @@ where
             format!("{:#010X}", server.server_id()),
             server.pool_name(),
             server.username(),
-            server.address_name(),
+            match address_names.get(&server.address_id()) {
+                Some(name) => name.clone(),
+                None => server.address_name(),
+            },
             application_name.clone(),
             server.state.load(Ordering::Relaxed).to_string(),
             server.transaction_count.load(Ordering::Relaxed).to_string(),
@@ -722,6 +891,7 @@ where
                 .duration_since(server.connect_time())
                 .as_secs()
                 .to_string(),
+            server.replica_lag().to_string(),
         ];
 
         res.put(data_row(&row));
@@ -784,6 +954,145 @@ where
     }
 }
 
+/// Disconnect a client, a server connection, or all clients and server connections of a pool.
+async fn kill<T>(stream: &mut T, tokens: Vec<&str>) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let usage = "usage: KILL CLIENT client_id, KILL SERVER server_id or KILL pool_name";
+
+    let (clients, servers) = match (tokens.get(1), tokens.get(2)) {
+        (Some(kind), Some(id)) if kind.eq_ignore_ascii_case("CLIENT") => {
+            let id = match parse_id(id) {
+                Some(id) => id,
+                None => return error_response(stream, usage).await,
+            };
+
+            match get_client_stats().get(&id) {
+                Some(client) => (vec![client.clone()], Vec::new()),
+                None => {
+                    return error_response(stream, &format!("No client with id {}", tokens[2]))
+                        .await
+                }
+            }
+        }
+
+        (Some(kind), Some(id)) if kind.eq_ignore_ascii_case("SERVER") => {
+            let id = match parse_id(id) {
+                Some(id) => id,
+                None => return error_response(stream, usage).await,
+            };
+
+            match get_server_stats().get(&id) {
+                Some(server) => (Vec::new(), vec![server.clone()]),
+                None => {
+                    return error_response(stream, &format!("No server with id {}", tokens[2]))
+                        .await
+                }
+            }
+        }
+
+        (Some(pool_name), None) => {
+            if !get_all_pools().keys().any(|id| id.db == *pool_name) {
+                return error_response(stream, &format!("No pool named {}", pool_name)).await;
+            }
+
+            (
+                get_client_stats()
+                    .values()
+                    .filter(|client| client.pool_name() == *pool_name)
+                    .cloned()
+                    .collect(),
+                get_server_stats()
+                    .values()
+                    .filter(|server| server.pool_name() == *pool_name)
+                    .cloned()
+                    .collect(),
+            )
+        }
+
+        _ => return error_response(stream, usage).await,
+    };
+
+    for client in clients.iter() {
+        info!(
+            "Killing client {:#010X} of pool {}",
+            client.client_id(),
+            client.pool_name()
+        );
+        client.kill();
+    }
+
+    for server in servers.iter() {
+        info!(
+            "Killing server {:#010X} {}",
+            server.server_id(),
+            server.address_name()
+        );
+        server.kill();
+    }
+
+    let mut res = BytesMut::new();
+
+    res.put(command_complete("KILL"));
+
+    // ReadyForQuery
+    res.put_u8(b'Z');
+    res.put_i32(5);
+    res.put_u8(b'I');
+
+    write_all_half(stream, &res).await
+}
+
+/// Parse a client or server id as shown by SHOW CLIENTS and SHOW SERVERS, e.g. 0x0000002A.
+fn parse_id(id: &str) -> Option<i32> {
+    match id.strip_prefix("0x").or_else(|| id.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok().map(|id| id as i32),
+        None => id.parse().ok(),
+    }
+}
+
+/// Disable or enable a pool. Clients of a disabled pool keep working,
+/// but new clients can't connect to it.
+async fn disable<T>(stream: &mut T, pool_name: Option<&str>, disable: bool) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let command = if disable { "DISABLE" } else { "ENABLE" };
+
+    let pool_name = match pool_name {
+        Some(pool_name) => pool_name,
+        None => {
+            return error_response(stream, &format!("usage: {} pool_name", command)).await;
+        }
+    };
+
+    let pools: Vec<_> = get_all_pools()
+        .into_iter()
+        .filter(|(id, _)| id.db == pool_name)
+        .collect();
+
+    if pools.is_empty() {
+        return error_response(stream, &format!("No pool named {}", pool_name)).await;
+    }
+
+    for (_, pool) in pools {
+        if disable {
+            pool.disable();
+        } else {
+            pool.enable();
+        }
+    }
+
+    info!(
+        "Pool {} {}",
+        pool_name,
+        if disable { "disabled" } else { "enabled" }
+    );
+
+    custom_protocol_response_ok(stream, command).await
+}
+
 /// Resume a pool. Queries are allowed again.
 async fn resume<T>(stream: &mut T, query: &str) -> Result<(), Error>
 where
@@ -887,3 +1196,42 @@ where
 
     write_all_half(stream, &res).await
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_setting_key() {
+        assert_eq!(setting_key("BAN_TIME"), "ban_time");
+        assert_eq!(
+            setting_key("POOLS.MyDb.Max_Client_Conn"),
+            "pools.MyDb.max_client_conn"
+        );
+        assert_eq!(
+            setting_key("pools.MyDb.USERS.Alice.Pool_Size"),
+            "pools.MyDb.users.Alice.pool_size"
+        );
+    }
+
+    #[test]
+    fn test_parse_id() {
+        assert_eq!(parse_id("0x0000002A"), Some(42));
+        assert_eq!(parse_id("0X2a"), Some(42));
+        assert_eq!(parse_id("42"), Some(42));
+        assert_eq!(parse_id("-42"), Some(-42));
+
+        // Negative ids are shown in two's complement.
+        assert_eq!(parse_id("0xFFFFFFD6"), Some(-42));
+
+        // As shown by SHOW CLIENTS and SHOW SERVERS.
+        for id in [0, 42, -42, i32::MAX, i32::MIN] {
+            assert_eq!(parse_id(&format!("{:#010X}", id)), Some(id));
+        }
+
+        assert_eq!(parse_id("0x"), None);
+        assert_eq!(parse_id("0x1FFFFFFFF"), None);
+        assert_eq!(parse_id("forty-two"), None);
+        assert_eq!(parse_id(""), None);
+    }
+}
@@ -0,0 +1,361 @@
+/// HTTP/JSON admin API, served with the Prometheus metrics.
+/// It exposes the admin database commands to tools that don't speak Postgres.
+use hyper::header::AUTHORIZATION;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use log::info;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::Instant;
+
+use crate::config::{get_config, reload_config};
+use crate::pool::{get_all_pools, get_pool, BanReason, ClientServerMap};
+use crate::scram::constant_time_eq;
+use crate::stats::pool::PoolStats;
+use crate::stats::{get_client_stats, get_server_stats};
+
+/// Handle a request to the admin API, under /api.
+pub async fn admin_api(
+    request: Request<Body>,
+    client_server_map: ClientServerMap,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let config = get_config();
+
+    let bearer = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "));
+
+    if !authorized(config.general.admin_api_token.as_deref(), bearer) {
+        return error(StatusCode::UNAUTHORIZED, "invalid or missing bearer token");
+    }
+
+    let path: Vec<&str> = request.uri().path().trim_matches('/').split('/').collect();
+
+    match (request.method(), path.as_slice()) {
+        (&Method::GET, ["api", "pools"]) => ok(pools()),
+        (&Method::GET, ["api", "clients"]) => ok(clients()),
+        (&Method::GET, ["api", "servers"]) => ok(servers()),
+        (&Method::GET, ["api", "bans"]) => ok(bans()),
+        (&Method::GET, ["api", "config"]) => {
+            let config: HashMap<String, String> = (&config).into();
+            ok(json!(config))
+        }
+
+        (&Method::POST, ["api", "reload"]) => {
+            info!("Reloading config");
+
+            match reload_config(client_server_map).await {
+                Ok(changed) => {
+                    get_config().show();
+                    ok(json!({ "changed": changed }))
+                }
+                Err(err) => error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    &format!("Config reload error: {:?}", err),
+                ),
+            }
+        }
+
+        (&Method::POST, ["api", "pools", database, user, action @ ("pause" | "resume")]) => {
+            match get_pool(database, user) {
+                Some(pool) => {
+                    if *action == "pause" {
+                        pool.pause();
+                    } else {
+                        pool.resume();
+                    }
+
+                    ok(json!({ "database": database, "user": user, "paused": pool.paused() }))
+                }
+                None => error(
+                    StatusCode::NOT_FOUND,
+                    &format!(
+                        "No pool configured for database: {}, user: {}",
+                        database, user
+                    ),
+                ),
+            }
+        }
+
+        (&Method::POST, ["api", "bans", host]) => {
+            let duration_seconds = request.uri().query().and_then(|query| {
+                query
+                    .split('&')
+                    .find_map(|param| param.strip_prefix("duration_seconds="))
+                    .and_then(|duration| duration.parse::<i64>().ok())
+            });
+
+            match duration_seconds {
+                Some(duration_seconds) if duration_seconds > 0 => ok(ban(host, duration_seconds)),
+                _ => error(
+                    StatusCode::BAD_REQUEST,
+                    "duration_seconds must be a positive integer, e.g. ?duration_seconds=60",
+                ),
+            }
+        }
+
+        (&Method::DELETE, ["api", "bans", host]) => ok(unban(host)),
+
+        _ => error(StatusCode::NOT_FOUND, "Not found"),
+    }
+}
+
+/// The request has the configured token. Without one, nobody is.
+fn authorized(token: Option<&str>, bearer: Option<&str>) -> bool {
+    match (token, bearer) {
+        (Some(token), Some(bearer)) if !token.is_empty() => {
+            constant_time_eq(token.as_bytes(), bearer.as_bytes())
+        }
+        _ => false,
+    }
+}
+
+fn ok(body: Value) -> Result<Response<Body>, hyper::http::Error> {
+    respond(StatusCode::OK, body)
+}
+
+fn error(status: StatusCode, message: &str) -> Result<Response<Body>, hyper::http::Error> {
+    respond(status, json!({ "error": message }))
+}
+
+fn respond(status: StatusCode, body: Value) -> Result<Response<Body>, hyper::http::Error> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(body.to_string().into())
+}
+
+/// Like SHOW POOLS.
+fn pools() -> Value {
+    let pools = get_all_pools();
+
+    PoolStats::construct_pool_lookup()
+        .into_iter()
+        .map(|(identifier, pool_stats)| {
+            let mut pool = json!({
+                "database": identifier.db,
+                "user": identifier.user,
+                "pool_mode": pool_stats.mode.to_string(),
+                "paused": pools.get(&identifier).map(|pool| pool.paused()).unwrap_or(false),
+                "disabled": pool_stats.disabled,
+            });
+
+            for (name, value) in pool_stats {
+                pool[name] = json!(value);
+            }
+
+            pool
+        })
+        .collect()
+}
+
+/// Like SHOW CLIENTS.
+fn clients() -> Value {
+    get_client_stats()
+        .values()
+        .map(|client| {
+            json!({
+                "client_id": format!("{:#010X}", client.client_id()),
+                "database": client.pool_name(),
+                "user": client.username(),
+                "application_name": client.application_name(),
+                "state": client.state.load(Ordering::Relaxed).to_string(),
+                "transaction_count": client.transaction_count.load(Ordering::Relaxed),
+                "query_count": client.query_count.load(Ordering::Relaxed),
+                "error_count": client.error_count.load(Ordering::Relaxed),
+                "age_seconds": Instant::now().duration_since(client.connect_time()).as_secs(),
+            })
+        })
+        .collect()
+}
+
+/// Like SHOW SERVERS.
+fn servers() -> Value {
+    get_server_stats()
+        .values()
+        .map(|server| {
+            json!({
+                "server_id": format!("{:#010X}", server.server_id()),
+                "database": server.pool_name(),
+                "user": server.username(),
+                "address": server.address_name(),
+                "application_name": server.application_name.read().clone(),
+                "state": server.state.load(Ordering::Relaxed).to_string(),
+                "transaction_count": server.transaction_count.load(Ordering::Relaxed),
+                "query_count": server.query_count.load(Ordering::Relaxed),
+                "bytes_sent": server.bytes_sent.load(Ordering::Relaxed),
+                "bytes_received": server.bytes_received.load(Ordering::Relaxed),
+                "age_seconds": Instant::now().duration_since(server.connect_time()).as_secs(),
+                "replica_lag": server.replica_lag(),
+            })
+        })
+        .collect()
+}
+
+/// Like SHOW BANS.
+fn bans() -> Value {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs() as i64;
+
+    let mut bans = Vec::new();
+
+    for (id, pool) in get_all_pools().iter() {
+        for (address, (ban_reason, ban_time)) in pool.get_bans().iter() {
+            let ban_duration = match ban_reason {
+                BanReason::AdminBan(duration) => *duration,
+                _ => pool.settings.ban_time,
+            };
+            let remaining = ban_duration - (now - ban_time.timestamp());
+            // Replica lag bans last until the replica catches up.
+            if remaining <= 0 && *ban_reason != BanReason::ReplicaLag {
+                continue;
+            }
+            bans.push(json!({
+                "database": id.db,
+                "user": id.user,
+                "role": address.role.to_string(),
+                "host": address.host,
+                "reason": format!("{:?}", ban_reason),
+                "ban_time": ban_time.to_string(),
+                "ban_duration_seconds": ban_duration,
+                "ban_remaining_seconds": remaining.max(0),
+            }));
+        }
+    }
+
+    Value::Array(bans)
+}
+
+/// Like BAN, returns the banned servers.
+fn ban(host: &str, duration_seconds: i64) -> Value {
+    let mut banned = Vec::new();
+
+    for (id, pool) in get_all_pools().iter() {
+        for address in pool.get_addresses_from_host(host) {
+            if pool.is_banned(&address) {
+                continue;
+            }
+
+            // Primaries can't be banned.
+            pool.ban(&address, BanReason::AdminBan(duration_seconds), None);
+
+            if pool.is_banned(&address) {
+                banned.push(json!({
+                    "database": id.db,
+                    "user": id.user,
+                    "role": address.role.to_string(),
+                    "host": address.host,
+                }));
+            }
+        }
+    }
+
+    Value::Array(banned)
+}
+
+/// Like UNBAN, returns the unbanned servers.
+fn unban(host: &str) -> Value {
+    let mut unbanned = Vec::new();
+
+    for (id, pool) in get_all_pools().iter() {
+        for address in pool.get_addresses_from_host(host) {
+            if pool.is_banned(&address) {
+                pool.unban(&address);
+                unbanned.push(json!({
+                    "database": id.db,
+                    "user": id.user,
+                    "role": address.role.to_string(),
+                    "host": address.host,
+                }));
+            }
+        }
+    }
+
+    Value::Array(unbanned)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{Address, Role};
+    use crate::pool::{ConnectionPool, PoolIdentifier, POOLS};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_authorized() {
+        assert!(authorized(Some("secret"), Some("secret")));
+        assert!(!authorized(Some("secret"), Some("secreT")));
+        assert!(!authorized(Some("secret"), Some("secret2")));
+        assert!(!authorized(Some("secret"), None));
+
+        // No token configured, the API is closed.
+        assert!(!authorized(None, Some("")));
+        assert!(!authorized(Some(""), Some("")));
+    }
+
+    #[test]
+    fn test_pools_and_bans() {
+        let address = |index: usize, host: &str, role: Role| Address {
+            id: index,
+            address_index: index,
+            host: host.into(),
+            role,
+            pool_name: "api_db".into(),
+            username: "api_user".into(),
+            ..Default::default()
+        };
+
+        let pool = ConnectionPool::with_addresses(vec![vec![
+            address(0, "primary.example.com", Role::Primary),
+            address(1, "replica.example.com", Role::Replica),
+        ]]);
+        POOLS.store(Arc::new(HashMap::from([(
+            PoolIdentifier::new("api_db", "api_user"),
+            pool,
+        )])));
+
+        let pools = pools();
+        assert_eq!(pools.as_array().unwrap().len(), 1);
+        assert_eq!(pools[0]["database"], "api_db");
+        assert_eq!(pools[0]["user"], "api_user");
+        assert_eq!(pools[0]["paused"], false);
+        assert_eq!(pools[0]["cl_active"], 0);
+
+        assert_eq!(bans(), json!([]));
+
+        // The primary can't be banned.
+        assert_eq!(ban("primary.example.com", 60), json!([]));
+
+        let banned = ban("replica.example.com", 60);
+        assert_eq!(
+            banned,
+            json!([{
+                "database": "api_db",
+                "user": "api_user",
+                "role": "replica",
+                "host": "replica.example.com",
+            }])
+        );
+
+        // Already banned.
+        assert_eq!(ban("replica.example.com", 60), json!([]));
+
+        let bans = bans();
+        assert_eq!(bans.as_array().unwrap().len(), 1);
+        assert_eq!(bans[0]["host"], "replica.example.com");
+        assert_eq!(bans[0]["reason"], "AdminBan(60)");
+        assert_eq!(bans[0]["ban_duration_seconds"], 60);
+        assert!(bans[0]["ban_remaining_seconds"].as_i64().unwrap() > 0);
+
+        assert_eq!(unban("replica.example.com"), banned);
+        assert_eq!(unban("replica.example.com"), json!([]));
+        assert_eq!(super::bans(), json!([]));
+
+        POOLS.store(Arc::new(HashMap::new()));
+    }
+}
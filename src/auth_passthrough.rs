@@ -1,5 +1,6 @@
+use crate::config::Address;
 use crate::errors::Error;
-use crate::pool::ConnectionPool;
+use crate::scram::ScramVerifier;
 use crate::server::Server;
 use log::debug;
 
@@ -49,7 +50,7 @@ impl AuthPassthrough {
 
     /// Connects to server and executes auth_query for the specified address.
     /// If the response is a row with two columns containing the username set in the address.
-    /// and its MD5 hash, the MD5 hash returned.
+    /// and its MD5 hash, the MD5 hash returned. SCRAM-SHA-256 verifiers are returned as is.
     ///
     /// Note that the query is executed, changing $1 with the name of the user
     /// this is so we only hold in memory (and transfer) the least amount of 'sensitive' data.
@@ -79,6 +80,7 @@ impl AuthPassthrough {
             pool_mode: None,
             server_lifetime: None,
             min_pool_size: None,
+            tls_client_certificate_names: None,
         };
 
         let user = &address.username;
@@ -90,16 +92,18 @@ impl AuthPassthrough {
         match Server::exec_simple_query(address, &auth_user, &auth_query).await {
             Ok(password_data) => {
                 if password_data.len() == 2 && password_data.first().unwrap() == user {
-                    if let Some(stripped_hash) = password_data
-                        .last()
-                        .unwrap()
-                        .to_string()
-                        .strip_prefix("md5") {
+                    let hash = password_data.last().unwrap();
+
+                    if let Some(stripped_hash) = hash.strip_prefix("md5") {
                             Ok(stripped_hash.to_string())
                         }
+                    // SCRAM verifiers are kept whole, they are parsed when a client authenticates.
+                    else if ScramVerifier::is_verifier(hash) {
+                            Ok(hash.to_string())
+                        }
                     else {
                         Err(Error::AuthPassthroughError(
-                            "Obtained hash from auth_query does not seem to be in md5 format.".to_string(),
+                            "Obtained hash from auth_query does not seem to be in md5 or SCRAM-SHA-256 format.".to_string(),
                         ))
                     }
                 } else {
@@ -119,9 +123,12 @@ impl AuthPassthrough {
     }
 }
 
-pub async fn refetch_auth_hash(pool: &ConnectionPool) -> Result<String, Error> {
-    let address = pool.address(0, 0);
-    if let Some(apt) = AuthPassthrough::from_pool_settings(&pool.settings) {
+/// Fetch the user's password hash again with auth_query, e.g. after it changed.
+pub async fn refetch_auth_hash(
+    address: &Address,
+    auth_passthrough: &Option<AuthPassthrough>,
+) -> Result<String, Error> {
+    if let Some(apt) = auth_passthrough {
         let hash = apt.fetch_hash(address).await?;
 
         return Ok(hash);
@@ -1,28 +1,44 @@
 use crate::errors::{ClientIdentifier, Error};
 use crate::pool::BanReason;
 /// Handle clients by pretending to be a PostgreSQL server.
+use bb8::PooledConnection;
 use bytes::{Buf, BufMut, BytesMut};
 use log::{debug, error, info, trace, warn};
-use std::collections::HashMap;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Instant;
 use tokio::io::{split, AsyncReadExt, BufReader, ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
 use tokio::sync::broadcast::Receiver;
 use tokio::sync::mpsc::Sender;
 
 use crate::admin::{generate_server_info_for_admin, handle_admin};
-use crate::auth_passthrough::refetch_auth_hash;
-use crate::config::{get_config, get_idle_client_in_transaction_timeout, Address, PoolMode};
+use crate::auth_passthrough::{refetch_auth_hash, AuthPassthrough};
+use crate::config::{
+    get_config, get_idle_client_in_transaction_timeout, Address, Config, PoolMode, Role,
+};
 use crate::constants::*;
+use crate::hba::{self, AuthMethod};
 use crate::messages::*;
 use crate::plugins::PluginOutput;
-use crate::pool::{get_pool, ClientServerMap, ConnectionPool};
+use crate::pool::{get_pool, AutoDb, ClientServerMap, ConnectionPool, ServerPool};
 use crate::query_router::{Command, QueryRouter};
-use crate::server::Server;
+use crate::scatter_gather::ScatterGather;
+use crate::scram::{ScramSha256Server, ScramVerifier};
+use crate::server::{PreparedStatementCache, Server};
+use crate::session_parameters::{Change, SessionParameters};
 use crate::stats::{ClientStats, ServerStats};
-use crate::tls::Tls;
+use crate::tls::{certificate_names, get_tls};
 
+use tokio_rustls::rustls;
 use tokio_rustls::server::TlsStream;
 
 /// Type of connection received from client.
@@ -32,6 +48,320 @@ enum ClientConnectionType {
     CancelQuery,
 }
 
+/// Where the client connected from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClientAddr {
+    /// TCP client, with its address.
+    Tcp(SocketAddr),
+
+    /// Unix socket client, with the credentials of its process.
+    Unix {
+        uid: u32,
+        gid: u32,
+        pid: Option<i32>,
+    },
+}
+
+impl Display for ClientAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientAddr::Tcp(addr) => write!(f, "{}", addr),
+            ClientAddr::Unix { uid, pid, .. } => match pid {
+                Some(pid) => write!(f, "[local] uid={} pid={}", uid, pid),
+                None => write!(f, "[local] uid={}", uid),
+            },
+        }
+    }
+}
+
+/// WAL position of the last write, if it's within the read your writes window (ms).
+fn recent_write_lsn(last_write: Option<&(u64, Instant)>, window: u64) -> Option<u64> {
+    match last_write {
+        Some((lsn, time)) if time.elapsed().as_millis() < window as u128 => Some(*lsn),
+        _ => None,
+    }
+}
+
+/// Response to one of the messages of an extended protocol batch, in order.
+#[derive(Debug)]
+enum Slot {
+    /// The message is sent to the server. The responses to the messages
+    /// we added to the batch are hidden from the client.
+    Server { code: char, hidden: bool },
+
+    /// The message isn't sent to the server, we answer it ourselves.
+    Synthetic(BytesMut),
+}
+
+/// What we changed in an extended protocol batch to use the prepared statements
+/// cached on the server, and how to fix up the response for the client.
+#[derive(Default)]
+struct PreparedStatementsBatch {
+    /// Responses expected for the messages of the batch, in order.
+    slots: VecDeque<Slot>,
+
+    /// After an error, the server skips the messages until Sync.
+    skipping: bool,
+
+    /// Statements prepared on the server in this batch.
+    prepared: Vec<String>,
+
+    /// Statements used in this batch, they're not closed to make room for others.
+    used: HashSet<String>,
+
+    /// The server returned an error, some statements might not have been prepared.
+    failed: bool,
+}
+
+impl PreparedStatementsBatch {
+    /// Rewrite the extended protocol messages to use the names the client's prepared
+    /// statements have on the server, and prepare the ones the server doesn't have yet.
+    /// Returns the messages to send to the server.
+    fn rewrite(
+        mut messages: BytesMut,
+        statements: &mut HashMap<String, Parse>,
+        cache: &mut PreparedStatementCache,
+        max_prepared_statements: usize,
+    ) -> Result<(BytesMut, PreparedStatementsBatch), Error> {
+        let mut batch = PreparedStatementsBatch::default();
+        let mut buffer = BytesMut::with_capacity(messages.len());
+
+        while messages.len() >= 5 {
+            let len = (&messages[1..5]).get_i32() as usize;
+
+            if messages.len() < len + 1 {
+                return Err(Error::ProtocolSyncError(
+                    "Incomplete message in extended protocol batch".into(),
+                ));
+            }
+
+            let message = messages.split_to(len + 1);
+            let code = message[0] as char;
+
+            match code {
+                'P' => {
+                    let parse = Parse::try_from(&message)?;
+
+                    if parse.anonymous() {
+                        batch.send(&mut buffer, message, false);
+                        continue;
+                    }
+
+                    let renamed = parse.rename();
+
+                    if cache.contains(&renamed.name) {
+                        batch.used.insert(renamed.name.clone());
+                        batch.slots.push_back(Slot::Synthetic(parse_complete()));
+                    } else {
+                        batch.prepare(&renamed, &mut buffer, cache, max_prepared_statements, false);
+                    }
+
+                    statements.insert(parse.name, renamed);
+                }
+
+                'B' => {
+                    let bind = Bind::try_from(&message)?;
+
+                    match statements.get(&bind.statement) {
+                        Some(parse) => {
+                            batch.prepare_if_missing(
+                                parse,
+                                &mut buffer,
+                                cache,
+                                max_prepared_statements,
+                            );
+                            batch.send(
+                                &mut buffer,
+                                BytesMut::from(&bind.rename(&parse.name)),
+                                false,
+                            );
+                        }
+
+                        None => batch.send(&mut buffer, message, false),
+                    }
+                }
+
+                'D' | 'C' => {
+                    let describe = Describe::try_from(&message)?;
+
+                    if describe.target != 'S' || describe.name.is_empty() {
+                        batch.send(&mut buffer, message, false);
+                        continue;
+                    }
+
+                    // Other clients might be using the statement, so we keep it on the server.
+                    if describe.is_close() {
+                        statements.remove(&describe.name);
+                        batch.slots.push_back(Slot::Synthetic(close_complete()));
+                        continue;
+                    }
+
+                    match statements.get(&describe.name) {
+                        Some(parse) => {
+                            batch.prepare_if_missing(
+                                parse,
+                                &mut buffer,
+                                cache,
+                                max_prepared_statements,
+                            );
+                            batch.send(
+                                &mut buffer,
+                                BytesMut::from(&describe.rename(&parse.name)),
+                                false,
+                            );
+                        }
+
+                        None => batch.send(&mut buffer, message, false),
+                    }
+                }
+
+                // These get a response from the server.
+                'E' | 'S' => batch.send(&mut buffer, message, false),
+
+                // Flush and the others don't.
+                _ => buffer.put(message),
+            }
+        }
+
+        buffer.put(messages);
+
+        Ok((buffer, batch))
+    }
+
+    /// Add a message to the batch sent to the server.
+    fn send(&mut self, buffer: &mut BytesMut, message: BytesMut, hidden: bool) {
+        self.slots.push_back(Slot::Server {
+            code: message[0] as char,
+            hidden,
+        });
+        buffer.put(message);
+    }
+
+    /// Prepare the statement the client is using, if the server doesn't have it.
+    fn prepare_if_missing(
+        &mut self,
+        parse: &Parse,
+        buffer: &mut BytesMut,
+        cache: &mut PreparedStatementCache,
+        max_prepared_statements: usize,
+    ) {
+        if cache.contains(&parse.name) {
+            self.used.insert(parse.name.clone());
+        } else {
+            self.prepare(parse, buffer, cache, max_prepared_statements, true);
+        }
+    }
+
+    /// Add the statement to the batch. It's closed first, in case it was prepared by
+    /// a batch that failed and we don't know about it. Then, the least recently used
+    /// statements past the limit are closed.
+    fn prepare(
+        &mut self,
+        parse: &Parse,
+        buffer: &mut BytesMut,
+        cache: &mut PreparedStatementCache,
+        max_prepared_statements: usize,
+        hidden: bool,
+    ) {
+        self.send(
+            buffer,
+            BytesMut::from(&Describe::close_statement(&parse.name)),
+            true,
+        );
+        self.send(buffer, BytesMut::from(parse), hidden);
+        self.prepared.push(parse.name.clone());
+        self.used.insert(parse.name.clone());
+        cache.insert(&parse.name);
+
+        while max_prepared_statements > 0 && cache.len() > max_prepared_statements {
+            match cache.evict(&self.used) {
+                Some(name) => self.send(
+                    buffer,
+                    BytesMut::from(&Describe::close_statement(&name)),
+                    true,
+                ),
+                None => break,
+            }
+        }
+    }
+
+    /// Fix up a chunk of the server response: add our answers where the messages
+    /// we answered were in the batch, and hide the responses to the messages we added.
+    fn response(&mut self, mut response: BytesMut) -> BytesMut {
+        let mut result = BytesMut::with_capacity(response.len());
+
+        self.synthesize(&mut result);
+
+        while response.len() >= 5 {
+            let len = (&response[1..5]).get_i32() as usize;
+            let message = response.split_to((len + 1).min(response.len()));
+
+            let hidden = matches!(self.slots.front(), Some(Slot::Server { hidden: true, .. }));
+
+            match message[0] as char {
+                // Notices, parameter changes and notifications aren't responses.
+                'N' | 'S' | 'A' => result.put(message),
+
+                // The client sees errors even for the messages it didn't send,
+                // since the messages that follow are skipped.
+                'E' => {
+                    self.failed = true;
+                    self.skipping = true;
+                    result.put(message);
+                }
+
+                // ReadyForQuery answers the Sync, the server skipped everything before it after an error.
+                'Z' => {
+                    while let Some(slot) = self.slots.pop_front() {
+                        if let Slot::Server { code: 'S', .. } = slot {
+                            break;
+                        }
+                    }
+
+                    self.skipping = false;
+                    result.put(message);
+                }
+
+                // Last response to a message: ParseComplete, BindComplete, CloseComplete,
+                // RowDescription or NoData after Describe, and the end of Execute.
+                '1' | '2' | '3' | 'T' | 'n' | 'C' | 'I' | 's' => {
+                    if !hidden {
+                        result.put(message);
+                    }
+
+                    self.slots.pop_front();
+                }
+
+                _ => {
+                    if !hidden {
+                        result.put(message);
+                    }
+                }
+            }
+
+            self.synthesize(&mut result);
+        }
+
+        result.put(response);
+        result
+    }
+
+    /// Add our answers that come next in the response.
+    fn synthesize(&mut self, result: &mut BytesMut) {
+        while !self.skipping {
+            match self.slots.front() {
+                Some(Slot::Synthetic(_)) => {
+                    if let Some(Slot::Synthetic(message)) = self.slots.pop_front() {
+                        result.put(message);
+                    }
+                }
+
+                _ => break,
+            }
+        }
+    }
+}
+
 /// The client state. One of these is created per client.
 pub struct Client<S, T> {
     /// The reads are buffered (8K by default).
@@ -45,8 +375,8 @@ pub struct Client<S, T> {
     /// them to the backend.
     buffer: BytesMut,
 
-    /// Address
-    addr: std::net::SocketAddr,
+    /// Address, or peer credentials for Unix socket clients.
+    addr: ClientAddr,
 
     /// The client was started with the sole reason to cancel another running query.
     cancel_mode: bool,
@@ -93,6 +423,31 @@ pub struct Client<S, T> {
 
     /// Used to notify clients about an impending shutdown
     shutdown: Receiver<()>,
+
+    /// Track the client's named prepared statements and prepare them
+    /// on whichever server the client is using.
+    prepared_statements_enabled: bool,
+
+    /// Named prepared statements of this client, as they are prepared on the servers.
+    prepared_statements: HashMap<String, Parse>,
+
+    /// Past this many prepared statements on a server, the least recently used are closed.
+    max_prepared_statements: usize,
+
+    /// Changes made to the current extended protocol batch.
+    prepared_statements_batch: Option<PreparedStatementsBatch>,
+
+    /// Counted against `max_client_conn` while the client is connected.
+    _client_conn: Option<ClientConn>,
+
+    /// Replay the session parameters the client changed with SET on every server it uses.
+    track_session_parameters: bool,
+
+    /// Session parameters the client changed with SET.
+    session_parameters: SessionParameters,
+
+    /// WAL position of the primary after the client's last write on each shard, and when.
+    last_writes: HashMap<usize, (u64, Instant)>,
 }
 
 /// Client entrypoint.
@@ -106,7 +461,7 @@ pub async fn client_entrypoint(
     log_client_connections: bool,
 ) -> Result<(), Error> {
     // Figure out if the client wants TLS or not.
-    let addr = stream.peer_addr().unwrap();
+    let addr = ClientAddr::Tcp(stream.peer_addr().unwrap());
 
     match get_startup::<TcpStream>(&mut stream).await {
         // Client requested a TLS connection.
@@ -123,9 +478,9 @@ pub async fn client_entrypoint(
                 match startup_tls(stream, client_server_map, shutdown, admin_only).await {
                     Ok(mut client) => {
                         if log_client_connections {
-                            info!("Client {:?} connected (TLS)", addr);
+                            info!("Client {} connected (TLS)", addr);
                         } else {
-                            debug!("Client {:?} connected (TLS)", addr);
+                            debug!("Client {} connected (TLS)", addr);
                         }
 
                         if !client.is_admin() {
@@ -159,6 +514,7 @@ pub async fn client_entrypoint(
                 match get_startup::<TcpStream>(&mut stream).await {
                     // Client accepted unencrypted connection.
                     Ok((ClientConnectionType::Startup, bytes)) => {
+                        let auth_method = hba_check(&mut stream, &addr, false, &bytes).await?;
                         let (read, write) = split(stream);
 
                         // Continue with regular startup.
@@ -167,6 +523,7 @@ pub async fn client_entrypoint(
                             write,
                             addr,
                             bytes,
+                            auth_method,
                             client_server_map,
                             shutdown,
                             admin_only,
@@ -175,9 +532,9 @@ pub async fn client_entrypoint(
                         {
                             Ok(mut client) => {
                                 if log_client_connections {
-                                    info!("Client {:?} connected (plain)", addr);
+                                    info!("Client {} connected (plain)", addr);
                                 } else {
-                                    debug!("Client {:?} connected (plain)", addr);
+                                    debug!("Client {} connected (plain)", addr);
                                 }
 
                                 if !client.is_admin() {
@@ -213,6 +570,7 @@ pub async fn client_entrypoint(
 
         // Client wants to use plain connection without encryption.
         Ok((ClientConnectionType::Startup, bytes)) => {
+            let auth_method = hba_check(&mut stream, &addr, false, &bytes).await?;
             let (read, write) = split(stream);
 
             // Continue with regular startup.
@@ -221,6 +579,7 @@ pub async fn client_entrypoint(
                 write,
                 addr,
                 bytes,
+                auth_method,
                 client_server_map,
                 shutdown,
                 admin_only,
@@ -229,9 +588,9 @@ pub async fn client_entrypoint(
             {
                 Ok(mut client) => {
                     if log_client_connections {
-                        info!("Client {:?} connected (plain)", addr);
+                        info!("Client {} connected (plain)", addr);
                     } else {
-                        debug!("Client {:?} connected (plain)", addr);
+                        debug!("Client {} connected (plain)", addr);
                     }
 
                     if !client.is_admin() {
@@ -261,7 +620,7 @@ pub async fn client_entrypoint(
             // Continue with cancel query request.
             match Client::cancel(read, write, addr, bytes, client_server_map, shutdown).await {
                 Ok(mut client) => {
-                    info!("Client {:?} issued a cancel query request", addr);
+                    info!("Client {} issued a cancel query request", addr);
 
                     if !client.is_admin() {
                         let _ = drain.send(1).await;
@@ -288,6 +647,261 @@ pub async fn client_entrypoint(
     }
 }
 
+/// Client entrypoint for clients connecting over the Unix socket.
+/// TLS is not offered, like Postgres doesn't on Unix sockets.
+#[cfg(unix)]
+pub async fn unix_client_entrypoint(
+    mut stream: UnixStream,
+    client_server_map: ClientServerMap,
+    shutdown: Receiver<()>,
+    drain: Sender<i32>,
+    admin_only: bool,
+    log_client_connections: bool,
+) -> Result<(), Error> {
+    let addr = match stream.peer_cred() {
+        Ok(cred) => ClientAddr::Unix {
+            uid: cred.uid(),
+            gid: cred.gid(),
+            pid: cred.pid(),
+        },
+        Err(err) => {
+            return Err(Error::SocketError(format!(
+                "Could not get Unix socket peer credentials: {:?}",
+                err
+            )))
+        }
+    };
+
+    let mut startup = get_startup::<UnixStream>(&mut stream).await?;
+
+    // Reject the TLS request, the client can continue in plain text.
+    if let (ClientConnectionType::Tls, _) = startup {
+        let mut no = BytesMut::new();
+        no.put_u8(b'N');
+        write_all(&mut stream, no).await?;
+
+        startup = get_startup::<UnixStream>(&mut stream).await?;
+    }
+
+    match startup {
+        // Client wants to use plain connection without encryption.
+        (ClientConnectionType::Startup, bytes) => {
+            let auth_method = hba_check(&mut stream, &addr, false, &bytes).await?;
+            let (read, write) = split(stream);
+
+            let mut client = Client::startup(
+                read,
+                write,
+                addr,
+                bytes,
+                auth_method,
+                client_server_map,
+                shutdown,
+                admin_only,
+            )
+            .await?;
+
+            if log_client_connections {
+                info!("Client {} connected (unix)", addr);
+            } else {
+                debug!("Client {} connected (unix)", addr);
+            }
+
+            if !client.is_admin() {
+                let _ = drain.send(1).await;
+            }
+
+            let result = client.handle().await;
+
+            if !client.is_admin() {
+                let _ = drain.send(-1).await;
+
+                if result.is_err() {
+                    client.stats.disconnect();
+                }
+            }
+
+            result
+        }
+
+        // Client wants to cancel a query.
+        (ClientConnectionType::CancelQuery, bytes) => {
+            let (read, write) = split(stream);
+
+            let mut client =
+                Client::cancel(read, write, addr, bytes, client_server_map, shutdown).await?;
+
+            info!("Client {} issued a cancel query request", addr);
+
+            client.handle().await
+        }
+
+        (ClientConnectionType::Tls, _) => Err(Error::ProtocolSyncError(
+            "Bad postgres client (unix)".into(),
+        )),
+    }
+}
+
+/// Find how the client should authenticate with the hba rules,
+/// and turn it away if it's not allowed to connect.
+async fn hba_check<S>(
+    stream: &mut S,
+    addr: &ClientAddr,
+    tls: bool,
+    bytes: &BytesMut,
+) -> Result<AuthMethod, Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let parameters = parse_startup(bytes.clone())?;
+    let username = parameters.get("user").map(|user| user.as_str()).unwrap();
+    let pool_name = match parameters.get("database") {
+        Some(db) => db,
+        None => username,
+    };
+
+    let config = get_config();
+    let host = match addr {
+        ClientAddr::Tcp(addr) => addr.ip().to_string(),
+        ClientAddr::Unix { .. } => "[local]".to_string(),
+    };
+
+    let message = match hba::auth_method(
+        &config.hba,
+        config.general.auth_type,
+        addr,
+        tls,
+        pool_name,
+        username,
+    ) {
+        Some(AuthMethod::Reject) => format!(
+            "hba rules reject connection for host \"{}\", user \"{}\", database \"{}\"",
+            host, username, pool_name
+        ),
+        Some(auth_method) => return Ok(auth_method),
+        None => format!(
+            "no hba entry for host \"{}\", user \"{}\", database \"{}\", {}",
+            host,
+            username,
+            pool_name,
+            if tls { "SSL on" } else { "SSL off" }
+        ),
+    };
+
+    warn!("Rejecting client {}: {}", addr, message);
+    error_response_terminal(stream, &message).await?;
+
+    Err(Error::ClientGeneralError(
+        "Rejected by hba rules".into(),
+        ClientIdentifier::new("", username, pool_name),
+    ))
+}
+
+/// Authentication method for a client with these certificate names, None if it's rejected.
+/// The certificate replaces the password only if the hba method is `cert`,
+/// or the user lists the certificate in `tls_client_certificate_names`.
+fn certificate_auth(
+    auth_method: AuthMethod,
+    certificate_names: &[String],
+    username: &str,
+    pool_name: &str,
+    config: &Config,
+) -> Option<AuthMethod> {
+    let admin = ["pgcat", "pgbouncer"].contains(&pool_name);
+
+    let user_names = match admin {
+        true => None,
+        false => config
+            .pools
+            .get(pool_name)
+            .and_then(|pool| pool.users.values().find(|user| user.username == username))
+            .and_then(|user| user.tls_client_certificate_names.clone()),
+    };
+
+    if auth_method != AuthMethod::Cert && user_names.is_none() {
+        return Some(auth_method);
+    }
+
+    // Certificates map to the user with the same name, unless the user says otherwise.
+    let allowed_names = match user_names {
+        Some(names) => names,
+        None if admin && config.general.admin_username != username => Vec::new(),
+        None => vec![username.to_string()],
+    };
+
+    if certificate_names
+        .iter()
+        .any(|name| allowed_names.contains(name))
+    {
+        debug!(
+            "Client certificate {:?} authenticates user {}",
+            certificate_names, username
+        );
+        return Some(AuthMethod::Trust);
+    }
+
+    match auth_method {
+        AuthMethod::Cert => None,
+        auth_method => Some(auth_method),
+    }
+}
+
+/// Let clients with a certificate issued to the user in without a password.
+/// Clients that must use one and don't are rejected.
+async fn certificate_check<S>(
+    stream: &mut S,
+    auth_method: AuthMethod,
+    certificate_names: &[String],
+    bytes: &BytesMut,
+) -> Result<AuthMethod, Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    if certificate_names.is_empty() && auth_method != AuthMethod::Cert {
+        return Ok(auth_method);
+    }
+
+    let parameters = parse_startup(bytes.clone())?;
+    let username = parameters.get("user").map(|user| user.as_str()).unwrap();
+    let pool_name = match parameters.get("database") {
+        Some(db) => db,
+        None => username,
+    };
+
+    if let Some(auth_method) = certificate_auth(
+        auth_method,
+        certificate_names,
+        username,
+        pool_name,
+        &get_config(),
+    ) {
+        return Ok(auth_method);
+    }
+
+    if certificate_names.is_empty() {
+        error!(
+            "Client rejected: no TLS client certificate for user {}",
+            username
+        );
+    } else {
+        error!(
+            "Client rejected: TLS client certificate {:?} is not allowed for user {}",
+            certificate_names, username
+        );
+    }
+
+    error_response_terminal(
+        stream,
+        &format!(
+            "certificate authentication failed for user \"{}\"",
+            username
+        ),
+    )
+    .await?;
+
+    Err(Error::TlsError)
+}
+
 /// Handle the first message the client sends.
 async fn get_startup<S>(stream: &mut S) -> Result<(ClientConnectionType, BytesMut), Error>
 where
@@ -336,25 +950,53 @@ pub async fn startup_tls(
     admin_only: bool,
 ) -> Result<Client<ReadHalf<TlsStream<TcpStream>>, WriteHalf<TlsStream<TcpStream>>>, Error> {
     // Negotiate TLS.
-    let tls = Tls::new()?;
-    let addr = stream.peer_addr().unwrap();
+    let tls = match get_tls() {
+        Some(tls) => tls,
+        None => {
+            error!("TLS is not configured");
+            return Err(Error::TlsError);
+        }
+    };
+    let addr = ClientAddr::Tcp(stream.peer_addr().unwrap());
 
     let mut stream = match tls.acceptor.accept(stream).await {
         Ok(stream) => stream,
 
         // TLS negotiation failed.
         Err(err) => {
-            error!("TLS negotiation failed: {:?}", err);
+            match err
+                .get_ref()
+                .and_then(|err| err.downcast_ref::<rustls::Error>())
+            {
+                Some(rustls::Error::NoCertificatesPresented) => {
+                    error!("Client {} rejected: no TLS client certificate", addr)
+                }
+                Some(rustls::Error::InvalidCertificate(reason)) => error!(
+                    "Client {} rejected: invalid TLS client certificate: {:?}",
+                    addr, reason
+                ),
+                _ => error!("TLS negotiation failed: {:?}", err),
+            };
+
             return Err(Error::TlsError);
         }
     };
 
+    // Names of the verified client certificate, if the client sent one.
+    let certificate_names = match stream.get_ref().1.peer_certificates() {
+        Some([cert, ..]) => certificate_names(cert),
+        _ => Vec::new(),
+    };
+
     // TLS negotiation successful.
     // Continue with regular startup using encrypted connection.
     match get_startup::<TlsStream<TcpStream>>(&mut stream).await {
         // Got good startup message, proceeding like normal except we
         // are encrypted now.
         Ok((ClientConnectionType::Startup, bytes)) => {
+            let auth_method = hba_check(&mut stream, &addr, true, &bytes).await?;
+            let auth_method =
+                certificate_check(&mut stream, auth_method, &certificate_names, &bytes).await?;
             let (read, write) = split(stream);
 
             Client::startup(
@@ -362,6 +1004,7 @@ pub async fn startup_tls(
                 write,
                 addr,
                 bytes,
+                auth_method,
                 client_server_map,
                 shutdown,
                 admin_only,
@@ -378,6 +1021,190 @@ pub async fn startup_tls(
     }
 }
 
+/// Read a PasswordMessage (or its SASL variants) from the client
+/// and return its payload.
+async fn read_password_message<S>(
+    read: &mut S,
+    client_identifier: &ClientIdentifier,
+) -> Result<Vec<u8>, Error>
+where
+    S: tokio::io::AsyncRead + std::marker::Unpin,
+{
+    let code = match read.read_u8().await {
+        Ok(p) => p,
+        Err(_) => {
+            return Err(Error::ClientSocketError(
+                "password code".into(),
+                client_identifier.clone(),
+            ))
+        }
+    };
+
+    // PasswordMessage
+    if code as char != 'p' {
+        return Err(Error::ProtocolSyncError(format!(
+            "Expected p, got {}",
+            code as char
+        )));
+    }
+
+    let len = match read.read_i32().await {
+        Ok(len) if len >= 4 => len,
+        _ => {
+            return Err(Error::ClientSocketError(
+                "password message length".into(),
+                client_identifier.clone(),
+            ))
+        }
+    };
+
+    let mut password_response = vec![0u8; (len - 4) as usize];
+
+    match read.read_exact(&mut password_response).await {
+        Ok(_) => (),
+        Err(_) => {
+            return Err(Error::ClientSocketError(
+                "password message".into(),
+                client_identifier.clone(),
+            ))
+        }
+    };
+
+    Ok(password_response)
+}
+
+/// Run the SCRAM-SHA-256 exchange with the client against the verifier.
+/// Returns false if the client didn't prove it knows the password.
+async fn scram_authenticate<S, T>(
+    read: &mut S,
+    write: &mut T,
+    verifier: ScramVerifier,
+    client_identifier: &ClientIdentifier,
+) -> Result<bool, Error>
+where
+    S: tokio::io::AsyncRead + std::marker::Unpin,
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let mut scram = ScramSha256Server::new(verifier);
+
+    sasl_challenge(write).await?;
+
+    // SASLInitialResponse: mechanism name, followed by the client-first-message.
+    let mut initial_response =
+        BytesMut::from(&read_password_message(read, client_identifier).await?[..]);
+
+    let mechanism = Cursor::new(&initial_response).read_string()?;
+
+    if mechanism != SCRAM_SHA_256 {
+        return Err(Error::ProtocolSyncError(format!(
+            "Unsupported SASL mechanism: {}",
+            mechanism
+        )));
+    }
+
+    initial_response.advance(mechanism.len() + 1);
+
+    if initial_response.remaining() < 4 {
+        return Err(Error::ProtocolSyncError("SASLInitialResponse".into()));
+    }
+
+    let len = initial_response.get_i32();
+
+    if len < 0 || len as usize != initial_response.remaining() {
+        return Err(Error::ProtocolSyncError("SASLInitialResponse".into()));
+    }
+
+    let server_first = scram.server_first(&initial_response)?;
+    sasl_response(write, SASL_CONTINUE, &server_first).await?;
+
+    // SASLResponse: the client-final-message.
+    let client_final = read_password_message(read, client_identifier).await?;
+
+    match scram.server_final(&client_final) {
+        Ok(server_final) => {
+            sasl_response(write, SASL_FINAL, &server_final).await?;
+            Ok(true)
+        }
+
+        Err(Error::AuthError(_)) => Ok(false),
+
+        Err(err) => Err(err),
+    }
+}
+
+/// Error message for the client when it couldn't get a server connection.
+fn get_connection_error(err: &Error) -> &'static str {
+    match err {
+        Error::MaxWaitQueueReached => "too many clients waiting for a connection (max_wait_queue)",
+        Error::MaxServerConnectionsReached => {
+            "too many server connections (max_db_connections or max_user_connections)"
+        }
+        _ => "could not get connection from the pool",
+    }
+}
+
+/// Clients connected to pgcat, admin clients excluded.
+static CLIENT_CONNS: AtomicUsize = AtomicUsize::new(0);
+
+/// Clients connected to each pool with a `max_client_conn`, by pool name.
+/// Pools without clients are removed, so it only holds pools that exist.
+static POOL_CLIENT_CONNS: Lazy<Mutex<HashMap<String, usize>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// A client counted against `max_client_conn`, until it's dropped.
+/// The pool name is set if it's also counted against the pool's limit.
+struct ClientConn(Option<String>);
+
+impl Drop for ClientConn {
+    fn drop(&mut self) {
+        CLIENT_CONNS.fetch_sub(1, Ordering::Relaxed);
+
+        if let Some(pool_name) = &self.0 {
+            let mut pool_conns = POOL_CLIENT_CONNS.lock();
+
+            if let Some(clients) = pool_conns.get_mut(pool_name) {
+                *clients -= 1;
+
+                if *clients == 0 {
+                    pool_conns.remove(pool_name);
+                }
+            }
+        }
+    }
+}
+
+/// Count the client against the `max_client_conn` limits of pgcat and the pool.
+/// Returns None if one of them is reached.
+fn reserve_client_conn(pool_name: &str, config: &Config) -> Option<ClientConn> {
+    // Count it first, so clients logging in at the same time see each other.
+    let clients = CLIENT_CONNS.fetch_add(1, Ordering::Relaxed);
+    let mut client_conn = ClientConn(None);
+
+    if config.general.max_client_conn > 0 && clients >= config.general.max_client_conn {
+        return None;
+    }
+
+    // The pool name comes from the client before it authenticated,
+    // only pools that exist and have a limit are counted.
+    if let Some(max) = config
+        .pools
+        .get(pool_name)
+        .and_then(|pool| pool.max_client_conn)
+    {
+        let mut pool_conns = POOL_CLIENT_CONNS.lock();
+        let pool_clients = pool_conns.entry(pool_name.to_string()).or_default();
+
+        if *pool_clients >= max {
+            return None;
+        }
+
+        *pool_clients += 1;
+        client_conn.0 = Some(pool_name.to_string());
+    }
+
+    Some(client_conn)
+}
+
 impl<S, T> Client<S, T>
 where
     S: tokio::io::AsyncRead + std::marker::Unpin,
@@ -389,11 +1216,13 @@ where
 
     /// Handle Postgres client startup after TLS negotiation is complete
     /// or over plain text.
+    #[allow(clippy::too_many_arguments)]
     pub async fn startup(
         mut read: S,
         mut write: T,
-        addr: std::net::SocketAddr,
-        bytes: BytesMut, // The rest of the startup message.
+        addr: ClientAddr,
+        bytes: BytesMut,         // The rest of the startup message.
+        auth_method: AuthMethod, // From the hba rules.
         client_server_map: ClientServerMap,
         shutdown: Receiver<()>,
         admin_only: bool,
@@ -446,62 +1275,61 @@ where
         let process_id: i32 = rand::random();
         let secret_key: i32 = rand::random();
 
-        // Perform MD5 authentication.
-        // TODO: Add SASL support.
-        let salt = md5_challenge(&mut write).await?;
+        let config = get_config();
 
-        let code = match read.read_u8().await {
-            Ok(p) => p,
-            Err(_) => {
-                return Err(Error::ClientSocketError(
-                    "password code".into(),
-                    client_identifier,
-                ))
-            }
+        // Reject the client if too many are connected already.
+        let client_conn = match admin {
+            true => None,
+            false => reserve_client_conn(pool_name, &config),
         };
 
-        // PasswordMessage
-        if code as char != 'p' {
-            return Err(Error::ProtocolSyncError(format!(
-                "Expected p, got {}",
-                code as char
-            )));
-        }
-
-        let len = match read.read_i32().await {
-            Ok(len) => len,
-            Err(_) => {
-                return Err(Error::ClientSocketError(
-                    "password message length".into(),
-                    client_identifier,
-                ))
+        if !admin && client_conn.is_none() {
+            if let Some(pool) = get_pool(pool_name, username) {
+                pool.client_conn_rejected();
             }
-        };
 
-        let mut password_response = vec![0u8; (len - 4) as usize];
+            warn!("Rejecting {}, too many clients", client_identifier);
+            error_response_terminal(&mut write, "no more connections allowed (max_client_conn)")
+                .await?;
 
-        match read.read_exact(&mut password_response).await {
-            Ok(_) => (),
-            Err(_) => {
-                return Err(Error::ClientSocketError(
-                    "password message".into(),
-                    client_identifier,
-                ))
-            }
-        };
+            return Err(Error::ClientGeneralError(
+                "Too many clients".into(),
+                client_identifier,
+            ));
+        }
 
         // Authenticate admin user.
         let (transaction_mode, server_info) = if admin {
-            let config = get_config();
+            let authenticated = match auth_method {
+                AuthMethod::Trust => true,
+                AuthMethod::Reject | AuthMethod::Cert => false,
+
+                AuthMethod::ScramSha256 => {
+                    let verifier = ScramVerifier::cached(
+                        &config.general.admin_username,
+                        &config.general.admin_password,
+                    )
+                    .await?;
+                    scram_authenticate(&mut read, &mut write, verifier, &client_identifier).await?
+                }
 
-            // Compare server and client hashes.
-            let password_hash = md5_hash_password(
-                &config.general.admin_username,
-                &config.general.admin_password,
-                &salt,
-            );
+                AuthMethod::Md5 => {
+                    let salt = md5_challenge(&mut write).await?;
+                    let password_response =
+                        read_password_message(&mut read, &client_identifier).await?;
+
+                    // Compare server and client hashes.
+                    let password_hash = md5_hash_password(
+                        &config.general.admin_username,
+                        &config.general.admin_password,
+                        &salt,
+                    );
 
-            if password_hash != password_response {
+                    password_hash == password_response
+                }
+            };
+
+            if !authenticated {
                 let error = Error::ClientGeneralError("Invalid password".into(), client_identifier);
 
                 warn!("{}", error);
@@ -514,9 +1342,31 @@ where
         }
         // Authenticate normal user.
         else {
-            let mut pool = match get_pool(pool_name, username) {
-                Some(pool) => pool,
-                None => {
+            // Clients of databases that aren't configured authenticate against the
+            // auto_db template, their pool is only created once they have.
+            let (pool, auto_db) = match get_pool(pool_name, username) {
+                Some(pool) => (Some(pool), None),
+                None => (None, AutoDb::new(pool_name, username).await),
+            };
+
+            // The user the client logs in as, its password hash from auth_query,
+            // and the server auth_query runs on.
+            let (user, auth_hash, disabled, address, auth_passthrough) = match (&pool, &auto_db) {
+                (Some(pool), _) => (
+                    pool.settings.user.clone(),
+                    pool.auth_hash.clone(),
+                    pool.disabled(),
+                    pool.address(0, 0),
+                    AuthPassthrough::from_pool_settings(&pool.settings),
+                ),
+                (None, Some(auto_db)) => (
+                    auto_db.user.clone(),
+                    auto_db.auth_hash.clone(),
+                    auto_db.disabled(),
+                    auto_db.address.clone(),
+                    auto_db.auth_passthrough(),
+                ),
+                (None, None) => {
                     error_response(
                         &mut write,
                         &format!(
@@ -533,95 +1383,212 @@ where
                 }
             };
 
-            // Obtain the hash to compare, we give preference to that written in cleartext in config
-            // if there is nothing set in cleartext and auth passthrough (auth_query) is configured, we use the hash obtained
-            // when the pool was created. If there is no hash there, we try to fetch it one more time.
-            let password_hash = if let Some(password) = &pool.settings.user.password {
-                Some(md5_hash_password(username, password, &salt))
+            // Connected clients keep working, but new ones are rejected.
+            if disabled {
+                warn!("Rejecting {}, the pool is disabled", client_identifier);
+                error_response_terminal(
+                    &mut write,
+                    &format!("pool {} is disabled, no new connections allowed", pool_name),
+                )
+                .await?;
+
+                return Err(Error::ClientGeneralError(
+                    "Pool disabled".into(),
+                    client_identifier,
+                ));
+            }
+
+            // Trusted clients don't need a password.
+            if auth_method == AuthMethod::Trust {
+                debug!("Trusting {}", client_identifier);
             } else {
-                if !get_config().is_auth_query_configured() {
-                    wrong_password(&mut write, username).await?;
-                    return Err(Error::ClientAuthImpossible(username.into()));
-                }
+                // Obtain the secret to compare, we give preference to that written in cleartext in config
+                // if there is nothing set in cleartext and auth passthrough (auth_query) is configured, we use the hash obtained
+                // when the pool was created. If there is no hash there, we try to fetch it one more time.
+                let hash = if user.password.is_some() {
+                    None
+                } else {
+                    if !config.is_auth_query_configured() {
+                        wrong_password(&mut write, username).await?;
+                        return Err(Error::ClientAuthImpossible(username.into()));
+                    }
 
-                let mut hash = (*pool.auth_hash.read()).clone();
+                    let mut hash = (*auth_hash.read()).clone();
 
-                if hash.is_none() {
-                    warn!(
-                        "Query auth configured \
-                          but no hash password found \
-                          for pool {}. Will try to refetch it.",
-                        pool_name
-                    );
+                    if hash.is_none() {
+                        warn!(
+                            "Query auth configured \
+                              but no hash password found \
+                              for pool {}. Will try to refetch it.",
+                            pool_name
+                        );
 
-                    match refetch_auth_hash(&pool).await {
-                        Ok(fetched_hash) => {
-                            warn!("Password for {}, obtained. Updating.", client_identifier);
+                        match refetch_auth_hash(&address, &auth_passthrough).await {
+                            Ok(fetched_hash) => {
+                                warn!("Password for {}, obtained. Updating.", client_identifier);
 
-                            {
-                                let mut pool_auth_hash = pool.auth_hash.write();
-                                *pool_auth_hash = Some(fetched_hash.clone());
+                                {
+                                    let mut pool_auth_hash = auth_hash.write();
+                                    *pool_auth_hash = Some(fetched_hash.clone());
+                                }
+
+                                hash = Some(fetched_hash);
                             }
 
-                            hash = Some(fetched_hash);
+                            Err(err) => {
+                                wrong_password(&mut write, username).await?;
+
+                                return Err(Error::ClientAuthPassthroughError(
+                                    err.to_string(),
+                                    client_identifier,
+                                ));
+                            }
                         }
+                    };
 
-                        Err(err) => {
-                            wrong_password(&mut write, username).await?;
+                    hash
+                };
 
-                            return Err(Error::ClientAuthPassthroughError(
-                                err.to_string(),
-                                client_identifier,
-                            ));
-                        }
-                    }
+                // SCRAM verifiers can only be checked with SCRAM, whatever auth_type says.
+                let use_scram = match &hash {
+                    Some(hash) => ScramVerifier::is_verifier(hash),
+                    None => auth_method == AuthMethod::ScramSha256,
                 };
 
-                Some(md5_hash_second_pass(&hash.unwrap(), &salt))
-            };
+                if use_scram {
+                    let verifier = match &hash {
+                        Some(hash) => match ScramVerifier::parse(hash) {
+                            Ok(verifier) => verifier,
+                            Err(err) => {
+                                wrong_password(&mut write, username).await?;
+                                return Err(Error::ClientAuthPassthroughError(
+                                    err.to_string(),
+                                    client_identifier,
+                                ));
+                            }
+                        },
+                        None => {
+                            ScramVerifier::cached(username, user.password.as_ref().unwrap()).await?
+                        }
+                    };
 
-            // Once we have the resulting hash, we compare with what the client gave us.
-            // If they do not match and auth query is set up, we try to refetch the hash one more time
-            // to see if the password has changed since the pool was created.
-            //
-            // @TODO: we could end up fetching again the same password twice (see above).
-            if password_hash.unwrap() != password_response {
-                warn!(
-                    "Invalid password {}, will try to refetch it.",
-                    client_identifier
-                );
+                    if !scram_authenticate(&mut read, &mut write, verifier, &client_identifier)
+                        .await?
+                    {
+                        // The SCRAM exchange can't be replayed with a new verifier, but if the password
+                        // has changed in the server, we pick it up so the next attempt succeeds.
+                        if let Some(hash) = hash {
+                            warn!(
+                                "Invalid password {}, will try to refetch it.",
+                                client_identifier
+                            );
+
+                            if let Ok(fetched_hash) =
+                                refetch_auth_hash(&address, &auth_passthrough).await
+                            {
+                                if fetched_hash != hash {
+                                    warn!(
+                                        "Password for {}, changed in server. Updating.",
+                                        client_identifier
+                                    );
+
+                                    let mut pool_auth_hash = auth_hash.write();
+                                    *pool_auth_hash = Some(fetched_hash);
+                                }
+                            }
+                        }
 
-                let fetched_hash = match refetch_auth_hash(&pool).await {
-                    Ok(fetched_hash) => fetched_hash,
-                    Err(err) => {
                         wrong_password(&mut write, username).await?;
-
-                        return Err(err);
+                        return Err(Error::ClientGeneralError(
+                            "Invalid password".into(),
+                            client_identifier,
+                        ));
+                    }
+                } else {
+                    // An md5 hash obtained with auth_query can't be used with SCRAM.
+                    if auth_method == AuthMethod::ScramSha256 {
+                        wrong_password(&mut write, username).await?;
+                        return Err(Error::ClientAuthPassthroughError(
+                            "auth_type is scram-sha-256 but auth_query returned an md5 hash".into(),
+                            client_identifier,
+                        ));
                     }
-                };
 
-                let new_password_hash = md5_hash_second_pass(&fetched_hash, &salt);
+                    // Perform MD5 authentication.
+                    let salt = md5_challenge(&mut write).await?;
+                    let password_response =
+                        read_password_message(&mut read, &client_identifier).await?;
+
+                    let password_hash = match (&user.password, &hash) {
+                        (Some(password), _) => md5_hash_password(username, password, &salt),
+                        (None, hash) => md5_hash_second_pass(hash.as_ref().unwrap(), &salt),
+                    };
+
+                    // Once we have the resulting hash, we compare with what the client gave us.
+                    // If they do not match and auth query is set up, we try to refetch the hash one more time
+                    // to see if the password has changed since the pool was created.
+                    //
+                    // @TODO: we could end up fetching again the same password twice (see above).
+                    if password_hash != password_response {
+                        warn!(
+                            "Invalid password {}, will try to refetch it.",
+                            client_identifier
+                        );
+
+                        let fetched_hash =
+                            match refetch_auth_hash(&address, &auth_passthrough).await {
+                                Ok(fetched_hash) => fetched_hash,
+                                Err(err) => {
+                                    wrong_password(&mut write, username).await?;
+
+                                    return Err(err);
+                                }
+                            };
 
-                // Ok password changed in server an auth is possible.
-                if new_password_hash == password_response {
-                    warn!(
-                        "Password for {}, changed in server. Updating.",
-                        client_identifier
-                    );
+                        let new_password_hash = md5_hash_second_pass(&fetched_hash, &salt);
 
-                    {
-                        let mut pool_auth_hash = pool.auth_hash.write();
-                        *pool_auth_hash = Some(fetched_hash);
+                        // Ok password changed in server an auth is possible.
+                        if new_password_hash == password_response {
+                            warn!(
+                                "Password for {}, changed in server. Updating.",
+                                client_identifier
+                            );
+
+                            {
+                                let mut pool_auth_hash = auth_hash.write();
+                                *pool_auth_hash = Some(fetched_hash);
+                            }
+                        } else {
+                            wrong_password(&mut write, username).await?;
+                            return Err(Error::ClientGeneralError(
+                                "Invalid password".into(),
+                                client_identifier,
+                            ));
+                        }
                     }
-                } else {
-                    wrong_password(&mut write, username).await?;
-                    return Err(Error::ClientGeneralError(
-                        "Invalid password".into(),
-                        client_identifier,
-                    ));
                 }
             }
 
+            // Only authenticated clients create auto_db pools.
+            let mut pool = match pool {
+                Some(pool) => pool,
+                None => match auto_db.unwrap().create(client_server_map.clone()).await {
+                    Ok(pool) => pool,
+                    Err(err) => {
+                        error_response(
+                            &mut write,
+                            &format!(
+                                "Could not create a pool for database: {:?}, user: {:?}",
+                                pool_name, username
+                            ),
+                        )
+                        .await?;
+
+                        return Err(err);
+                    }
+                },
+            };
+
             let transaction_mode = pool.settings.pool_mode == PoolMode::Transaction;
 
             // If the pool hasn't been validated yet,
@@ -682,6 +1649,14 @@ where
             application_name: application_name.to_string(),
             shutdown,
             connected_to_server: false,
+            prepared_statements_enabled: get_config().general.prepared_statements,
+            prepared_statements: HashMap::new(),
+            max_prepared_statements: get_config().general.max_prepared_statements,
+            prepared_statements_batch: None,
+            _client_conn: client_conn,
+            track_session_parameters: get_config().general.track_session_parameters,
+            session_parameters: SessionParameters::default(),
+            last_writes: HashMap::new(),
         })
     }
 
@@ -689,7 +1664,7 @@ where
     pub async fn cancel(
         read: S,
         write: T,
-        addr: std::net::SocketAddr,
+        addr: ClientAddr,
         mut bytes: BytesMut, // The rest of the startup message.
         client_server_map: ClientServerMap,
         shutdown: Receiver<()>,
@@ -716,6 +1691,14 @@ where
             application_name: String::from("undefined"),
             shutdown,
             connected_to_server: false,
+            prepared_statements_enabled: false,
+            prepared_statements: HashMap::new(),
+            max_prepared_statements: 0,
+            prepared_statements_batch: None,
+            _client_conn: None,
+            track_session_parameters: false,
+            session_parameters: SessionParameters::default(),
+            last_writes: HashMap::new(),
         })
     }
 
@@ -725,16 +1708,14 @@ where
         if self.cancel_mode {
             trace!("Sending CancelRequest");
 
-            let (process_id, secret_key, address, port) = {
+            let servers = {
                 let guard = self.client_server_map.lock();
 
                 match guard.get(&(self.process_id, self.secret_key)) {
                     // Drop the mutex as soon as possible.
-                    // We found the server the client is using for its query
-                    // that it wants to cancel.
-                    Some((process_id, secret_key, address, port)) => {
-                        (*process_id, *secret_key, address.clone(), *port)
-                    }
+                    // We found the servers the client is using for its query
+                    // that it wants to cancel, several for multi-shard queries.
+                    Some(servers) => servers.clone(),
 
                     // The client doesn't know / got the wrong server,
                     // we're closing the connection for security reasons.
@@ -742,21 +1723,37 @@ where
                 }
             };
 
-            // Opens a new separate connection to the server, sends the backend_id
+            // Opens a new separate connection to each server, sends the backend_id
             // and secret_key and then closes it for security reasons. No other interactions
             // take place.
-            return Server::cancel(&address, port, process_id, secret_key).await;
+            let mut result = Ok(());
+
+            for (process_id, secret_key, address, port) in servers {
+                if let Err(err) = Server::cancel(&address, port, process_id, secret_key).await {
+                    result = Err(err);
+                }
+            }
+
+            return result;
         }
 
         // The query router determines where the query is going to go,
         // e.g. primary, replica, which shard.
         let mut query_router = QueryRouter::new();
 
+        // Load the pool settings now, so the first query is routed with them too.
+        if let Some(pool) = get_pool(&self.pool_name, &self.username) {
+            query_router.update_pool_settings(pool.settings.clone());
+        }
+
         self.stats.register(self.stats.clone());
 
         // Result returned by one of the plugins.
         let mut plugin_output = None;
 
+        // Sharding key of the extended protocol query that isn't in any shard.
+        let mut sharding_error = None;
+
         // Our custom protocol loop.
         // We expect the client to either start a transaction with regular queries
         // or issue commands for our sharding and server selection protocol.
@@ -789,6 +1786,16 @@ where
                         read_message(&mut self.read).await?
                     }
                 },
+                _ = self.stats.killed() => {
+                    error_response_terminal(
+                        &mut self.write,
+                        "terminating connection due to administrator command"
+                    ).await?;
+
+                    self.stats.disconnect();
+                    return Ok(());
+                },
+
                 message_result = read_message(&mut self.read) => message_result?
             };
 
@@ -824,7 +1831,12 @@ where
                                 _ => (),
                             };
 
-                            let _ = query_router.infer(&ast);
+                            if let Err(err @ Error::ShardingKeyNotInShard(_)) =
+                                query_router.infer(&ast)
+                            {
+                                error_response(&mut self.write, &err.to_string()).await?;
+                                continue;
+                            }
                         }
                     }
                 }
@@ -838,7 +1850,11 @@ where
                                 plugin_output = Some(output);
                             }
 
-                            let _ = query_router.infer(&ast);
+                            if let Err(err @ Error::ShardingKeyNotInShard(_)) =
+                                query_router.infer(&ast)
+                            {
+                                sharding_error = Some(err);
+                            }
                         }
                     }
 
@@ -849,7 +1865,9 @@ where
                     self.buffer.put(&message[..]);
 
                     if query_router.query_parser_enabled() {
-                        query_router.infer_shard_from_bind(&message);
+                        if let Err(err) = query_router.infer_shard_from_bind(&message) {
+                            sharding_error = Some(err);
+                        }
                     }
 
                     continue;
@@ -873,6 +1891,14 @@ where
                 continue;
             }
 
+            // The query can't be routed, a plugin result doesn't matter.
+            if let Some(err) = sharding_error.take() {
+                self.buffer.clear();
+                error_response(&mut self.write, &err.to_string()).await?;
+                plugin_output = None;
+                continue;
+            }
+
             // Check on plugin results.
             match plugin_output {
                 Some(PluginOutput::Deny(error)) => {
@@ -903,10 +1929,17 @@ where
             // Handle all custom protocol commands, if any.
             match query_router.try_execute_command(&message) {
                 // Normal query, not a custom command.
-                None => (),
+                Ok(None) => (),
+
+                // The sharding key in the comment isn't in any shard.
+                Err(err) => {
+                    self.buffer.clear();
+                    error_response(&mut self.write, &err.to_string()).await?;
+                    continue;
+                }
 
                 // SET SHARD TO
-                Some((Command::SetShard, _)) => {
+                Ok(Some((Command::SetShard, _))) => {
                     // Selected shard is not configured.
                     if query_router.shard() >= pool.shards() {
                         // Set the shard back to what it was.
@@ -929,42 +1962,63 @@ where
                 }
 
                 // SET PRIMARY READS TO
-                Some((Command::SetPrimaryReads, _)) => {
+                Ok(Some((Command::SetPrimaryReads, _))) => {
                     custom_protocol_response_ok(&mut self.write, "SET PRIMARY READS").await?;
                     continue;
                 }
 
                 // SET SHARDING KEY TO
-                Some((Command::SetShardingKey, _)) => {
-                    custom_protocol_response_ok(&mut self.write, "SET SHARDING KEY").await?;
+                Ok(Some((Command::SetShardingKey, value))) => {
+                    // The sharding key isn't in any shard.
+                    if value.is_empty() {
+                        error_response(
+                            &mut self.write,
+                            &format!(
+                                "sharding key is not in any shard, staying on shard {}",
+                                query_router.shard()
+                            ),
+                        )
+                        .await?;
+                    } else {
+                        custom_protocol_response_ok(&mut self.write, "SET SHARDING KEY").await?;
+                    }
                     continue;
                 }
 
                 // SET SERVER ROLE TO
-                Some((Command::SetServerRole, _)) => {
+                Ok(Some((Command::SetServerRole, _))) => {
                     custom_protocol_response_ok(&mut self.write, "SET SERVER ROLE").await?;
                     continue;
                 }
 
                 // SHOW SERVER ROLE
-                Some((Command::ShowServerRole, value)) => {
+                Ok(Some((Command::ShowServerRole, value))) => {
                     show_response(&mut self.write, "server role", &value).await?;
                     continue;
                 }
 
                 // SHOW SHARD
-                Some((Command::ShowShard, value)) => {
+                Ok(Some((Command::ShowShard, value))) => {
                     show_response(&mut self.write, "shard", &value).await?;
                     continue;
                 }
 
                 // SHOW PRIMARY READS
-                Some((Command::ShowPrimaryReads, value)) => {
+                Ok(Some((Command::ShowPrimaryReads, value))) => {
                     show_response(&mut self.write, "primary reads", &value).await?;
                     continue;
                 }
             };
 
+            // Read-only query that runs on multiple shards.
+            if let Some(scatter_gather) = query_router.take_scatter_gather() {
+                if message[0] as char == 'Q' {
+                    self.scatter_gather(&message, &scatter_gather, &pool, query_router.role())
+                        .await?;
+                    continue;
+                }
+            }
+
             debug!("Waiting for connection from pool");
             if !self.admin {
                 self.stats.waiting();
@@ -972,7 +2026,12 @@ where
 
             // Grab a server from the pool.
             let connection = match pool
-                .get(query_router.shard(), query_router.role(), &self.stats)
+                .get(
+                    query_router.shard(),
+                    query_router.role(),
+                    &self.stats,
+                    self.min_replay_lsn(query_router.shard(), &pool),
+                )
                 .await
             {
                 Ok(conn) => {
@@ -991,8 +2050,7 @@ where
                         self.buffer.clear();
                     }
 
-                    error_response(&mut self.write, "could not get connection from the pool")
-                        .await?;
+                    error_response(&mut self.write, get_connection_error(&err)).await?;
 
                     error!(
                         "Could not get connection from pool: \
@@ -1030,7 +2088,7 @@ where
             self.last_server_stats = Some(server.stats());
 
             debug!(
-                "Client {:?} talking to server {:?}",
+                "Client {} talking to server {:?}",
                 self.addr,
                 server.address()
             );
@@ -1040,6 +2098,36 @@ where
             // Set application_name.
             server.set_name(&self.application_name).await?;
 
+            // Replay the parameters the client set on other servers. Clients that don't
+            // track them reset the ones left by the clients that do.
+            let parameter_status = match server.sync_parameters(&self.session_parameters).await {
+                Ok(parameter_status) => parameter_status,
+                Err(err) => {
+                    // The server was marked bad, it doesn't go back to the pool.
+                    self.stats.idle();
+                    self.buffer.clear();
+                    self.connected_to_server = false;
+                    self.release();
+
+                    error_response(
+                        &mut self.write,
+                        "could not restore the session parameters on the server",
+                    )
+                    .await?;
+
+                    error!(
+                        "Could not restore the session parameters of {} on {:?}: {:?}",
+                        self.addr, address, err
+                    );
+
+                    continue;
+                }
+            };
+
+            if !parameter_status.is_empty() {
+                write_all(&mut self.write, parameter_status).await?;
+            }
+
             let mut initial_message = Some(message);
 
             let idle_client_timeout_duration = match get_idle_client_in_transaction_timeout() {
@@ -1058,12 +2146,26 @@ where
                     None => {
                         trace!("Waiting for message inside transaction or in session mode");
 
-                        match tokio::time::timeout(
-                            idle_client_timeout_duration,
-                            read_message(&mut self.read),
-                        )
-                        .await
-                        {
+                        let read = tokio::select! {
+                            read = tokio::time::timeout(
+                                idle_client_timeout_duration,
+                                read_message(&mut self.read),
+                            ) => read,
+
+                            _ = self.stats.killed() => {
+                                error_response_terminal(
+                                    &mut self.write,
+                                    "terminating connection due to administrator command"
+                                ).await?;
+
+                                self.stats.disconnect();
+                                server.checkin_cleanup().await?;
+
+                                return Ok(());
+                            }
+                        };
+
+                        match read {
                             Ok(Ok(message)) => message,
                             Ok(Err(err)) => {
                                 // Client disconnected inside a transaction.
@@ -1132,8 +2234,19 @@ where
                                 };
 
                                 let _ = query_router.infer(&ast);
+
+                                // We're already attached to a server, the query runs on it.
+                                query_router.take_scatter_gather();
                             }
                         }
+
+                        let query = String::from_utf8_lossy(&message[5..message.len() - 1]);
+                        let changes = match self.track_session_parameters {
+                            true => SessionParameters::parse(&query),
+                            false => Vec::new(),
+                        };
+                        let needs_cleanup_set = server.needs_cleanup_set();
+
                         debug!("Sending query to server");
 
                         self.send_and_receive_loop(
@@ -1146,6 +2259,21 @@ where
                         )
                         .await?;
 
+                        let tracked = !changes.is_empty()
+                            && SessionParameters::tracked(&query, server.commands_completed());
+
+                        // SET in a transaction block is only tracked if it's committed by the same query.
+                        if !changes.is_empty()
+                            && !server.in_transaction()
+                            && !server.error_received()
+                        {
+                            self.update_session_parameters(changes, server).await?;
+
+                            if tracked {
+                                server.set_needs_cleanup_set(needs_cleanup_set);
+                            }
+                        }
+
                         if !server.in_transaction() {
                             // Report transaction executed statistics.
                             self.stats.transaction();
@@ -1234,8 +2362,12 @@ where
 
                         let first_message_code = (*self.buffer.get(0).unwrap_or(&0)) as char;
 
+                        if self.prepared_statements_enabled {
+                            // Named statements are shared between clients and stay on the server.
+                            self.rewrite_prepared_statements(server)?;
+                        }
                         // Almost certainly true
-                        if first_message_code == 'P' {
+                        else if first_message_code == 'P' {
                             // Message layout
                             // P followed by 32 int followed by null-terminated statement name
                             // So message code should be in offset 0 of the buffer, first character
@@ -1260,6 +2392,14 @@ where
 
                         self.buffer.clear();
 
+                        if let Some(batch) = self.prepared_statements_batch.take() {
+                            if batch.failed {
+                                for name in batch.prepared.iter() {
+                                    server.prepared_statements().remove(name);
+                                }
+                            }
+                        }
+
                         if !server.in_transaction() {
                             self.stats.transaction();
                             server.stats().transaction(&self.application_name);
@@ -1329,6 +2469,19 @@ where
                 }
             }
 
+            // Reads should see this write, even if they go to a replica.
+            let select_writes = query_router.take_select_writes();
+
+            if self.transaction_mode
+                && pool.settings.read_your_writes_window > 0
+                && query_router.query_parser_enabled()
+                && address.role == Role::Primary
+                && (server.wrote() || select_writes)
+                && !server.error_received()
+            {
+                self.record_write(server, address.shard).await;
+            }
+
             // The server is no longer bound to us, we can't cancel it's queries anymore.
             debug!("Releasing server back into the pool");
             server.checkin_cleanup().await?;
@@ -1340,6 +2493,36 @@ where
         }
     }
 
+    /// Remember the WAL position of the primary after the client wrote to it.
+    async fn record_write(&mut self, server: &mut Server, shard: usize) {
+        match server
+            .query_values("SELECT (pg_current_wal_lsn() - '0/0')::bigint")
+            .await
+        {
+            Ok(values) => {
+                if let Some(Ok(lsn)) = values.first().map(|value| value.parse::<u64>()) {
+                    debug!("Client wrote to shard {} up to WAL position {}", shard, lsn);
+                    self.last_writes.insert(shard, (lsn, Instant::now()));
+                }
+            }
+
+            Err(err) => warn!(
+                "Could not get the WAL position of {:?}, error: {:?}",
+                server.address(),
+                err
+            ),
+        }
+    }
+
+    /// WAL position that servers must have replayed to get the client's reads,
+    /// if it wrote to the shard recently.
+    fn min_replay_lsn(&self, shard: usize, pool: &ConnectionPool) -> Option<u64> {
+        recent_write_lsn(
+            self.last_writes.get(&shard),
+            pool.settings.read_your_writes_window,
+        )
+    }
+
     /// Retrieve connection pool, if it exists.
     /// Return an error to the client otherwise.
     async fn get_pool(&mut self) -> Result<ConnectionPool, Error> {
@@ -1363,6 +2546,207 @@ where
         }
     }
 
+    /// Send the query to several shards at once and merge their results
+    /// into one response for the client.
+    async fn scatter_gather(
+        &mut self,
+        message: &BytesMut,
+        scatter_gather: &ScatterGather,
+        pool: &ConnectionPool,
+        role: Option<Role>,
+    ) -> Result<(), Error> {
+        debug!("Sending query to shards {:?}", scatter_gather.shards());
+
+        self.stats.waiting();
+
+        // Shards are always checked out in the same order, so clients
+        // can't deadlock waiting on each other's connections.
+        let mut connections = Vec::new();
+
+        for shard in scatter_gather.shards() {
+            match pool
+                .get(*shard, role, &self.stats, self.min_replay_lsn(*shard, pool))
+                .await
+            {
+                Ok(connection) => connections.push(connection),
+                Err(err) => {
+                    self.stats.idle();
+
+                    error_response(&mut self.write, get_connection_error(&err)).await?;
+
+                    error!(
+                        "Could not get connection from pool: \
+                        {{ \
+                            pool_name: {:?}, \
+                            username: {:?}, \
+                            shard: {:?}, \
+                            role: \"{:?}\", \
+                            error: \"{:?}\" \
+                        }}",
+                        self.pool_name, self.username, shard, role, err
+                    );
+
+                    return Ok(());
+                }
+            }
+        }
+
+        self.stats.active();
+
+        // The client can cancel the query on all the shards.
+        self.release();
+
+        for (server, _) in connections.iter_mut() {
+            server.claim_also(self.process_id, self.secret_key);
+        }
+
+        let mut responses = Vec::new();
+
+        let result = self
+            .scatter_gather_query(
+                message,
+                scatter_gather,
+                pool,
+                &mut connections,
+                &mut responses,
+            )
+            .await;
+
+        self.release();
+
+        if let Err(err) = result {
+            // The shards that didn't send their whole response are still running the query,
+            // they can't go back to the pool like this.
+            for (server, _) in connections.iter_mut().skip(responses.len()) {
+                server.mark_bad();
+            }
+
+            return Err(err);
+        }
+
+        for (server, _) in connections.iter_mut() {
+            server.checkin_cleanup().await?;
+            server.stats().idle();
+        }
+
+        drop(connections);
+
+        self.stats.query();
+        self.stats.transaction();
+        self.stats.idle();
+
+        match scatter_gather.merge(responses) {
+            Ok(response) => write_all_flush(&mut self.write, &response).await,
+            Err(err) => {
+                error!("Could not merge multi-shard query results: {:?}", err);
+                error_response(&mut self.write, &err.to_string()).await
+            }
+        }
+    }
+
+    /// Send the query to the shards and read their responses, in the order of the connections.
+    async fn scatter_gather_query(
+        &mut self,
+        message: &BytesMut,
+        scatter_gather: &ScatterGather,
+        pool: &ConnectionPool,
+        connections: &mut [(PooledConnection<'_, ServerPool>, Address)],
+        responses: &mut Vec<BytesMut>,
+    ) -> Result<(), Error> {
+        let message = scatter_gather.message(message);
+
+        // Send the query to all shards first, so they run it concurrently.
+        for (server, address) in connections.iter_mut() {
+            server.set_name(&self.application_name).await?;
+
+            // The client already knows these parameters, they're the same on all shards.
+            server.sync_parameters(&self.session_parameters).await?;
+
+            self.send_server_message(server, &message, address, pool)
+                .await?;
+        }
+
+        let query_start = Instant::now();
+
+        for (server, address) in connections.iter_mut() {
+            let mut response = BytesMut::new();
+
+            loop {
+                response.put(
+                    self.receive_server_message(server, address, pool, &self.stats.clone())
+                        .await?,
+                );
+
+                if !server.is_data_available() {
+                    break;
+                }
+            }
+
+            responses.push(response);
+
+            server.stats().query(
+                Instant::now().duration_since(query_start).as_millis() as u64,
+                &self.application_name,
+            );
+            server.stats().transaction(&self.application_name);
+        }
+
+        Ok(())
+    }
+
+    /// Rewrite the buffered extended protocol messages to use the names the client's
+    /// prepared statements have on the servers, and prepare the ones this server doesn't have yet.
+    fn rewrite_prepared_statements(&mut self, server: &mut Server) -> Result<(), Error> {
+        let (messages, batch) = PreparedStatementsBatch::rewrite(
+            self.buffer.split(),
+            &mut self.prepared_statements,
+            server.prepared_statements(),
+            self.max_prepared_statements,
+        )?;
+
+        self.buffer.put(messages);
+        self.prepared_statements_batch = Some(batch);
+
+        Ok(())
+    }
+
+    /// Record the session parameters the client changed on the server.
+    async fn update_session_parameters(
+        &mut self,
+        changes: Vec<Change>,
+        server: &mut Server,
+    ) -> Result<(), Error> {
+        for change in changes {
+            match change {
+                // We already set application_name on every server the client uses.
+                Change::Set(name, value, _) if name == "application_name" => {
+                    self.application_name = value.trim_matches('\'').to_string();
+                }
+
+                Change::Reset(name) if name == "application_name" => {
+                    self.application_name = self.startup_application_name();
+                }
+
+                Change::ResetAll => {
+                    self.application_name = self.startup_application_name();
+                    self.session_parameters.apply(Change::ResetAll);
+                }
+
+                change => self.session_parameters.apply(change),
+            }
+        }
+
+        server.set_session_parameters(&self.session_parameters);
+        server.set_name(&self.application_name).await
+    }
+
+    fn startup_application_name(&self) -> String {
+        match self.parameters.get("application_name") {
+            Some(application_name) => application_name.clone(),
+            None => String::from("pgcat"),
+        }
+    }
+
     /// Release the server from the client: it can't cancel its queries anymore.
     pub fn release(&self) {
         let mut guard = self.client_server_map.lock();
@@ -1392,10 +2776,14 @@ where
         // Read all data the server has to offer, which can be multiple messages
         // buffered in 8196 bytes chunks.
         loop {
-            let response = self
+            let mut response = self
                 .receive_server_message(server, address, pool, client_stats)
                 .await?;
 
+            if let Some(batch) = self.prepared_statements_batch.as_mut() {
+                response = batch.response(response);
+            }
+
             match write_all_flush(&mut self.write, &response).await {
                 Ok(_) => (),
                 Err(err) => {
@@ -1502,3 +2890,401 @@ impl<S, T> Drop for Client<S, T> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn message(code: u8, body: &[u8]) -> BytesMut {
+        let mut res = BytesMut::new();
+        res.put_u8(code);
+        res.put_i32(body.len() as i32 + 4);
+        res.put_slice(body);
+        res
+    }
+
+    fn parse(name: &str, query: &str) -> BytesMut {
+        message(b'P', format!("{}\0{}\0\0\0", name, query).as_bytes())
+    }
+
+    fn bind(statement: &str) -> BytesMut {
+        message(b'B', format!("\0{}\0\0\0\0\0\0\0", statement).as_bytes())
+    }
+
+    fn close(name: &str) -> BytesMut {
+        message(b'C', format!("S{}\0", name).as_bytes())
+    }
+
+    fn execute() -> BytesMut {
+        message(b'E', b"\0\0\0\0\0")
+    }
+
+    fn sync() -> BytesMut {
+        message(b'S', b"")
+    }
+
+    fn batch(messages: &[BytesMut]) -> BytesMut {
+        let mut res = BytesMut::new();
+        for message in messages {
+            res.put_slice(message);
+        }
+        res
+    }
+
+    fn batch_response(codes: &[&str]) -> BytesMut {
+        let mut res = BytesMut::new();
+        for code in codes {
+            res.put(message(code.as_bytes()[0], b""));
+        }
+        res
+    }
+
+    /// Message codes, and names for the ones naming a statement.
+    fn codes(mut messages: BytesMut) -> Vec<String> {
+        let mut res = Vec::new();
+        while !messages.is_empty() {
+            let len = (&messages[1..5]).get_i32() as usize;
+            let message = messages.split_to(len + 1);
+            let name = match message[0] {
+                b'P' => Parse::try_from(&message).unwrap().name,
+                b'B' => Bind::try_from(&message).unwrap().statement,
+                b'C' if len > 4 => Describe::try_from(&message).unwrap().name,
+                _ => String::new(),
+            };
+            res.push(format!("{}{}", message[0] as char, name));
+        }
+        res
+    }
+
+    fn renamed(query: &str) -> String {
+        Parse::try_from(&parse("", query)).unwrap().rename().name
+    }
+
+    #[test]
+    fn test_certificate_auth() {
+        let mut config = Config::default();
+        let names = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>()
+        };
+        let user = |username: &str, certificate_names: Option<Vec<String>>| crate::config::User {
+            username: username.into(),
+            tls_client_certificate_names: certificate_names,
+            ..Default::default()
+        };
+
+        let mut pool = crate::config::Pool::default();
+        pool.users.insert("0".into(), user("md5_user", None));
+        pool.users
+            .insert("1".into(), user("app", Some(names(&["app.example.com"]))));
+        config.pools.insert("db".into(), pool);
+
+        // The hba method asks for a password, a certificate named like the user doesn't replace it.
+        assert_eq!(
+            certificate_auth(
+                AuthMethod::Md5,
+                &names(&["md5_user"]),
+                "md5_user",
+                "db",
+                &config
+            ),
+            Some(AuthMethod::Md5)
+        );
+
+        // Unless it's one of the user's certificate names.
+        assert_eq!(
+            certificate_auth(
+                AuthMethod::Md5,
+                &names(&["app.example.com"]),
+                "app",
+                "db",
+                &config
+            ),
+            Some(AuthMethod::Trust)
+        );
+        assert_eq!(
+            certificate_auth(AuthMethod::Md5, &names(&["app"]), "app", "db", &config),
+            Some(AuthMethod::Md5)
+        );
+
+        // The cert method requires a matching certificate.
+        assert_eq!(
+            certificate_auth(
+                AuthMethod::Cert,
+                &names(&["md5_user"]),
+                "md5_user",
+                "db",
+                &config
+            ),
+            Some(AuthMethod::Trust)
+        );
+        assert_eq!(
+            certificate_auth(
+                AuthMethod::Cert,
+                &names(&["other"]),
+                "md5_user",
+                "db",
+                &config
+            ),
+            None
+        );
+        assert_eq!(
+            certificate_auth(AuthMethod::Cert, &[], "app", "db", &config),
+            None
+        );
+    }
+
+    #[test]
+    fn test_reserve_client_conn() {
+        let mut config = Config::default();
+        config.pools.insert(
+            "limited".into(),
+            crate::config::Pool {
+                max_client_conn: Some(1),
+                ..Default::default()
+            },
+        );
+
+        let first = reserve_client_conn("limited", &config);
+        assert!(first.is_some());
+        assert!(reserve_client_conn("limited", &config).is_none());
+
+        // Rejected clients don't count, the others are released when dropped.
+        drop(first);
+        let first = reserve_client_conn("limited", &config);
+        assert!(first.is_some());
+
+        config.general.max_client_conn = 2;
+        let second = reserve_client_conn("unlimited", &config);
+        assert!(second.is_some());
+        assert!(reserve_client_conn("unlimited", &config).is_none());
+
+        drop(second);
+        assert!(reserve_client_conn("unlimited", &config).is_some());
+
+        // Only pools with a limit are counted, and only while they have clients.
+        assert!(!POOL_CLIENT_CONNS.lock().contains_key("unlimited"));
+        drop(first);
+        assert!(!POOL_CLIENT_CONNS.lock().contains_key("limited"));
+    }
+
+    #[test]
+    fn test_recent_write_lsn() {
+        let now = Instant::now();
+        assert_eq!(recent_write_lsn(Some(&(42, now)), 1000), Some(42));
+
+        // Disabled, or too long ago.
+        assert_eq!(recent_write_lsn(Some(&(42, now)), 0), None);
+        let before = now - std::time::Duration::from_millis(2000);
+        assert_eq!(recent_write_lsn(Some(&(42, before)), 1000), None);
+
+        assert_eq!(recent_write_lsn(None, 1000), None);
+    }
+
+    #[test]
+    fn test_synthesized_in_order() {
+        let (s1, s2) = (renamed("SELECT 1"), renamed("SELECT 2"));
+        let mut statements = HashMap::new();
+        let mut cache = PreparedStatementCache::default();
+        cache.insert(&s2);
+
+        let (messages, mut batch) = PreparedStatementsBatch::rewrite(
+            batch(&[
+                parse("s1", "SELECT 1"),
+                bind("s1"),
+                execute(),
+                parse("s2", "SELECT 2"),
+                bind("s2"),
+                execute(),
+                sync(),
+            ]),
+            &mut statements,
+            &mut cache,
+            0,
+        )
+        .unwrap();
+
+        // The cached statement isn't prepared again.
+        assert_eq!(
+            codes(messages),
+            vec![
+                format!("C{}", s1),
+                format!("P{}", s1),
+                format!("B{}", s1),
+                "E".into(),
+                format!("B{}", s2),
+                "E".into(),
+                "S".into(),
+            ]
+        );
+
+        let response = batch.response(batch_response(&["3", "1", "2", "C", "2", "C", "Z"]));
+
+        // ParseComplete for s2 goes after the first command, not at the end.
+        assert_eq!(codes(response), vec!["1", "2", "C", "1", "2", "C", "Z"]);
+        assert!(!batch.failed);
+        assert_eq!(batch.prepared, vec![s1]);
+    }
+
+    #[test]
+    fn test_synthesized_split_response() {
+        let s2 = renamed("SELECT 2");
+        let mut cache = PreparedStatementCache::default();
+        cache.insert(&s2);
+
+        let (_, mut batch) = PreparedStatementsBatch::rewrite(
+            batch(&[
+                parse("s1", "SELECT 1"),
+                bind("s1"),
+                execute(),
+                parse("s2", "SELECT 2"),
+                bind("s2"),
+                execute(),
+                sync(),
+            ]),
+            &mut HashMap::new(),
+            &mut cache,
+            0,
+        )
+        .unwrap();
+
+        // The server response comes in several chunks.
+        let mut response = BytesMut::new();
+        for chunk in [&["3", "1"][..], &["2", "C"], &["2", "C", "Z"]] {
+            response.put(batch.response(batch_response(chunk)));
+        }
+
+        assert_eq!(codes(response), vec!["1", "2", "C", "1", "2", "C", "Z"]);
+    }
+
+    #[test]
+    fn test_error_skips_synthesized() {
+        let s2 = renamed("SELECT 2");
+        let mut cache = PreparedStatementCache::default();
+        cache.insert(&s2);
+
+        let (_, mut batch) = PreparedStatementsBatch::rewrite(
+            batch(&[
+                parse("s1", "SELECT 1"),
+                bind("s1"),
+                execute(),
+                parse("s2", "SELECT 2"),
+                sync(),
+            ]),
+            &mut HashMap::new(),
+            &mut cache,
+            0,
+        )
+        .unwrap();
+
+        // The server skips everything until Sync after the error, so do we.
+        let response = batch.response(batch_response(&["3", "1", "2", "E", "Z"]));
+
+        assert_eq!(codes(response), vec!["1", "2", "E", "Z"]);
+        assert!(batch.failed);
+    }
+
+    #[test]
+    fn test_close() {
+        let s1 = renamed("SELECT 1");
+        let mut statements = HashMap::new();
+
+        let (messages, mut batch) = PreparedStatementsBatch::rewrite(
+            batch(&[parse("s1", "SELECT 1"), close("s1"), sync()]),
+            &mut statements,
+            &mut PreparedStatementCache::default(),
+            0,
+        )
+        .unwrap();
+
+        // The statement stays on the server for other clients.
+        assert_eq!(
+            codes(messages),
+            vec![format!("C{}", s1), format!("P{}", s1), "S".into()]
+        );
+        assert!(statements.is_empty());
+
+        let response = batch.response(batch_response(&["3", "1", "Z"]));
+        assert_eq!(codes(response), vec!["1", "3", "Z"]);
+    }
+
+    #[test]
+    fn test_prepare_on_bind() {
+        let s1 = renamed("SELECT 1");
+        let mut statements = HashMap::new();
+        let mut cache = PreparedStatementCache::default();
+
+        // Prepared on another server.
+        PreparedStatementsBatch::rewrite(
+            batch(&[parse("s1", "SELECT 1"), sync()]),
+            &mut statements,
+            &mut PreparedStatementCache::default(),
+            0,
+        )
+        .unwrap();
+
+        let (messages, mut batch) = PreparedStatementsBatch::rewrite(
+            batch(&[bind("s1"), execute(), sync()]),
+            &mut statements,
+            &mut cache,
+            0,
+        )
+        .unwrap();
+
+        assert_eq!(
+            codes(messages),
+            vec![
+                format!("C{}", s1),
+                format!("P{}", s1),
+                format!("B{}", s1),
+                "E".into(),
+                "S".into(),
+            ]
+        );
+        assert!(cache.contains(&s1));
+
+        // The client didn't ask for these.
+        let response = batch.response(batch_response(&["3", "1", "2", "C", "Z"]));
+        assert_eq!(codes(response), vec!["2", "C", "Z"]);
+    }
+
+    #[test]
+    fn test_evict() {
+        let (s1, s2) = (renamed("SELECT 1"), renamed("SELECT 2"));
+        let mut cache = PreparedStatementCache::default();
+        cache.insert("old");
+
+        let (messages, _) = PreparedStatementsBatch::rewrite(
+            batch(&[
+                parse("s1", "SELECT 1"),
+                bind("s1"),
+                parse("s2", "SELECT 2"),
+                bind("s2"),
+                sync(),
+            ]),
+            &mut HashMap::new(),
+            &mut cache,
+            1,
+        )
+        .unwrap();
+
+        // Statements used by the batch aren't closed, even past the limit.
+        assert_eq!(
+            codes(messages),
+            vec![
+                format!("C{}", s1),
+                format!("P{}", s1),
+                "Cold".into(),
+                format!("B{}", s1),
+                format!("C{}", s2),
+                format!("P{}", s2),
+                format!("B{}", s2),
+                "S".into(),
+            ]
+        );
+        assert!(!cache.contains("old"));
+        assert_eq!(cache.len(), 2);
+    }
+}
@@ -1,29 +1,37 @@
 /// Parse the configuration file.
 use arc_swap::ArcSwap;
-use log::{error, info};
+use log::{error, info, warn};
 use once_cell::sync::Lazy;
 use regex::Regex;
 use serde_derive::{Deserialize, Serialize};
 use std::collections::hash_map::DefaultHasher;
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::hash::{Hash, Hasher};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
 
 use crate::dns_cache::CachedResolver;
 use crate::errors::Error;
+use crate::hba::HbaRule;
 use crate::pool::{ClientServerMap, ConnectionPool};
-use crate::sharding::ShardingFunction;
+use crate::scram::clear_verifiers;
+use crate::sharding::{ShardKeys, ShardingFunction};
 use crate::stats::AddressStats;
-use crate::tls::{load_certs, load_keys};
+use crate::tls::{load_certs, load_keys, reload_tls};
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 /// Globally available configuration.
 static CONFIG: Lazy<ArcSwap<Config>> = Lazy::new(|| ArcSwap::from_pointee(Config::default()));
 
+/// The config as read from the file, before any admin SET.
+static FILE_CONFIG: Lazy<ArcSwap<Config>> = Lazy::new(|| ArcSwap::from_pointee(Config::default()));
+
+/// Held while RELOAD or SET change the config, so they don't overwrite each other's changes.
+static CONFIG_LOCK: Lazy<tokio::sync::Mutex<()>> = Lazy::new(|| tokio::sync::Mutex::new(()));
+
 /// Server role: primary or replica.
 #[derive(Clone, PartialEq, Serialize, Deserialize, Hash, std::cmp::Eq, Debug, Copy)]
 pub enum Role {
@@ -197,6 +205,7 @@ pub struct User {
     pub server_lifetime: Option<u64>,
     #[serde(default)] // 0
     pub statement_timeout: u64,
+    pub tls_client_certificate_names: Option<Vec<String>>,
 }
 
 impl Default for User {
@@ -211,11 +220,20 @@ impl Default for User {
             statement_timeout: 0,
             pool_mode: None,
             server_lifetime: None,
+            tls_client_certificate_names: None,
         }
     }
 }
 
 impl User {
+    /// The user pgcat logs in as on the servers.
+    pub fn server_username(&self) -> &str {
+        match self.server_username {
+            Some(ref server_username) => server_username,
+            None => &self.username,
+        }
+    }
+
     fn validate(&self) -> Result<(), Error> {
         match self.min_pool_size {
             Some(min_pool_size) => {
@@ -244,11 +262,20 @@ pub struct General {
     #[serde(default = "General::default_port")]
     pub port: u16,
 
+    pub unix_socket_dir: Option<String>,
+
+    pub upgrade_socket: Option<String>,
+
     pub enable_prometheus_exporter: Option<bool>,
 
     #[serde(default = "General::default_prometheus_exporter_port")]
     pub prometheus_exporter_port: i16,
 
+    #[serde(default)] // false
+    pub enable_admin_api: bool,
+
+    pub admin_api_token: Option<String>,
+
     #[serde(default = "General::default_connect_timeout")]
     pub connect_timeout: u64,
 
@@ -292,24 +319,56 @@ pub struct General {
     #[serde(default = "General::default_server_lifetime")]
     pub server_lifetime: u64,
 
+    #[serde(default)] // false
+    pub prepared_statements: bool,
+
+    #[serde(default = "General::default_max_prepared_statements")]
+    pub max_prepared_statements: usize,
+
+    #[serde(default)] // false
+    pub track_session_parameters: bool,
+
     #[serde(default = "General::default_worker_threads")]
     pub worker_threads: usize,
 
+    #[serde(default)] // 0
+    pub max_client_conn: usize,
+
+    #[serde(default)] // 0
+    pub max_db_connections: usize,
+
+    #[serde(default)] // 0
+    pub max_user_connections: usize,
+
+    pub auto_db: Option<String>,
+
+    #[serde(default = "General::default_auto_db_idle_timeout")]
+    pub auto_db_idle_timeout: u64,
+
     #[serde(default)] // None
     pub autoreload: Option<u64>,
 
     pub tls_certificate: Option<String>,
     pub tls_private_key: Option<String>,
+    pub tls_client_ca_certificate: Option<String>,
 
     #[serde(default)] // false
-    pub server_tls: bool,
+    pub tls_client_certificate_required: bool,
 
-    #[serde(default)] // false
-    pub verify_server_certificate: bool,
+    pub tls_certificate_check_interval: Option<u64>,
+
+    #[serde(default = "General::default_server_tls_mode")]
+    pub server_tls_mode: ServerTlsMode,
+    pub server_tls_ca_certificate: Option<String>,
+    pub server_tls_certificate: Option<String>,
+    pub server_tls_private_key: Option<String>,
 
     pub admin_username: String,
     pub admin_password: String,
 
+    #[serde(default = "General::default_auth_type")]
+    pub auth_type: AuthType,
+
     #[serde(default = "General::default_validate_config")]
     pub validate_config: bool,
 
@@ -336,6 +395,10 @@ impl General {
         1000
     }
 
+    pub fn default_auto_db_idle_timeout() -> u64 {
+        1000 * 60 * 60 // 1 hour
+    }
+
     // These keepalive defaults should detect a dead connection within 30 seconds.
     // Tokio defaults to disabling keepalives which keeps dead connections around indefinitely.
     // This can lead to permanent server pool exhaustion
@@ -379,6 +442,10 @@ impl General {
         4
     }
 
+    pub fn default_max_prepared_statements() -> usize {
+        100
+    }
+
     pub fn default_idle_client_in_transaction_timeout() -> u64 {
         0
     }
@@ -387,9 +454,24 @@ impl General {
         true
     }
 
+    pub fn default_auth_type() -> AuthType {
+        AuthType::Md5
+    }
+
+    pub fn default_server_tls_mode() -> ServerTlsMode {
+        ServerTlsMode::Disable
+    }
+
     pub fn default_prometheus_exporter_port() -> i16 {
         9930
     }
+
+    /// Path of the Unix socket clients can connect to, named like the Postgres one.
+    pub fn unix_socket_path(&self) -> Option<String> {
+        self.unix_socket_dir
+            .as_ref()
+            .map(|dir| format!("{}/.s.PGSQL.{}", dir.trim_end_matches('/'), self.port))
+    }
 }
 
 impl Default for General {
@@ -397,8 +479,12 @@ impl Default for General {
         General {
             host: Self::default_host(),
             port: Self::default_port(),
+            unix_socket_dir: None,
+            upgrade_socket: None,
             enable_prometheus_exporter: Some(false),
             prometheus_exporter_port: 9930,
+            enable_admin_api: false,
+            admin_api_token: None,
             connect_timeout: General::default_connect_timeout(),
             idle_timeout: General::default_idle_timeout(),
             shutdown_timeout: Self::default_shutdown_timeout(),
@@ -417,19 +503,103 @@ impl Default for General {
             dns_max_ttl: Self::default_dns_max_ttl(),
             tls_certificate: None,
             tls_private_key: None,
-            server_tls: false,
-            verify_server_certificate: false,
+            tls_client_ca_certificate: None,
+            tls_client_certificate_required: false,
+            tls_certificate_check_interval: None,
+            server_tls_mode: Self::default_server_tls_mode(),
+            server_tls_ca_certificate: None,
+            server_tls_certificate: None,
+            server_tls_private_key: None,
             admin_username: String::from("admin"),
             admin_password: String::from("admin"),
+            auth_type: Self::default_auth_type(),
             auth_query: None,
             auth_query_user: None,
             auth_query_password: None,
             server_lifetime: 1000 * 3600 * 24, // 24 hours,
+            prepared_statements: false,
+            max_prepared_statements: Self::default_max_prepared_statements(),
+            track_session_parameters: false,
+            max_client_conn: 0,
+            max_db_connections: 0,
+            max_user_connections: 0,
+            auto_db: None,
+            auto_db_idle_timeout: Self::default_auto_db_idle_timeout(),
             validate_config: true,
         }
     }
 }
 
+/// Client authentication method:
+/// - md5: MD5 challenge, like Postgres' md5 method,
+/// - scram-sha-256: SASL exchange, like Postgres' scram-sha-256 method.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Copy, Hash)]
+pub enum AuthType {
+    #[serde(alias = "md5", alias = "MD5")]
+    Md5,
+
+    #[serde(
+        alias = "scram-sha-256",
+        alias = "scram_sha_256",
+        alias = "SCRAM-SHA-256"
+    )]
+    ScramSha256,
+}
+
+impl std::fmt::Display for AuthType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            AuthType::Md5 => write!(f, "md5"),
+            AuthType::ScramSha256 => write!(f, "scram-sha-256"),
+        }
+    }
+}
+
+/// How to use TLS with the servers, like libpq's sslmode:
+/// - disable: never,
+/// - prefer: if the server supports it, without verifying its certificate,
+/// - require: always, verifying the certificate only if we have a CA for it,
+/// - verify-ca: always, verifying the certificate was issued by a trusted CA,
+/// - verify-full: always, also verifying the certificate is for the server host.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Copy, Hash)]
+pub enum ServerTlsMode {
+    #[serde(alias = "disable")]
+    Disable,
+
+    #[serde(alias = "prefer")]
+    Prefer,
+
+    #[serde(alias = "require")]
+    Require,
+
+    #[serde(alias = "verify-ca", alias = "verify_ca")]
+    VerifyCa,
+
+    #[serde(alias = "verify-full", alias = "verify_full")]
+    VerifyFull,
+}
+
+impl std::fmt::Display for ServerTlsMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            ServerTlsMode::Disable => write!(f, "disable"),
+            ServerTlsMode::Prefer => write!(f, "prefer"),
+            ServerTlsMode::Require => write!(f, "require"),
+            ServerTlsMode::VerifyCa => write!(f, "verify-ca"),
+            ServerTlsMode::VerifyFull => write!(f, "verify-full"),
+        }
+    }
+}
+
+/// TLS settings for the servers of a pool.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerTls {
+    pub mode: ServerTlsMode,
+    pub ca_certificate: Option<String>,
+    pub certificate: Option<String>,
+    pub private_key: Option<String>,
+}
+
 /// Pool mode:
 /// - transaction: server serves one transaction,
 /// - session: server is attached to the client.
@@ -487,6 +657,46 @@ pub struct Pool {
     #[serde(default)] // False
     pub primary_reads_enabled: bool,
 
+    /// Send read-only queries without a single sharding key to all (or the matching) shards
+    /// and merge the results.
+    #[serde(default)] // False
+    pub scatter_gather_enabled: bool,
+
+    /// How often to check which server is the primary with `pg_is_in_recovery()`
+    /// and update the roles after a failover. Disabled if 0.
+    #[serde(default)] // 0
+    pub topology_check_interval: u64,
+
+    /// Don't send queries to replicas that are further behind the primary than this (ms).
+    /// Disabled if 0.
+    #[serde(default)] // 0
+    pub max_replica_lag: u64,
+
+    /// How often to measure the replication lag of the replicas.
+    #[serde(default = "Pool::default_replica_lag_check_interval")]
+    pub replica_lag_check_interval: u64,
+
+    /// After a client writes, send its reads only to the primary and replicas that replayed
+    /// the write for this long (ms). Disabled if 0.
+    #[serde(default)] // 0
+    pub read_your_writes_window: u64,
+
+    /// Maximum number of clients connected to this pool, for all users.
+    pub max_client_conn: Option<usize>,
+
+    /// Maximum number of clients waiting for a server connection, per user.
+    /// Clients over this get an error instead of waiting. Unlimited if 0.
+    #[serde(default)] // 0
+    pub max_wait_queue: usize,
+
+    /// Maximum number of server connections to each database of this pool,
+    /// counting connections of all pools to the same database.
+    pub max_db_connections: Option<usize>,
+
+    /// Maximum number of server connections of each user of this pool,
+    /// counting connections of all pools to the same server.
+    pub max_user_connections: Option<usize>,
+
     /// Maximum time to allow for establishing a new server connection.
     pub connect_timeout: Option<u64>,
 
@@ -515,6 +725,12 @@ pub struct Pool {
     #[serde(default = "Pool::default_cleanup_server_connections")]
     pub cleanup_server_connections: bool,
 
+    /// Override the general server TLS settings for this pool.
+    pub server_tls_mode: Option<ServerTlsMode>,
+    pub server_tls_ca_certificate: Option<String>,
+    pub server_tls_certificate: Option<String>,
+    pub server_tls_private_key: Option<String>,
+
     pub plugins: Option<Plugins>,
     pub shards: BTreeMap<String, Shard>,
     pub users: BTreeMap<String, User>,
@@ -536,6 +752,33 @@ impl Pool {
             && self.auth_query_password.is_some()
     }
 
+    /// The config of a pool created from this `auto_db` template for a database
+    /// and user that aren't configured. The servers are the template's, and the
+    /// user settings are those of the template user with the same name or,
+    /// failing that, of its first user, authenticated with auth_query.
+    pub fn auto_db(&self, database: &str, username: &str) -> Pool {
+        let mut pool = self.clone();
+
+        for shard in pool.shards.values_mut() {
+            shard.database = database.to_string();
+        }
+
+        let user = match self.users.values().find(|user| user.username == username) {
+            Some(user) => user.clone(),
+            None => User {
+                username: username.to_string(),
+                password: None,
+                server_username: None,
+                server_password: None,
+                tls_client_certificate_names: None,
+                ..self.users.values().next().cloned().unwrap_or_default()
+            },
+        };
+
+        pool.users = BTreeMap::from([("0".to_string(), user)]);
+        pool
+    }
+
     pub fn default_pool_mode() -> PoolMode {
         PoolMode::Transaction
     }
@@ -560,7 +803,14 @@ impl Pool {
         true
     }
 
+    pub fn default_replica_lag_check_interval() -> u64 {
+        1000
+    }
+
+    /// Validate the pool. Every error is logged before failing.
     pub fn validate(&mut self) -> Result<(), Error> {
+        let mut results = Vec::new();
+
         match self.default_role.as_ref() {
             "any" => (),
             "primary" => (),
@@ -570,7 +820,7 @@ impl Pool {
                     "Query router default_role must be 'primary', 'replica', or 'any', got: '{}'",
                     other
                 );
-                return Err(Error::BadConfig);
+                results.push(Err(Error::BadConfig));
             }
         };
 
@@ -582,12 +832,14 @@ impl Pool {
                         "Shard '{}' is not a valid number, shards must be numbered starting at 0",
                         shard_idx
                     );
-                    return Err(Error::BadConfig);
+                    results.push(Err(Error::BadConfig));
                 }
             };
-            shard.validate()?;
+            results.push(shard.validate());
         }
 
+        results.push(self.validate_shard_keys());
+
         for (option, name) in [
             (&self.shard_id_regex, "shard_id_regex"),
             (&self.sharding_key_regex, "sharding_key_regex"),
@@ -595,36 +847,121 @@ impl Pool {
             if let Some(regex) = option {
                 if let Err(parse_err) = Regex::new(regex.as_str()) {
                     error!("{} is not a valid Regex: {}", name, parse_err);
-                    return Err(Error::BadConfig);
+                    results.push(Err(Error::BadConfig));
                 }
             }
         }
 
-        self.automatic_sharding_key = match &self.automatic_sharding_key {
-            Some(key) => {
-                // No quotes in the key so we don't have to compare quoted
-                // to unquoted idents.
-                let key = key.replace("\"", "");
+        if let Some(ref key) = self.automatic_sharding_key {
+            // No quotes in the key so we don't have to compare quoted
+            // to unquoted idents.
+            let key = key.replace("\"", "");
+
+            if key.split(".").count() != 2 {
+                error!(
+                    "automatic_sharding_key '{}' must be fully qualified, e.g. t.{}`",
+                    key, key
+                );
+                results.push(Err(Error::BadConfig));
+            }
+
+            self.automatic_sharding_key = Some(key);
+        }
+
+        for (_, user) in &self.users {
+            results.push(user.validate());
+        }
+
+        results.into_iter().collect()
+    }
+
+    /// Check the keys of the shards, used by the range and list sharding functions.
+    fn validate_shard_keys(&self) -> Result<(), Error> {
+        let mut ranges = Vec::new();
+        let mut keys = HashSet::new();
+
+        for (shard_idx, shard) in &self.shards {
+            match (self.sharding_function, &shard.key_range, &shard.keys) {
+                (ShardingFunction::Range, Some([start, end]), None) => {
+                    if start >= end {
+                        error!("Shard {} key_range must end after its first key", shard_idx);
+                        return Err(Error::BadConfig);
+                    }
+
+                    ranges.push((*start, *end, shard_idx));
+                }
+
+                (ShardingFunction::List, None, Some(shard_keys)) => {
+                    for key in shard_keys {
+                        if !keys.insert(*key) {
+                            error!("Key {} is in more than one shard", key);
+                            return Err(Error::BadConfig);
+                        }
+                    }
+                }
+
+                (ShardingFunction::Range, _, _) => {
+                    error!(
+                        "Shard {} must have a key_range, and no keys, with the range sharding function",
+                        shard_idx
+                    );
+                    return Err(Error::BadConfig);
+                }
 
-                if key.split(".").count() != 2 {
+                (ShardingFunction::List, _, _) => {
                     error!(
-                        "automatic_sharding_key '{}' must be fully qualified, e.g. t.{}`",
-                        key, key
+                        "Shard {} must have keys, and no key_range, with the list sharding function",
+                        shard_idx
                     );
                     return Err(Error::BadConfig);
                 }
 
-                Some(key)
+                (_, None, None) => (),
+
+                (sharding_function, _, _) => {
+                    error!(
+                        "Shard {} has keys, which aren't used with the {} sharding function",
+                        shard_idx,
+                        sharding_function.to_string()
+                    );
+                    return Err(Error::BadConfig);
+                }
             }
-            None => None,
-        };
+        }
 
-        for (_, user) in &self.users {
-            user.validate()?;
+        ranges.sort();
+
+        for pair in ranges.windows(2) {
+            if pair[0].1 > pair[1].0 {
+                error!(
+                    "Shards {} and {} have overlapping key ranges",
+                    pair[0].2, pair[1].2
+                );
+                return Err(Error::BadConfig);
+            }
         }
 
         Ok(())
     }
+
+    /// Keys of each shard, for the range and list sharding functions.
+    pub fn shard_keys(&self) -> ShardKeys {
+        let mut shard_keys = ShardKeys::default();
+
+        for (shard_idx, shard) in &self.shards {
+            let shard_idx = shard_idx.parse::<usize>().unwrap();
+
+            if let Some([start, end]) = shard.key_range {
+                shard_keys.add_range(shard_idx, start..end);
+            }
+
+            if let Some(ref keys) = shard.keys {
+                shard_keys.add_keys(shard_idx, keys);
+            }
+        }
+
+        shard_keys
+    }
 }
 
 impl Default for Pool {
@@ -637,6 +974,15 @@ impl Default for Pool {
             default_role: String::from("any"),
             query_parser_enabled: false,
             primary_reads_enabled: false,
+            scatter_gather_enabled: false,
+            topology_check_interval: 0,
+            max_replica_lag: 0,
+            replica_lag_check_interval: Self::default_replica_lag_check_interval(),
+            read_your_writes_window: 0,
+            max_client_conn: None,
+            max_wait_queue: 0,
+            max_db_connections: None,
+            max_user_connections: None,
             sharding_function: ShardingFunction::PgBigintHash,
             automatic_sharding_key: None,
             connect_timeout: None,
@@ -650,6 +996,10 @@ impl Default for Pool {
             server_lifetime: None,
             plugins: None,
             cleanup_server_connections: true,
+            server_tls_mode: None,
+            server_tls_ca_certificate: None,
+            server_tls_certificate: None,
+            server_tls_private_key: None,
         }
     }
 }
@@ -674,6 +1024,12 @@ pub struct Shard {
     pub database: String,
     pub mirrors: Option<Vec<MirrorServerConfig>>,
     pub servers: Vec<ServerConfig>,
+
+    /// Keys of the shard with the range sharding function, from the first one to the last one (exclusive).
+    pub key_range: Option<[i64; 2]>,
+
+    /// Keys of the shard with the list sharding function.
+    pub keys: Option<Vec<i64>>,
 }
 
 impl Shard {
@@ -724,6 +1080,8 @@ impl Default for Shard {
             }],
             mirrors: None,
             database: String::from("postgres"),
+            key_range: None,
+            keys: None,
         }
     }
 }
@@ -815,6 +1173,11 @@ pub struct Config {
     #[serde(default = "Config::default_path")]
     pub path: String,
 
+    // Settings with values interpolated from the environment or files,
+    // redacted in SHOW CONFIG.
+    #[serde(skip)]
+    pub secrets: HashSet<String>,
+
     // General and global settings.
     pub general: General,
 
@@ -823,9 +1186,81 @@ pub struct Config {
 
     // Connection pools.
     pub pools: HashMap<String, Pool>,
+
+    // Client access rules, checked in order.
+    #[serde(default)] // No rules
+    pub hba: Vec<HbaRule>,
 }
 
 impl Config {
+    /// The config of a pool. Pools created for `auto_db` use their template's.
+    pub fn pool_config(&self, pool_name: &str) -> Option<&Pool> {
+        self.pools
+            .get(pool_name)
+            .or_else(|| self.auto_db_template())
+    }
+
+    /// The config as TOML, with the passwords, tokens and interpolated secrets redacted.
+    pub fn to_toml(&self) -> Result<String, Error> {
+        let mut value = match toml::Value::try_from(self) {
+            Ok(value) => value,
+            Err(err) => {
+                error!("Could not serialize the config: {}", err);
+                return Err(Error::BadConfig);
+            }
+        };
+
+        redact(&mut value);
+
+        for secret in self.secrets.iter() {
+            let mut setting = Some(&mut value);
+
+            for key in secret.split('.') {
+                setting = setting.and_then(|setting| setting.get_mut(key));
+            }
+
+            if let Some(setting) = setting {
+                *setting = toml::Value::String("<redacted>".to_string());
+            }
+        }
+
+        match toml::to_string_pretty(&value) {
+            Ok(toml) => Ok(toml),
+            Err(err) => {
+                error!("Could not serialize the config: {}", err);
+                Err(Error::BadConfig)
+            }
+        }
+    }
+
+    /// The template pool of `auto_db`, if it's enabled.
+    pub fn auto_db_template(&self) -> Option<&Pool> {
+        self.general
+            .auto_db
+            .as_ref()
+            .and_then(|auto_db| self.pools.get(auto_db))
+    }
+
+    /// TLS settings for the servers of the pool, the pool settings override the general ones.
+    pub fn server_tls(&self, pool_name: &str) -> ServerTls {
+        let pool = self.pool_config(pool_name);
+
+        ServerTls {
+            mode: pool
+                .and_then(|pool| pool.server_tls_mode)
+                .unwrap_or(self.general.server_tls_mode),
+            ca_certificate: pool
+                .and_then(|pool| pool.server_tls_ca_certificate.clone())
+                .or_else(|| self.general.server_tls_ca_certificate.clone()),
+            certificate: pool
+                .and_then(|pool| pool.server_tls_certificate.clone())
+                .or_else(|| self.general.server_tls_certificate.clone()),
+            private_key: pool
+                .and_then(|pool| pool.server_tls_private_key.clone())
+                .or_else(|| self.general.server_tls_private_key.clone()),
+        }
+    }
+
     pub fn is_auth_query_configured(&self) -> bool {
         self.pools
             .iter()
@@ -857,9 +1292,11 @@ impl Default for Config {
     fn default() -> Config {
         Config {
             path: Self::default_path(),
+            secrets: HashSet::new(),
             general: General::default(),
             pools: HashMap::default(),
             plugins: None,
+            hba: Vec::new(),
         }
     }
 }
@@ -887,6 +1324,51 @@ impl From<&Config> for std::collections::HashMap<String, String> {
                         format!("pools.{}.query_parser_enabled", pool_name),
                         pool.query_parser_enabled.to_string(),
                     ),
+                    (
+                        format!("pools.{}.scatter_gather_enabled", pool_name),
+                        pool.scatter_gather_enabled.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.topology_check_interval", pool_name),
+                        pool.topology_check_interval.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.max_replica_lag", pool_name),
+                        pool.max_replica_lag.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.replica_lag_check_interval", pool_name),
+                        pool.replica_lag_check_interval.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.read_your_writes_window", pool_name),
+                        pool.read_your_writes_window.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.max_client_conn", pool_name),
+                        match pool.max_client_conn {
+                            Some(max) => max.to_string(),
+                            None => "unlimited".to_string(),
+                        },
+                    ),
+                    (
+                        format!("pools.{}.max_wait_queue", pool_name),
+                        pool.max_wait_queue.to_string(),
+                    ),
+                    (
+                        format!("pools.{}.max_db_connections", pool_name),
+                        match pool.max_db_connections {
+                            Some(max) => max.to_string(),
+                            None => config.general.max_db_connections.to_string(),
+                        },
+                    ),
+                    (
+                        format!("pools.{}.max_user_connections", pool_name),
+                        match pool.max_user_connections {
+                            Some(max) => max.to_string(),
+                            None => config.general.max_user_connections.to_string(),
+                        },
+                    ),
                     (
                         format!("pools.{}.default_role", pool_name),
                         pool.default_role.clone(),
@@ -915,10 +1397,22 @@ impl From<&Config> for std::collections::HashMap<String, String> {
         let mut static_settings = vec![
             ("host".to_string(), config.general.host.to_string()),
             ("port".to_string(), config.general.port.to_string()),
+            (
+                "unix_socket_dir".to_string(),
+                config.general.unix_socket_dir.clone().unwrap_or_default(),
+            ),
+            (
+                "upgrade_socket".to_string(),
+                config.general.upgrade_socket.clone().unwrap_or_default(),
+            ),
             (
                 "prometheus_exporter_port".to_string(),
                 config.general.prometheus_exporter_port.to_string(),
             ),
+            (
+                "enable_admin_api".to_string(),
+                config.general.enable_admin_api.to_string(),
+            ),
             (
                 "connect_timeout".to_string(),
                 config.general.connect_timeout.to_string(),
@@ -947,16 +1441,170 @@ impl From<&Config> for std::collections::HashMap<String, String> {
                     .idle_client_in_transaction_timeout
                     .to_string(),
             ),
+            (
+                "auth_type".to_string(),
+                config.general.auth_type.to_string(),
+            ),
+            ("hba_rules".to_string(), config.hba.len().to_string()),
+            (
+                "prepared_statements".to_string(),
+                config.general.prepared_statements.to_string(),
+            ),
+            (
+                "max_prepared_statements".to_string(),
+                config.general.max_prepared_statements.to_string(),
+            ),
+            (
+                "track_session_parameters".to_string(),
+                config.general.track_session_parameters.to_string(),
+            ),
+            (
+                "max_client_conn".to_string(),
+                config.general.max_client_conn.to_string(),
+            ),
+            (
+                "max_db_connections".to_string(),
+                config.general.max_db_connections.to_string(),
+            ),
+            (
+                "max_user_connections".to_string(),
+                config.general.max_user_connections.to_string(),
+            ),
+            (
+                "auto_db".to_string(),
+                config.general.auto_db.clone().unwrap_or_default(),
+            ),
+            (
+                "auto_db_idle_timeout".to_string(),
+                config.general.auto_db_idle_timeout.to_string(),
+            ),
         ];
 
+        let mut user_settings: Vec<(String, String)> = config
+            .pools
+            .iter()
+            .flat_map(|(pool_name, pool)| {
+                pool.users.values().flat_map(move |user| {
+                    [
+                        (
+                            format!("pools.{}.users.{}.pool_size", pool_name, user.username),
+                            user.pool_size.to_string(),
+                        ),
+                        (
+                            format!(
+                                "pools.{}.users.{}.statement_timeout",
+                                pool_name, user.username
+                            ),
+                            user.statement_timeout.to_string(),
+                        ),
+                    ]
+                })
+            })
+            .collect();
+
+        r.append(&mut user_settings);
         r.append(&mut static_settings);
+
+        for (key, value) in r.iter_mut() {
+            // General settings are shown without their section.
+            if config.secrets.contains(key) || config.secrets.contains(&format!("general.{}", key))
+            {
+                *value = "<redacted>".to_string();
+            }
+        }
+
         return r.iter().cloned().collect();
     }
 }
 
 impl Config {
+    /// Change a setting that can be changed at runtime with the admin SET command,
+    /// e.g. `ban_time` or `pools.simple_db.users.simple_user.pool_size`.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        let parts: Vec<&str> = key.split('.').collect();
+
+        match parts.as_slice() {
+            ["ban_time"] => self.general.ban_time = parse_setting(key, value)?,
+            ["healthcheck_delay"] => self.general.healthcheck_delay = parse_setting(key, value)?,
+            ["healthcheck_timeout"] => {
+                self.general.healthcheck_timeout = parse_setting(key, value)?
+            }
+            ["idle_client_in_transaction_timeout"] => {
+                self.general.idle_client_in_transaction_timeout = parse_setting(key, value)?
+            }
+            ["max_client_conn"] => self.general.max_client_conn = parse_setting(key, value)?,
+            ["max_db_connections"] => self.general.max_db_connections = parse_setting(key, value)?,
+            ["max_user_connections"] => {
+                self.general.max_user_connections = parse_setting(key, value)?
+            }
+
+            ["pools", pool_name, setting] => {
+                let pool = match self.pools.get_mut(*pool_name) {
+                    Some(pool) => pool,
+                    None => {
+                        error!("No pool named {}", pool_name);
+                        return Err(Error::BadConfig);
+                    }
+                };
+
+                match *setting {
+                    "max_client_conn" => pool.max_client_conn = Some(parse_setting(key, value)?),
+                    "max_wait_queue" => pool.max_wait_queue = parse_setting(key, value)?,
+                    "max_db_connections" => {
+                        pool.max_db_connections = Some(parse_setting(key, value)?)
+                    }
+                    "max_user_connections" => {
+                        pool.max_user_connections = Some(parse_setting(key, value)?)
+                    }
+                    _ => {
+                        error!("{} can't be changed at runtime", key);
+                        return Err(Error::BadConfig);
+                    }
+                }
+            }
+
+            ["pools", pool_name, "users", username, setting] => {
+                let user = match self.pools.get_mut(*pool_name).and_then(|pool| {
+                    pool.users
+                        .values_mut()
+                        .find(|user| user.username == *username)
+                }) {
+                    Some(user) => user,
+                    None => {
+                        error!("No user {} in pool {}", username, pool_name);
+                        return Err(Error::BadConfig);
+                    }
+                };
+
+                match *setting {
+                    "pool_size" => user.pool_size = parse_setting(key, value)?,
+                    "min_pool_size" => user.min_pool_size = Some(parse_setting(key, value)?),
+                    "statement_timeout" => user.statement_timeout = parse_setting(key, value)?,
+                    _ => {
+                        error!("{} can't be changed at runtime", key);
+                        return Err(Error::BadConfig);
+                    }
+                }
+            }
+
+            _ => {
+                error!("{} can't be changed at runtime", key);
+                return Err(Error::BadConfig);
+            }
+        };
+
+        Ok(())
+    }
+
     /// Print current configuration.
     pub fn show(&self) {
+        info!(
+            "Admin API: {}",
+            match self.general.enable_admin_api {
+                true => "enabled",
+                false => "disabled",
+            }
+        );
         info!("Ban time: {}s", self.general.ban_time);
         info!(
             "Idle client in transaction timeout: {}ms",
@@ -979,18 +1627,83 @@ impl Config {
         );
         info!("Shutdown timeout: {}ms", self.general.shutdown_timeout);
         info!("Healthcheck delay: {}ms", self.general.healthcheck_delay);
+        info!("Client auth type: {}", self.general.auth_type);
         info!(
-            "Default max server lifetime: {}ms",
-            self.general.server_lifetime
+            "Client hba rules: {}",
+            match self.hba.len() {
+                0 => "none".to_string(),
+                rules => rules.to_string(),
+            }
         );
-        match self.general.tls_certificate.clone() {
-            Some(tls_certificate) => {
+        info!("Prepared statements: {}", self.general.prepared_statements);
+        info!(
+            "Max prepared statements per server: {}",
+            match self.general.max_prepared_statements {
+                0 => "unlimited".to_string(),
+                max => max.to_string(),
+            }
+        );
+        info!(
+            "Max client connections: {}",
+            match self.general.max_client_conn {
+                0 => "unlimited".to_string(),
+                max => max.to_string(),
+            }
+        );
+        info!(
+            "Max server connections per database: {}",
+            match self.general.max_db_connections {
+                0 => "unlimited".to_string(),
+                max => max.to_string(),
+            }
+        );
+        info!(
+            "Max server connections per user: {}",
+            match self.general.max_user_connections {
+                0 => "unlimited".to_string(),
+                max => max.to_string(),
+            }
+        );
+        match self.general.auto_db {
+            Some(ref auto_db) => info!(
+                "Auto database template: {}, idle timeout: {}ms",
+                auto_db, self.general.auto_db_idle_timeout
+            ),
+            None => info!("Auto database: disabled"),
+        };
+        info!(
+            "Default max server lifetime: {}ms",
+            self.general.server_lifetime
+        );
+        match self.general.tls_certificate.clone() {
+            Some(tls_certificate) => {
                 info!("TLS certificate: {}", tls_certificate);
 
                 match self.general.tls_private_key.clone() {
                     Some(tls_private_key) => {
                         info!("TLS private key: {}", tls_private_key);
                         info!("TLS support is enabled");
+
+                        match self.general.tls_client_ca_certificate {
+                            Some(ref ca) => info!(
+                                "TLS client certificates: {}, verified with {}",
+                                if self.general.tls_client_certificate_required {
+                                    "required"
+                                } else {
+                                    "optional"
+                                },
+                                ca
+                            ),
+                            None => info!("TLS client certificates: disabled"),
+                        }
+
+                        info!(
+                            "TLS certificate check interval: {}",
+                            match self.general.tls_certificate_check_interval {
+                                Some(interval) => format!("{}ms", interval),
+                                None => "disabled".into(),
+                            }
+                        );
                     }
 
                     None => (),
@@ -1001,11 +1714,7 @@ impl Config {
                 info!("TLS support is disabled");
             }
         };
-        info!("Server TLS enabled: {}", self.general.server_tls);
-        info!(
-            "Server TLS certificate verification: {}",
-            self.general.verify_server_certificate
-        );
+        info!("Server TLS mode: {}", self.general.server_tls_mode);
         info!(
             "Plugins: {}",
             match self.plugins {
@@ -1061,6 +1770,72 @@ impl Config {
                 "[pool: {}] Query router: {}",
                 pool_name, pool_config.query_parser_enabled
             );
+            info!(
+                "[pool: {}] Scatter-gather: {}",
+                pool_name, pool_config.scatter_gather_enabled
+            );
+            info!(
+                "[pool: {}] Topology check interval: {}",
+                pool_name,
+                match pool_config.topology_check_interval {
+                    0 => "disabled".to_string(),
+                    interval => format!("{}ms", interval),
+                }
+            );
+            info!(
+                "[pool: {}] Maximum replica lag: {}",
+                pool_name,
+                match pool_config.max_replica_lag {
+                    0 => "disabled".to_string(),
+                    lag => format!("{}ms", lag),
+                }
+            );
+            info!(
+                "[pool: {}] Read your writes window: {}",
+                pool_name,
+                match pool_config.read_your_writes_window {
+                    0 => "disabled".to_string(),
+                    window => format!("{}ms", window),
+                }
+            );
+            info!(
+                "[pool: {}] Max client connections: {}",
+                pool_name,
+                match pool_config.max_client_conn {
+                    Some(max) => max.to_string(),
+                    None => "unlimited".to_string(),
+                }
+            );
+            info!(
+                "[pool: {}] Max wait queue: {}",
+                pool_name,
+                match pool_config.max_wait_queue {
+                    0 => "unlimited".to_string(),
+                    max => max.to_string(),
+                }
+            );
+            info!(
+                "[pool: {}] Max server connections per database: {}",
+                pool_name,
+                match pool_config
+                    .max_db_connections
+                    .unwrap_or(self.general.max_db_connections)
+                {
+                    0 => "unlimited".to_string(),
+                    max => max.to_string(),
+                }
+            );
+            info!(
+                "[pool: {}] Max server connections per user: {}",
+                pool_name,
+                match pool_config
+                    .max_user_connections
+                    .unwrap_or(self.general.max_user_connections)
+                {
+                    0 => "unlimited".to_string(),
+                    max => max.to_string(),
+                }
+            );
             info!(
                 "[pool: {}] Number of shards: {}",
                 pool_name,
@@ -1083,6 +1858,11 @@ impl Config {
                 "[pool: {}] Cleanup server connections: {}",
                 pool_name, pool_config.cleanup_server_connections
             );
+            info!(
+                "[pool: {}] Server TLS mode: {}",
+                pool_name,
+                self.server_tls(pool_name).mode
+            );
             info!(
                 "[pool: {}] Plugins: {}",
                 pool_name,
@@ -1129,7 +1909,34 @@ impl Config {
         }
     }
 
+    /// Validate the config. Every error is logged before failing.
     pub fn validate(&mut self) -> Result<(), Error> {
+        let mut results = vec![self.validate_auth(), self.validate_tls()];
+
+        for (pool_name, pool) in self.pools.iter() {
+            results.push(Self::validate_pool_auth(pool_name, pool));
+            results.push(self.validate_server_tls(pool_name));
+        }
+
+        for pool in self.pools.values_mut() {
+            results.push(pool.validate());
+        }
+
+        for rule in self.hba.iter() {
+            results.push(rule.validate());
+        }
+
+        results.into_iter().collect()
+    }
+
+    fn validate_auth(&self) -> Result<(), Error> {
+        if self.general.enable_admin_api
+            && !matches!(self.general.admin_api_token, Some(ref token) if !token.is_empty())
+        {
+            error!("enable_admin_api requires admin_api_token");
+            return Err(Error::BadConfig);
+        }
+
         // Validation for auth_query feature
         if self.general.auth_query.is_some()
             && (self.general.auth_query_user.is_none()
@@ -1145,39 +1952,64 @@ impl Config {
             return Err(Error::BadConfig);
         }
 
-        for (name, pool) in self.pools.iter() {
-            if pool.auth_query.is_some()
-                && (pool.auth_query_user.is_none() || pool.auth_query_password.is_none())
+        if let Some(ref auto_db) = self.general.auto_db {
+            match self.pools.get(auto_db) {
+                Some(pool) if pool.is_auth_query_configured() => (),
+                Some(_) => {
+                    error!(
+                        "auto_db pool {} needs auth_query to authenticate its users",
+                        auto_db
+                    );
+                    return Err(Error::BadConfig);
+                }
+                None => {
+                    error!("auto_db pool {} does not exist", auto_db);
+                    return Err(Error::BadConfig);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_pool_auth(name: &str, pool: &Pool) -> Result<(), Error> {
+        let mut results = Vec::new();
+
+        if pool.auth_query.is_some()
+            && (pool.auth_query_user.is_none() || pool.auth_query_password.is_none())
+        {
+            error!(
+                "Error in pool {{ {} }}. \
+                If auth_query is specified, you need \
+                to provide a value for `auth_query_user`, \
+                `auth_query_password`",
+                name
+            );
+
+            results.push(Err(Error::BadConfig));
+        }
+
+        for (_name, user_data) in pool.users.iter() {
+            if (pool.auth_query.is_none()
+                || pool.auth_query_password.is_none()
+                || pool.auth_query_user.is_none())
+                && user_data.password.is_none()
             {
                 error!(
                     "Error in pool {{ {} }}. \
-                    If auth_query is specified, you need \
-                    to provide a value for `auth_query_user`, \
-                    `auth_query_password`",
-                    name
+                    You have to specify a password for user {} \
+                    if auth_query is not specified",
+                    name, user_data.username
                 );
 
-                return Err(Error::BadConfig);
-            }
-
-            for (_name, user_data) in pool.users.iter() {
-                if (pool.auth_query.is_none()
-                    || pool.auth_query_password.is_none()
-                    || pool.auth_query_user.is_none())
-                    && user_data.password.is_none()
-                {
-                    error!(
-                        "Error in pool {{ {} }}. \
-                        You have to specify a user password \
-                        for every pool if auth_query is not specified",
-                        name
-                    );
-
-                    return Err(Error::BadConfig);
-                }
+                results.push(Err(Error::BadConfig));
             }
         }
 
+        results.into_iter().collect()
+    }
+
+    fn validate_tls(&self) -> Result<(), Error> {
         // Validate TLS!
         match self.general.tls_certificate.clone() {
             Some(tls_certificate) => {
@@ -1198,6 +2030,16 @@ impl Config {
                                 return Err(Error::BadConfig);
                             }
                         };
+
+                        if let Some(ref ca) = self.general.tls_client_ca_certificate {
+                            if let Err(err) = load_certs(Path::new(ca)) {
+                                error!(
+                                    "tls_client_ca_certificate is incorrectly configured: {:?}",
+                                    err
+                                );
+                                return Err(Error::BadConfig);
+                            }
+                        }
                     }
 
                     Err(err) => {
@@ -1209,8 +2051,53 @@ impl Config {
             None => (),
         };
 
-        for pool in self.pools.values_mut() {
-            pool.validate()?;
+        if self.general.tls_client_certificate_required
+            && self.general.tls_client_ca_certificate.is_none()
+        {
+            error!(
+                "tls_client_certificate_required is set, but the tls_client_ca_certificate is not"
+            );
+            return Err(Error::BadConfig);
+        }
+
+        Ok(())
+    }
+
+    fn validate_server_tls(&self, pool_name: &str) -> Result<(), Error> {
+        let server_tls = self.server_tls(pool_name);
+
+        if server_tls.certificate.is_some() != server_tls.private_key.is_some() {
+            error!(
+                "Error in pool {{ {} }}. server_tls_certificate and server_tls_private_key must be set together",
+                pool_name
+            );
+            return Err(Error::BadConfig);
+        }
+
+        for path in [&server_tls.ca_certificate, &server_tls.certificate]
+            .into_iter()
+            .flatten()
+        {
+            if let Err(err) = load_certs(Path::new(path)) {
+                error!(
+                    "Error in pool {{ {} }}. Server TLS certificate {} is incorrectly configured: {:?}",
+                    pool_name, path, err
+                );
+                return Err(Error::BadConfig);
+            }
+        }
+
+        if let Some(ref path) = server_tls.private_key {
+            match load_keys(Path::new(path)) {
+                Ok(keys) if !keys.is_empty() => (),
+                result => {
+                    error!(
+                        "Error in pool {{ {} }}. server_tls_private_key {} is incorrectly configured: {:?}",
+                        pool_name, path, result
+                    );
+                    return Err(Error::BadConfig);
+                }
+            }
         }
 
         Ok(())
@@ -1224,6 +2111,11 @@ pub fn get_config() -> Config {
     (*(*CONFIG.load())).clone()
 }
 
+/// Get the configuration as read from the file, without the changes made with SET.
+pub fn get_file_config() -> Config {
+    (*(*FILE_CONFIG.load())).clone()
+}
+
 pub fn get_idle_client_in_transaction_timeout() -> u64 {
     (*(*CONFIG.load()))
         .general
@@ -1249,7 +2141,55 @@ pub async fn parse(path: &str) -> Result<(), Error> {
         }
     };
 
-    let mut config: Config = match toml::from_str(&contents) {
+    let mut value: toml::Value = match toml::from_str(&contents) {
+        Ok(value) => value,
+        Err(err) => {
+            error!("Could not parse config file: {}", err.to_string());
+            return Err(Error::BadConfig);
+        }
+    };
+
+    let includes = match value
+        .as_table_mut()
+        .and_then(|table| table.remove("include"))
+    {
+        Some(includes) => match includes.try_into::<Vec<String>>() {
+            Ok(includes) => includes,
+            Err(_) => {
+                error!("include must be a list of file patterns");
+                return Err(Error::BadConfig);
+            }
+        },
+        None => Vec::new(),
+    };
+
+    migrate_server_tls(&mut value)?;
+
+    let mut secrets = HashSet::new();
+    interpolate(&mut value, "", &mut secrets)?;
+
+    for pattern in includes {
+        for include in include_files(path, &pattern).await? {
+            let mut included: toml::Value = match tokio::fs::read_to_string(&include).await {
+                Ok(contents) => match toml::from_str(&contents) {
+                    Ok(included) => included,
+                    Err(err) => {
+                        error!("Could not parse {}: {}", include.display(), err);
+                        return Err(Error::BadConfig);
+                    }
+                },
+                Err(err) => {
+                    error!("Could not read {}: {}", include.display(), err);
+                    return Err(Error::BadConfig);
+                }
+            };
+
+            interpolate(&mut included, "", &mut secrets)?;
+            merge_pools(&mut value, included, &include)?;
+        }
+    }
+
+    let mut config: Config = match value.try_into() {
         Ok(config) => config,
         Err(err) => {
             error!("Could not parse config file: {}", err.to_string());
@@ -1257,6 +2197,7 @@ pub async fn parse(path: &str) -> Result<(), Error> {
         }
     };
 
+    config.secrets = secrets;
     config.fill_up_auth_query_config();
     config.validate()?;
 
@@ -1264,11 +2205,279 @@ pub async fn parse(path: &str) -> Result<(), Error> {
 
     // Update the configuration globally.
     CONFIG.store(Arc::new(config.clone()));
+    FILE_CONFIG.store(Arc::new(config));
+
+    Ok(())
+}
+
+/// Replace `general.server_tls` and `general.verify_server_certificate`
+/// of older configs with the `server_tls_mode` they mean.
+fn migrate_server_tls(value: &mut toml::Value) -> Result<(), Error> {
+    let general = match value
+        .get_mut("general")
+        .and_then(|general| general.as_table_mut())
+    {
+        Some(general) => general,
+        None => return Ok(()),
+    };
+
+    let server_tls = general.remove("server_tls");
+    let verify_server_certificate = general.remove("verify_server_certificate");
+
+    if server_tls.is_none() && verify_server_certificate.is_none() {
+        return Ok(());
+    }
+
+    let flag = |name: &str, value: Option<toml::Value>| match value {
+        None => Ok(false),
+        Some(toml::Value::Boolean(value)) => Ok(value),
+        Some(_) => {
+            error!(
+                "{} must be true or false, use server_tls_mode instead",
+                name
+            );
+            Err(Error::BadConfig)
+        }
+    };
+
+    let server_tls = flag("server_tls", server_tls)?;
+    let verify_server_certificate = flag("verify_server_certificate", verify_server_certificate)?;
+
+    let mode = match (server_tls, verify_server_certificate) {
+        (false, _) => ServerTlsMode::Disable,
+        (true, false) => ServerTlsMode::Require,
+        (true, true) => ServerTlsMode::VerifyFull,
+    };
+
+    if general.contains_key("server_tls_mode") {
+        error!(
+            "server_tls and verify_server_certificate are replaced by server_tls_mode, remove them"
+        );
+        return Err(Error::BadConfig);
+    }
+
+    warn!(
+        "server_tls and verify_server_certificate are deprecated, use server_tls_mode = \"{}\" instead",
+        mode
+    );
+
+    general.insert(
+        "server_tls_mode".to_string(),
+        toml::Value::String(mode.to_string()),
+    );
+
+    Ok(())
+}
+
+/// Settings that are always redacted from the config, wherever they are.
+const SECRET_SETTINGS: [&str; 5] = [
+    "password",
+    "server_password",
+    "admin_password",
+    "auth_query_password",
+    "admin_api_token",
+];
+
+/// Replace the values of the secret settings by `<redacted>`.
+fn redact(value: &mut toml::Value) {
+    match value {
+        toml::Value::Table(table) => {
+            for (key, value) in table.iter_mut() {
+                if SECRET_SETTINGS.contains(&key.as_str()) {
+                    *value = toml::Value::String("<redacted>".to_string());
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        toml::Value::Array(array) => array.iter_mut().for_each(redact),
+        _ => (),
+    }
+}
+
+/// Replace `${VAR}` with the environment variable and `${file:/path}` with the
+/// contents of the file, in the string values of the config. The settings that
+/// were interpolated are added to `secrets`.
+fn interpolate(
+    value: &mut toml::Value,
+    key: &str,
+    secrets: &mut HashSet<String>,
+) -> Result<(), Error> {
+    static VARIABLE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\$\{([^}]*)\}").unwrap());
+
+    match value {
+        toml::Value::String(string) => {
+            if !VARIABLE.is_match(string) {
+                return Ok(());
+            }
+
+            let mut interpolated = String::new();
+            let mut last = 0;
+
+            for captures in VARIABLE.captures_iter(string) {
+                let variable = captures.get(0).unwrap();
+                let name = &captures[1];
+
+                let resolved = match name.strip_prefix("file:") {
+                    Some(file) => match std::fs::read_to_string(file) {
+                        Ok(contents) => contents.trim_end_matches(['\r', '\n']).to_string(),
+                        Err(err) => {
+                            error!("Could not read {} for {}: {}", file, key, err);
+                            return Err(Error::BadConfig);
+                        }
+                    },
+                    None => match std::env::var(name) {
+                        Ok(resolved) => resolved,
+                        Err(_) => {
+                            error!("Environment variable {} for {} is not set", name, key);
+                            return Err(Error::BadConfig);
+                        }
+                    },
+                };
+
+                interpolated.push_str(&string[last..variable.start()]);
+                interpolated.push_str(&resolved);
+                last = variable.end();
+            }
+
+            interpolated.push_str(&string[last..]);
+            *string = interpolated;
+
+            secrets.insert(key.to_string());
+        }
+
+        toml::Value::Array(array) => {
+            for value in array.iter_mut() {
+                interpolate(value, key, secrets)?;
+            }
+        }
+
+        toml::Value::Table(table) => {
+            for (name, value) in table.iter_mut() {
+                // The intercept plugin substitutes its own ${USER} and ${DATABASE}.
+                if name == "intercept" && (key == "plugins" || key.ends_with(".plugins")) {
+                    continue;
+                }
+
+                let key = match key {
+                    "" => name.clone(),
+                    key => format!("{}.{}", key, name),
+                };
+
+                interpolate(value, &key, secrets)?;
+            }
+        }
+
+        _ => (),
+    };
+
+    Ok(())
+}
+
+/// The files matching an include pattern, relative to the config file, in order.
+/// Only the file name can have `*` and `?` wildcards, e.g. `conf.d/*.toml`.
+async fn include_files(config_path: &str, pattern: &str) -> Result<Vec<PathBuf>, Error> {
+    let pattern = match Path::new(config_path).parent() {
+        Some(dir) => dir.join(pattern),
+        None => PathBuf::from(pattern),
+    };
+
+    let file_name = pattern
+        .file_name()
+        .map(|file_name| file_name.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    if !file_name.contains(['*', '?']) {
+        return Ok(vec![pattern]);
+    }
+
+    let dir = match pattern.parent() {
+        Some(dir) if dir != Path::new("") => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+
+    let regex = format!(
+        "^{}$",
+        regex::escape(&file_name)
+            .replace("\\*", ".*")
+            .replace("\\?", ".")
+    );
+    let regex = Regex::new(&regex).unwrap();
+
+    let mut entries = match tokio::fs::read_dir(&dir).await {
+        Ok(entries) => entries,
+        Err(err) => {
+            error!("Could not read {}: {}", dir.display(), err);
+            return Err(Error::BadConfig);
+        }
+    };
+
+    let mut files = Vec::new();
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if regex.is_match(&entry.file_name().to_string_lossy()) {
+            files.push(entry.path());
+        }
+    }
+
+    files.sort();
+
+    Ok(files)
+}
+
+/// Add the pools of an included file to the config.
+fn merge_pools(
+    config: &mut toml::Value,
+    included: toml::Value,
+    include: &Path,
+) -> Result<(), Error> {
+    let included = match included {
+        toml::Value::Table(included) => included,
+        _ => return Ok(()),
+    };
+
+    for (key, value) in included {
+        let pools = match (key.as_str(), value) {
+            ("pools", toml::Value::Table(pools)) => pools,
+            _ => {
+                error!("{} can only define pools, found {}", include.display(), key);
+                return Err(Error::BadConfig);
+            }
+        };
+
+        let config_pools = config
+            .as_table_mut()
+            .unwrap()
+            .entry("pools")
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+
+        let config_pools = match config_pools.as_table_mut() {
+            Some(config_pools) => config_pools,
+            None => {
+                error!("pools must be a table");
+                return Err(Error::BadConfig);
+            }
+        };
+
+        for (pool_name, pool) in pools {
+            if config_pools.contains_key(&pool_name) {
+                error!(
+                    "Pool {} in {} is already defined",
+                    pool_name,
+                    include.display()
+                );
+                return Err(Error::BadConfig);
+            }
+
+            config_pools.insert(pool_name, pool);
+        }
+    }
 
     Ok(())
 }
 
 pub async fn reload_config(client_server_map: ClientServerMap) -> Result<bool, Error> {
+    let _lock = CONFIG_LOCK.lock().await;
     let old_config = get_config();
 
     match parse(&old_config.path).await {
@@ -1286,6 +2495,12 @@ pub async fn reload_config(client_server_map: ClientServerMap) -> Result<bool, E
         Err(err) => error!("DNS cache reinitialization error: {:?}", err),
     };
 
+    // The certificate files may have changed even if the config didn't.
+    let _ = reload_tls();
+
+    // The passwords may have changed, their SCRAM verifiers are rebuilt on the next login.
+    clear_verifiers();
+
     if old_config != new_config {
         info!("Config changed, reloading");
         ConnectionPool::from_config(client_server_map).await?;
@@ -1295,6 +2510,36 @@ pub async fn reload_config(client_server_map: ClientServerMap) -> Result<bool, E
     }
 }
 
+/// Change a setting at runtime. Like RELOAD, the new config is validated
+/// before it's used and the pools it changed are recreated. RELOAD reverts
+/// the setting to its value in the config file.
+pub async fn set_config(
+    key: &str,
+    value: &str,
+    client_server_map: ClientServerMap,
+) -> Result<(), Error> {
+    let _lock = CONFIG_LOCK.lock().await;
+    let mut config = get_config();
+
+    config.set(key, value)?;
+    config.validate()?;
+
+    info!("Setting {} to {}", key, value);
+
+    CONFIG.store(Arc::new(config));
+    ConnectionPool::from_config(client_server_map).await
+}
+
+fn parse_setting<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, Error> {
+    match value.parse() {
+        Ok(value) => Ok(value),
+        Err(_) => {
+            error!("Invalid value for {}: {}", key, value);
+            Err(Error::BadConfig)
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -1372,6 +2617,250 @@ mod test {
         assert_eq!(get_config().general.auth_query_password, None);
     }
 
+    #[tokio::test]
+    async fn test_set() {
+        parse("pgcat.toml").await.unwrap();
+
+        let mut config = get_config();
+
+        config.set("ban_time", "30").unwrap();
+        config
+            .set("pools.simple_db.users.simple_user.pool_size", "3")
+            .unwrap();
+        config.set("pools.simple_db.max_wait_queue", "7").unwrap();
+
+        assert_eq!(config.general.ban_time, 30);
+        assert_eq!(config.pools["simple_db"].users["0"].pool_size, 3);
+        assert_eq!(config.pools["simple_db"].max_wait_queue, 7);
+
+        assert!(config.set("ban_time", "abc").is_err());
+        assert!(config.set("port", "6432").is_err());
+        assert!(config.set("pools.missing_db.max_wait_queue", "7").is_err());
+        assert!(config
+            .set("pools.simple_db.users.missing_user.pool_size", "3")
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_auto_db() {
+        parse("pgcat.toml").await.unwrap();
+
+        let template = &get_config().pools["sharded_db"];
+
+        let pool = template.auto_db("tenant_db", "other_user");
+        assert!(pool
+            .shards
+            .values()
+            .all(|shard| shard.database == "tenant_db"));
+        assert_eq!(pool.users.len(), 1);
+        assert_eq!(pool.users["0"].pool_size, 21);
+        assert!(pool.users["0"].password.is_some());
+
+        let pool = template.auto_db("tenant_db", "tenant_user");
+        assert_eq!(pool.users["0"].username, "tenant_user");
+        assert_eq!(pool.users["0"].password, None);
+        assert_eq!(pool.users["0"].pool_size, template.users["0"].pool_size);
+    }
+
+    #[tokio::test]
+    async fn test_shard_keys() {
+        parse("pgcat.toml").await.unwrap();
+
+        let mut pool = get_config().pools["sharded_db"].clone();
+        pool.sharding_function = ShardingFunction::Range;
+        assert!(pool.validate().is_err());
+
+        for (shard_idx, shard) in pool.shards.iter_mut() {
+            let start = shard_idx.parse::<i64>().unwrap() * 1000;
+            shard.key_range = Some([start, start + 1000]);
+        }
+        assert!(pool.validate().is_ok());
+
+        pool.shards.get_mut("1").unwrap().key_range = Some([500, 1500]);
+        assert!(pool.validate().is_err());
+
+        for shard in pool.shards.values_mut() {
+            shard.key_range = None;
+        }
+        pool.sharding_function = ShardingFunction::List;
+        pool.shards.get_mut("0").unwrap().keys = Some(vec![1, 2]);
+        pool.shards.get_mut("1").unwrap().keys = Some(vec![3]);
+        pool.shards.get_mut("2").unwrap().keys = Some(vec![]);
+        assert!(pool.validate().is_ok());
+
+        pool.shards.get_mut("2").unwrap().keys = Some(vec![2]);
+        assert!(pool.validate().is_err());
+    }
+
+    #[test]
+    fn test_migrate_server_tls() {
+        let migrate = |general: &str| {
+            let mut value: toml::Value =
+                toml::from_str(&format!("[general]\n{}", general)).unwrap();
+            migrate_server_tls(&mut value).map(|_| value["general"].clone())
+        };
+
+        let general = migrate("server_tls = true").unwrap();
+        assert_eq!(general["server_tls_mode"].as_str(), Some("require"));
+        assert!(general.get("server_tls").is_none());
+
+        let general = migrate("server_tls = true\nverify_server_certificate = true").unwrap();
+        assert_eq!(general["server_tls_mode"].as_str(), Some("verify-full"));
+        assert!(general.get("verify_server_certificate").is_none());
+
+        let general = migrate("server_tls = false\nverify_server_certificate = true").unwrap();
+        assert_eq!(general["server_tls_mode"].as_str(), Some("disable"));
+
+        let general = migrate("server_tls_mode = \"prefer\"").unwrap();
+        assert_eq!(general["server_tls_mode"].as_str(), Some("prefer"));
+
+        // Both the old and the new settings.
+        assert!(migrate("server_tls = true\nserver_tls_mode = \"prefer\"").is_err());
+        assert!(migrate("server_tls = \"yes\"").is_err());
+
+        // The migrated setting parses.
+        let general: General = migrate("server_tls = true\nverify_server_certificate = true\nadmin_username = \"admin\"\nadmin_password = \"admin\"")
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert_eq!(general.server_tls_mode, ServerTlsMode::VerifyFull);
+    }
+
+    #[test]
+    fn test_server_tls_mode() {
+        #[derive(Deserialize)]
+        struct Mode {
+            mode: ServerTlsMode,
+        }
+
+        for (name, mode) in [
+            ("disable", ServerTlsMode::Disable),
+            ("prefer", ServerTlsMode::Prefer),
+            ("require", ServerTlsMode::Require),
+            ("verify-ca", ServerTlsMode::VerifyCa),
+            ("verify_ca", ServerTlsMode::VerifyCa),
+            ("verify-full", ServerTlsMode::VerifyFull),
+            ("VerifyFull", ServerTlsMode::VerifyFull),
+        ] {
+            let parsed: Mode = toml::from_str(&format!("mode = \"{}\"", name)).unwrap();
+            assert_eq!(parsed.mode, mode);
+        }
+
+        assert!(toml::from_str::<Mode>("mode = \"allow\"").is_err());
+        assert_eq!(ServerTlsMode::VerifyCa.to_string(), "verify-ca");
+    }
+
+    #[test]
+    fn test_server_tls() {
+        let mut config = Config::default();
+        config.general.server_tls_mode = ServerTlsMode::Require;
+        config.general.server_tls_ca_certificate = Some("ca.crt".into());
+        config.general.server_tls_certificate = Some("general.crt".into());
+        config.pools.insert(
+            "verified".into(),
+            Pool {
+                server_tls_mode: Some(ServerTlsMode::VerifyFull),
+                server_tls_certificate: Some("pool.crt".into()),
+                ..Default::default()
+            },
+        );
+
+        // Pools inherit the general settings they don't override.
+        assert_eq!(
+            config.server_tls("verified"),
+            ServerTls {
+                mode: ServerTlsMode::VerifyFull,
+                ca_certificate: Some("ca.crt".into()),
+                certificate: Some("pool.crt".into()),
+                private_key: None,
+            }
+        );
+        assert_eq!(
+            config.server_tls("missing"),
+            ServerTls {
+                mode: ServerTlsMode::Require,
+                ca_certificate: Some("ca.crt".into()),
+                certificate: Some("general.crt".into()),
+                private_key: None,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_interpolate() {
+        let file = std::env::temp_dir().join("pgcat_test_secret");
+        std::fs::write(&file, "file_secret\n").unwrap();
+        std::env::set_var("PGCAT_TEST_SECRET", "env_secret");
+
+        let mut value: toml::Value = toml::from_str(&format!(
+            r#"
+            [general]
+            admin_password = "${{PGCAT_TEST_SECRET}}"
+
+            [pools.simple_db.users.0]
+            password = "pre_${{file:{}}}"
+
+            [plugins.intercept.queries.0]
+            result = [["${{USER}}"]]
+            "#,
+            file.display()
+        ))
+        .unwrap();
+
+        let mut secrets = HashSet::new();
+        interpolate(&mut value, "", &mut secrets).unwrap();
+
+        assert_eq!(
+            value["general"]["admin_password"].as_str(),
+            Some("env_secret")
+        );
+        assert_eq!(
+            value["pools"]["simple_db"]["users"]["0"]["password"].as_str(),
+            Some("pre_file_secret")
+        );
+        assert_eq!(
+            value["plugins"]["intercept"]["queries"]["0"]["result"][0][0].as_str(),
+            Some("${USER}")
+        );
+        assert!(secrets.contains("general.admin_password"));
+        assert!(secrets.contains("pools.simple_db.users.0.password"));
+        assert_eq!(secrets.len(), 2);
+
+        let mut value: toml::Value =
+            toml::from_str(r#"password = "${PGCAT_TEST_MISSING}""#).unwrap();
+        assert!(interpolate(&mut value, "", &mut secrets).is_err());
+
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_to_toml() {
+        parse("pgcat.toml").await.unwrap();
+
+        let mut config = get_config();
+        config.general.admin_api_token = Some("admin_api_token".to_string());
+        config.secrets.insert("general.host".to_string());
+
+        let toml = config.to_toml().unwrap();
+
+        // Passwords and tokens are redacted even if they weren't interpolated.
+        assert!(toml.contains(r#"admin_password = "<redacted>""#));
+        assert!(toml.contains(r#"admin_api_token = "<redacted>""#));
+        assert!(!toml.contains(&format!(r#""{}""#, config.general.admin_password)));
+        assert!(!toml.contains(r#""admin_api_token""#));
+
+        for pool in config.pools.values() {
+            for user in pool.users.values() {
+                if let Some(ref password) = user.password {
+                    assert!(!toml.contains(&format!(r#"password = "{}""#, password)));
+                }
+            }
+        }
+
+        // So are the other interpolated settings.
+        assert!(toml.contains(r#"host = "<redacted>""#));
+    }
+
     #[tokio::test]
     async fn test_serialize_configs() {
         parse("pgcat.toml").await.unwrap();
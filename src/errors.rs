@@ -26,6 +26,9 @@ pub enum Error {
     AuthPassthroughError(String),
     UnsupportedStatement,
     QueryRouterParserError(String),
+    MaxWaitQueueReached,
+    MaxServerConnectionsReached,
+    ShardingKeyNotInShard(i64),
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -115,6 +118,9 @@ impl std::fmt::Display for Error {
             &Error::ServerAuthError(error, server_identifier) => {
                 write!(f, "{} for {}", error, server_identifier,)
             }
+            &Error::ShardingKeyNotInShard(sharding_key) => {
+                write!(f, "sharding key {} is not in any shard", sharding_key)
+            }
 
             // The rest can use Debug.
             err => write!(f, "{:?}", err),
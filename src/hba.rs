@@ -0,0 +1,313 @@
+/// Host-based client access rules, like Postgres' pg_hba.conf.
+/// The first rule matching the client decides how it authenticates.
+use log::error;
+use serde_derive::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+use crate::client::ClientAddr;
+use crate::config::AuthType;
+use crate::errors::Error;
+
+/// How the client connected.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionType {
+    /// TCP, with or without TLS.
+    Host,
+
+    /// TCP with TLS.
+    HostSsl,
+
+    /// TCP without TLS.
+    HostNoSsl,
+
+    /// Unix socket.
+    Local,
+}
+
+/// What to do with a client matching a rule.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AuthMethod {
+    #[serde(alias = "reject")]
+    Reject,
+
+    #[serde(alias = "trust")]
+    Trust,
+
+    /// TLS client certificate mapped to the user.
+    #[serde(alias = "cert")]
+    Cert,
+
+    #[serde(alias = "md5", alias = "MD5")]
+    Md5,
+
+    #[serde(
+        alias = "scram-sha-256",
+        alias = "scram_sha_256",
+        alias = "SCRAM-SHA-256"
+    )]
+    ScramSha256,
+}
+
+impl From<AuthType> for AuthMethod {
+    fn from(auth_type: AuthType) -> AuthMethod {
+        match auth_type {
+            AuthType::Md5 => AuthMethod::Md5,
+            AuthType::ScramSha256 => AuthMethod::ScramSha256,
+        }
+    }
+}
+
+/// A client access rule.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct HbaRule {
+    #[serde(rename = "type", default = "HbaRule::default_connection_type")]
+    pub connection_type: ConnectionType,
+
+    /// Pools the rule applies to, or "all".
+    #[serde(default = "HbaRule::default_all")]
+    pub database: Vec<String>,
+
+    /// Users the rule applies to, or "all".
+    #[serde(default = "HbaRule::default_all")]
+    pub user: Vec<String>,
+
+    /// Client network in CIDR notation, e.g. "10.0.0.0/8". Any address if not set.
+    pub address: Option<String>,
+
+    pub method: AuthMethod,
+}
+
+impl HbaRule {
+    pub fn default_connection_type() -> ConnectionType {
+        ConnectionType::Host
+    }
+
+    pub fn default_all() -> Vec<String> {
+        vec!["all".into()]
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.method == AuthMethod::Cert && self.connection_type != ConnectionType::HostSsl {
+            error!("hba rules with the cert method must be of type hostssl");
+            return Err(Error::BadConfig);
+        }
+
+        match &self.address {
+            Some(_) if self.connection_type == ConnectionType::Local => {
+                error!("hba rules of type local can't have an address");
+                Err(Error::BadConfig)
+            }
+
+            Some(address) if parse_cidr(address).is_none() => {
+                error!("hba rule address {} is not a valid CIDR", address);
+                Err(Error::BadConfig)
+            }
+
+            _ => Ok(()),
+        }
+    }
+
+    /// Does the rule apply to this client.
+    pub fn matches(&self, addr: &ClientAddr, tls: bool, database: &str, user: &str) -> bool {
+        let connection_type = match (addr, self.connection_type) {
+            (ClientAddr::Unix { .. }, ConnectionType::Local) => true,
+            (ClientAddr::Tcp(_), ConnectionType::Host) => true,
+            (ClientAddr::Tcp(_), ConnectionType::HostSsl) => tls,
+            (ClientAddr::Tcp(_), ConnectionType::HostNoSsl) => !tls,
+            _ => false,
+        };
+
+        let address = match (&self.address, addr) {
+            (Some(cidr), ClientAddr::Tcp(addr)) => match parse_cidr(cidr) {
+                Some((network, prefix)) => in_network(addr.ip(), network, prefix),
+                None => false,
+            },
+            _ => true,
+        };
+
+        connection_type
+            && address
+            && matches_name(&self.database, database)
+            && matches_name(&self.user, user)
+    }
+}
+
+/// Find how the client should authenticate. Without rules, every client
+/// uses `auth_type`; with rules, clients no rule matches are rejected.
+pub fn auth_method(
+    rules: &[HbaRule],
+    auth_type: AuthType,
+    addr: &ClientAddr,
+    tls: bool,
+    database: &str,
+    user: &str,
+) -> Option<AuthMethod> {
+    if rules.is_empty() {
+        return Some(auth_type.into());
+    }
+
+    rules
+        .iter()
+        .find(|rule| rule.matches(addr, tls, database, user))
+        .map(|rule| rule.method)
+}
+
+fn matches_name(names: &[String], name: &str) -> bool {
+    names.iter().any(|n| n == "all" || n == name)
+}
+
+/// Parse "10.0.0.0/8" or a single address into the network and prefix length.
+fn parse_cidr(cidr: &str) -> Option<(IpAddr, u8)> {
+    let (address, prefix) = match cidr.split_once('/') {
+        Some((address, prefix)) => (address, Some(prefix)),
+        None => (cidr, None),
+    };
+
+    let address: IpAddr = address.trim().parse().ok()?;
+    let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+
+    let prefix = match prefix {
+        Some(prefix) => prefix.trim().parse().ok()?,
+        None => max_prefix,
+    };
+
+    if prefix > max_prefix {
+        return None;
+    }
+
+    Some((address, prefix))
+}
+
+fn in_network(ip: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    // IPv4 clients of a listener on :: show up as IPv4-mapped IPv6 addresses.
+    let ip = match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        ip => ip,
+    };
+
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rule(connection_type: ConnectionType, address: Option<&str>, method: AuthMethod) -> HbaRule {
+        HbaRule {
+            connection_type,
+            database: HbaRule::default_all(),
+            user: HbaRule::default_all(),
+            address: address.map(|address| address.into()),
+            method,
+        }
+    }
+
+    fn tcp(addr: &str) -> ClientAddr {
+        ClientAddr::Tcp(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn test_cidr() {
+        assert!(in_network(
+            "10.1.2.3".parse().unwrap(),
+            "10.0.0.0".parse().unwrap(),
+            8
+        ));
+        assert!(!in_network(
+            "11.1.2.3".parse().unwrap(),
+            "10.0.0.0".parse().unwrap(),
+            8
+        ));
+        assert!(in_network(
+            "::ffff:10.1.2.3".parse().unwrap(),
+            "10.0.0.0".parse().unwrap(),
+            8
+        ));
+        assert!(in_network(
+            "1.2.3.4".parse().unwrap(),
+            "0.0.0.0".parse().unwrap(),
+            0
+        ));
+        assert!(in_network(
+            "fd00::1".parse().unwrap(),
+            "fd00::".parse().unwrap(),
+            8
+        ));
+        assert_eq!(
+            parse_cidr("127.0.0.1"),
+            Some(("127.0.0.1".parse().unwrap(), 32))
+        );
+        assert_eq!(parse_cidr("127.0.0.1/33"), None);
+        assert_eq!(parse_cidr("localhost"), None);
+    }
+
+    #[test]
+    fn test_auth_method() {
+        let unix = ClientAddr::Unix {
+            uid: 0,
+            gid: 0,
+            pid: None,
+        };
+
+        assert_eq!(
+            auth_method(&[], AuthType::Md5, &unix, false, "db", "user"),
+            Some(AuthMethod::Md5)
+        );
+
+        let mut rules = vec![
+            rule(ConnectionType::Local, None, AuthMethod::Trust),
+            rule(
+                ConnectionType::HostNoSsl,
+                Some("10.0.0.0/8"),
+                AuthMethod::Reject,
+            ),
+            rule(
+                ConnectionType::Host,
+                Some("10.0.0.0/8"),
+                AuthMethod::ScramSha256,
+            ),
+        ];
+        rules[2].user = vec!["app".into()];
+
+        let private = tcp("10.0.0.1:5432");
+
+        assert_eq!(
+            auth_method(&rules, AuthType::Md5, &unix, false, "db", "user"),
+            Some(AuthMethod::Trust)
+        );
+        assert_eq!(
+            auth_method(&rules, AuthType::Md5, &private, false, "db", "app"),
+            Some(AuthMethod::Reject)
+        );
+        assert_eq!(
+            auth_method(&rules, AuthType::Md5, &private, true, "db", "app"),
+            Some(AuthMethod::ScramSha256)
+        );
+        assert_eq!(
+            auth_method(&rules, AuthType::Md5, &private, true, "db", "other"),
+            None
+        );
+        assert_eq!(
+            auth_method(
+                &rules,
+                AuthType::Md5,
+                &tcp("1.2.3.4:5432"),
+                true,
+                "db",
+                "app"
+            ),
+            None
+        );
+    }
+}
@@ -1,10 +1,12 @@
 pub mod admin;
+pub mod admin_api;
 pub mod auth_passthrough;
 pub mod client;
 pub mod config;
 pub mod constants;
 pub mod dns_cache;
 pub mod errors;
+pub mod hba;
 pub mod messages;
 pub mod mirrors;
 pub mod multi_logger;
@@ -12,11 +14,15 @@ pub mod plugins;
 pub mod pool;
 pub mod prometheus;
 pub mod query_router;
+pub mod scatter_gather;
 pub mod scram;
 pub mod server;
+pub mod session_parameters;
 pub mod sharding;
 pub mod stats;
 pub mod tls;
+#[cfg(not(windows))]
+pub mod upgrade;
 
 /// Format chrono::Duration to be more human-friendly.
 ///
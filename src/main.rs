@@ -50,23 +50,32 @@ use parking_lot::Mutex;
 use pgcat::format_duration;
 use tokio::net::TcpListener;
 #[cfg(not(windows))]
+use tokio::net::UnixListener;
+#[cfg(not(windows))]
 use tokio::signal::unix::{signal as unix_signal, SignalKind};
 #[cfg(windows)]
 use tokio::signal::windows as win_signal;
-use tokio::{runtime::Builder, sync::mpsc};
+use tokio::{
+    runtime::Builder,
+    sync::{mpsc, watch},
+};
 
 use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::str::FromStr;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::sync::Arc;
 use tokio::sync::broadcast;
 
 use pgcat::config::{get_config, reload_config, VERSION};
 use pgcat::dns_cache;
 use pgcat::messages::configure_socket;
-use pgcat::pool::{ClientServerMap, ConnectionPool};
+use pgcat::pool::{self, ClientServerMap, ConnectionPool};
 use pgcat::prometheus::start_metric_server;
 use pgcat::stats::{Collector, Reporter, REPORTER};
+use pgcat::tls;
+#[cfg(not(windows))]
+use pgcat::upgrade::{self, Takeover};
+#[cfg(not(windows))]
+use std::os::unix::io::AsRawFd;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     pgcat::multi_logger::MultiLogger::init().unwrap();
@@ -78,14 +87,31 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         std::process::exit(exitcode::CONFIG);
     }
 
-    let args = std::env::args().collect::<Vec<String>>();
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    let (flags, files): (Vec<&String>, Vec<&String>) =
+        args.iter().partition(|arg| arg.starts_with("--"));
 
-    let config_file = if args.len() == 2 {
-        args[1].to_string()
-    } else {
-        String::from("pgcat.toml")
+    let config_file = match files.as_slice() {
+        [] => String::from("pgcat.toml"),
+        [config_file] => config_file.to_string(),
+        _ => {
+            error!("Usage: pgcat [--check [--connect]] [config file]");
+            std::process::exit(exitcode::USAGE);
+        }
     };
 
+    match flags.as_slice() {
+        [] => (),
+        [check] if *check == "--check" => std::process::exit(check_config(&config_file, false)),
+        [check, connect] if *check == "--check" && *connect == "--connect" => {
+            std::process::exit(check_config(&config_file, true))
+        }
+        _ => {
+            error!("Usage: pgcat [--check [--connect]] [config file]");
+            std::process::exit(exitcode::USAGE);
+        }
+    }
+
     // Create a transient runtime for loading the config for the first time.
     {
         let runtime = Builder::new_multi_thread().worker_threads(1).build()?;
@@ -111,42 +137,160 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     runtime.block_on(async move {
 
-        if let Some(true) = config.general.enable_prometheus_exporter {
+        let addr = format!("{}:{}", config.general.host, config.general.port);
+
+        // Take over the listening sockets of the running pgcat, if there is one.
+        #[cfg(not(windows))]
+        let mut takeover = match config.general.upgrade_socket {
+            Some(ref path) => match Takeover::connect(path).await {
+                Ok(takeover) => takeover,
+                Err(err) => {
+                    error!("Could not take over from the running pgcat: {:?}", err);
+                    std::process::exit(exitcode::UNAVAILABLE);
+                }
+            },
+            None => None,
+        };
+
+        #[cfg(not(windows))]
+        let listener = match takeover.as_mut() {
+            Some(takeover) => {
+                let listener = takeover
+                    .tcp_listener
+                    .try_clone()
+                    .and_then(TcpListener::from_std);
+
+                match listener {
+                    Ok(listener) if listens_on(&listener.local_addr(), &addr) => {
+                        info!("Took over {} from the running pgcat", addr);
+                        listener
+                    }
+                    Ok(listener) => {
+                        error!(
+                            "The running pgcat listens on {:?}, not on {}",
+                            listener.local_addr(),
+                            addr
+                        );
+                        std::process::exit(exitcode::CONFIG);
+                    }
+                    Err(err) => {
+                        error!("Listener socket error: {:?}", err);
+                        std::process::exit(exitcode::CONFIG);
+                    }
+                }
+            }
+            None => bind_listener(&addr).await,
+        };
+
+        #[cfg(windows)]
+        let listener = bind_listener(&addr).await;
+
+        info!("Running on {}", addr);
+
+        #[cfg(not(windows))]
+        let inherited_unix_listener = takeover
+            .as_mut()
+            .and_then(|takeover| takeover.unix_listener.take());
+
+        #[cfg(not(windows))]
+        let unix_listener = match config.general.unix_socket_path() {
+            Some(path) => {
+                let listener = match inherited_unix_listener {
+                    Some(listener) => UnixListener::from_std(listener),
+                    None => bind_unix_socket(&path),
+                };
+
+                match listener {
+                    Ok(sock) => {
+                        info!("Running on {}", path);
+                        Some(sock)
+                    }
+                    Err(err) => {
+                        error!("Unix socket {} error: {:?}", path, err);
+                        std::process::exit(exitcode::CONFIG);
+                    }
+                }
+            }
+            None => None,
+        };
+
+        config.show();
+
+        // Load the TLS certificates clients negotiate with.
+        if let Err(err) = tls::reload_tls() {
+            error!("TLS error: {:?}", err);
+            std::process::exit(exitcode::CONFIG);
+        }
+
+        tokio::task::spawn(tls::watch_tls_files());
+
+        // Tracks which client is connected to which server for query cancellation.
+        let client_server_map: ClientServerMap = Arc::new(Mutex::new(HashMap::new()));
+
+        // Tells the listeners to stop once a new pgcat took them over.
+        let (handed_off_tx, handed_off_rx) = watch::channel(false);
+
+        // Prometheus metrics and the admin API.
+        #[cfg(not(windows))]
+        let mut http_listener_fd = None;
+
+        if config.general.enable_prometheus_exporter == Some(true) || config.general.enable_admin_api {
             let http_addr_str = format!(
                 "{}:{}",
                 config.general.host, config.general.prometheus_exporter_port
             );
 
-            let http_addr = match SocketAddr::from_str(&http_addr_str) {
-                Ok(addr) => addr,
-                Err(err) => {
-                    error!("Invalid http address: {}", err);
+            #[cfg(not(windows))]
+            let inherited_http_listener = takeover
+                .as_mut()
+                .and_then(|takeover| takeover.http_listener.take());
+
+            #[cfg(windows)]
+            let inherited_http_listener: Option<std::net::TcpListener> = None;
+
+            let http_listener = match inherited_http_listener {
+                Some(listener) if listens_on(&listener.local_addr(), &http_addr_str) => {
+                    info!("Took over {} from the running pgcat", http_addr_str);
+                    listener
+                }
+                Some(listener) => {
+                    error!(
+                        "The running pgcat serves http on {:?}, not on {}",
+                        listener.local_addr(),
+                        http_addr_str
+                    );
                     std::process::exit(exitcode::CONFIG);
                 }
+                None => match bind_http_listener(&http_addr_str) {
+                    Ok(listener) => listener,
+                    Err(err) => {
+                        error!("Http listener socket error: {:?}", err);
+                        std::process::exit(exitcode::CONFIG);
+                    }
+                },
             };
 
+            #[cfg(not(windows))]
+            {
+                http_listener_fd = Some(http_listener.as_raw_fd());
+            }
+
+            let client_server_map = client_server_map.clone();
+            let handed_off_rx = handed_off_rx.clone();
+
             tokio::task::spawn(async move {
-                start_metric_server(http_addr).await;
+                start_metric_server(http_listener, client_server_map, handed_off_rx).await;
             });
         }
 
-        let addr = format!("{}:{}", config.general.host, config.general.port);
-
-        let listener = match TcpListener::bind(&addr).await {
-            Ok(sock) => sock,
-            Err(err) => {
-                error!("Listener socket error: {:?}", err);
-                std::process::exit(exitcode::CONFIG);
-            }
+        // The listening sockets handed over to the next pgcat on upgrade.
+        #[cfg(not(windows))]
+        let upgrade_listeners = upgrade::Listeners {
+            tcp: listener.as_raw_fd(),
+            unix: unix_listener.as_ref().map(|listener| listener.as_raw_fd()),
+            http: http_listener_fd,
         };
 
-        info!("Running on {}", addr);
-
-        config.show();
-
-        // Tracks which client is connected to which server for query cancellation.
-        let client_server_map: ClientServerMap = Arc::new(Mutex::new(HashMap::new()));
-
         // Statistics reporting.
         REPORTER.store(Arc::new(Reporter::default()));
 
@@ -165,6 +309,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
         };
 
+        // Pools created for auto_db are removed when they're idle.
+        tokio::task::spawn(pool::reap_auto_db_pools());
+
         tokio::task::spawn(async move {
             let mut stats_collector = Collector::default();
             stats_collector.collect().await;
@@ -212,8 +359,57 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         let (drain_tx, mut drain_rx) = mpsc::channel::<i32>(2048);
         let (exit_tx, mut exit_rx) = mpsc::channel::<()>(1);
         let mut admin_only = false;
+        let mut handed_off = false;
         let mut total_clients = 0;
 
+        #[cfg(not(windows))]
+        if let Some(listener) = unix_listener {
+            accept_unix_clients(
+                listener,
+                client_server_map.clone(),
+                shutdown_tx.clone(),
+                drain_tx.clone(),
+                handed_off_rx,
+                config.general.log_client_connections,
+            );
+        }
+
+        // The pgcat we took over from can stop accepting clients now.
+        #[cfg(not(windows))]
+        let took_over = match takeover {
+            Some(takeover) => match takeover.complete().await {
+                Ok(()) => true,
+                Err(err) => {
+                    warn!("Could not tell the previous pgcat to drain its clients: {:?}", err);
+                    false
+                }
+            },
+            None => true,
+        };
+
+        // Hand the listening sockets over to the next pgcat on upgrade. If the previous
+        // pgcat is still accepting clients, its upgrade socket is left alone.
+        let (handoff_tx, mut handoff_rx) = mpsc::channel::<()>(1);
+
+        #[cfg(not(windows))]
+        let upgrade_socket = match config.general.upgrade_socket {
+            Some(ref path) if took_over => {
+                if upgrade::serve(path, upgrade_listeners, handoff_tx).is_err() {
+                    std::process::exit(exitcode::CONFIG);
+                }
+
+                Some(path)
+            }
+            Some(_) => {
+                warn!("Not listening on the upgrade socket, the previous pgcat still is");
+                None
+            }
+            None => None,
+        };
+
+        #[cfg(windows)]
+        drop(handoff_tx);
+
         info!("Waiting for clients");
 
         loop {
@@ -239,25 +435,24 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
                     admin_only = true;
 
-                    // Broadcast that client tasks need to finish
-                    let _ = shutdown_tx.send(());
-                    let exit_tx = exit_tx.clone();
-                    let _ = drain_tx.send(0).await;
+                    graceful_shutdown(&shutdown_tx, &drain_tx, exit_tx.clone(), config.general.shutdown_timeout, total_clients).await;
+                },
 
-                    tokio::task::spawn(async move {
-                        let mut interval = tokio::time::interval(tokio::time::Duration::from_millis(config.general.shutdown_timeout));
+                // A new pgcat took over the listening sockets, stop accepting clients
+                // and drain the connected ones.
+                Some(()) = handoff_rx.recv() => {
+                    info!("A new pgcat took over, draining {} clients", total_clients);
 
-                        // First tick fires immediately.
-                        interval.tick().await;
+                    handed_off = true;
+                    let _ = handed_off_tx.send(true);
 
-                        // Second one in the interval time.
-                        interval.tick().await;
+                    if admin_only {
+                        continue;
+                    }
 
-                        // We're done waiting.
-                        error!("Graceful shutdown timed out. {} active clients being closed", total_clients);
+                    admin_only = true;
 
-                        let _ = exit_tx.send(()).await;
-                    });
+                    graceful_shutdown(&shutdown_tx, &drain_tx, exit_tx.clone(), config.general.shutdown_timeout, total_clients).await;
                 },
 
                 _ = term_signal.recv() => {
@@ -265,7 +460,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     break;
                 },
 
-                new_client = listener.accept() => {
+                new_client = listener.accept(), if !handed_off => {
                     let (socket, addr) = match new_client {
                         Ok((socket, addr)) => (socket, addr),
                         Err(err) => {
@@ -341,6 +536,212 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
 
     info!("Shutting down...");
+
+    // The sockets belong to the new pgcat after a takeover.
+    #[cfg(not(windows))]
+    if !handed_off {
+        if let Some(path) = config.general.unix_socket_path() {
+            let _ = std::fs::remove_file(path);
+        }
+
+        if let Some(path) = upgrade_socket {
+            let _ = std::fs::remove_file(path);
+        }
+    }
     });
     Ok(())
 }
+
+/// Is the listener bound to one of the addresses host:port resolves to.
+fn listens_on(local_addr: &std::io::Result<SocketAddr>, addr: &str) -> bool {
+    match (local_addr, addr.to_socket_addrs()) {
+        (Ok(local_addr), Ok(mut addrs)) => addrs.any(|addr| addr == *local_addr),
+        _ => false,
+    }
+}
+
+/// Bind the TCP socket of the Prometheus exporter and the admin API.
+fn bind_http_listener(addr: &str) -> std::io::Result<std::net::TcpListener> {
+    let listener = std::net::TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Bind the TCP socket clients connect to.
+async fn bind_listener(addr: &str) -> TcpListener {
+    match TcpListener::bind(addr).await {
+        Ok(sock) => sock,
+        Err(err) => {
+            error!("Listener socket error: {:?}", err);
+            std::process::exit(exitcode::CONFIG);
+        }
+    }
+}
+
+/// Broadcast to the clients that they need to finish, and exit once they're done
+/// or after the shutdown timeout.
+async fn graceful_shutdown(
+    shutdown_tx: &broadcast::Sender<()>,
+    drain_tx: &mpsc::Sender<i32>,
+    exit_tx: mpsc::Sender<()>,
+    shutdown_timeout: u64,
+    total_clients: i32,
+) {
+    let _ = shutdown_tx.send(());
+    let _ = drain_tx.send(0).await;
+
+    tokio::task::spawn(async move {
+        let mut interval =
+            tokio::time::interval(tokio::time::Duration::from_millis(shutdown_timeout));
+
+        // First tick fires immediately.
+        interval.tick().await;
+
+        // Second one in the interval time.
+        interval.tick().await;
+
+        // We're done waiting.
+        error!(
+            "Graceful shutdown timed out. {} active clients being closed",
+            total_clients
+        );
+
+        let _ = exit_tx.send(()).await;
+    });
+}
+
+/// Parse and validate the config, and print it normalized, without starting the pooler.
+/// With `connect`, also connect to every server of every pool. Returns the exit code.
+fn check_config(config_file: &str, connect: bool) -> exitcode::ExitCode {
+    let runtime = match Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(err) => {
+            error!("Could not start the runtime: {:?}", err);
+            return exitcode::OSERR;
+        }
+    };
+
+    runtime.block_on(async {
+        if let Err(err) = pgcat::config::parse(config_file).await {
+            error!("Config check failed: {:?}", err);
+            return exitcode::CONFIG;
+        }
+
+        match get_config().to_toml() {
+            Ok(config) => print!("{}", config),
+            Err(_) => return exitcode::CONFIG,
+        };
+
+        if !connect {
+            return exitcode::OK;
+        }
+
+        let ok = pool::check_servers().await;
+
+        if ok {
+            exitcode::OK
+        } else {
+            exitcode::UNAVAILABLE
+        }
+    })
+}
+
+/// Bind the Unix socket, replacing the one left over by a previous run.
+#[cfg(not(windows))]
+fn bind_unix_socket(path: &str) -> std::io::Result<UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if let Ok(metadata) = std::fs::metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+
+    UnixListener::bind(path)
+}
+
+/// Accept clients on the Unix socket until pgcat exits.
+#[cfg(not(windows))]
+fn accept_unix_clients(
+    listener: UnixListener,
+    client_server_map: ClientServerMap,
+    shutdown_tx: broadcast::Sender<()>,
+    drain_tx: mpsc::Sender<i32>,
+    mut handed_off: watch::Receiver<bool>,
+    log_client_connections: bool,
+) {
+    tokio::task::spawn(async move {
+        let mut shutdown = shutdown_tx.subscribe();
+        let mut admin_only = false;
+
+        loop {
+            tokio::select! {
+                // A new pgcat took over the socket.
+                _ = handed_off.changed() => {
+                    break;
+                }
+
+                // Graceful shutdown started, only admin clients are allowed in.
+                _ = shutdown.recv(), if !admin_only => {
+                    admin_only = true;
+                }
+
+                new_client = listener.accept() => {
+                    let socket = match new_client {
+                        Ok((socket, _)) => socket,
+                        Err(err) => {
+                            error!("{:?}", err);
+                            continue;
+                        }
+                    };
+
+                    let shutdown_rx = shutdown_tx.subscribe();
+                    let drain_tx = drain_tx.clone();
+                    let client_server_map = client_server_map.clone();
+
+                    tokio::task::spawn(async move {
+                        let start = chrono::offset::Utc::now().naive_utc();
+
+                        match pgcat::client::unix_client_entrypoint(
+                            socket,
+                            client_server_map,
+                            shutdown_rx,
+                            drain_tx,
+                            admin_only,
+                            log_client_connections,
+                        )
+                        .await
+                        {
+                            Ok(()) => {
+                                let duration = chrono::offset::Utc::now().naive_utc() - start;
+
+                                if get_config().general.log_client_disconnections {
+                                    info!(
+                                        "Unix socket client disconnected, session duration: {}",
+                                        format_duration(&duration)
+                                    );
+                                } else {
+                                    debug!(
+                                        "Unix socket client disconnected, session duration: {}",
+                                        format_duration(&duration)
+                                    );
+                                }
+                            }
+
+                            Err(err) => match err {
+                                pgcat::errors::Error::ClientBadStartup => {
+                                    debug!("Client disconnected with error {:?}", err)
+                                }
+                                _ => warn!("Client disconnected with error {:?}", err),
+                            },
+                        };
+                    });
+                }
+            }
+        }
+    });
+}
@@ -8,8 +8,11 @@ use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 
 use crate::config::get_config;
+use crate::constants::{SASL, SCRAM_SHA_256};
 use crate::errors::Error;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
 use std::io::{BufRead, Cursor};
 use std::mem;
 use std::time::Duration;
@@ -77,6 +80,37 @@ where
     Ok(salt)
 }
 
+/// Ask the client to authenticate using SCRAM-SHA-256 (AuthenticationSASL).
+pub async fn sasl_challenge<S>(stream: &mut S) -> Result<(), Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let mut res = BytesMut::new();
+    res.put_u8(b'R');
+    res.put_i32(4 + 4 + SCRAM_SHA_256.len() as i32 + 1 + 1);
+    res.put_i32(SASL);
+    res.put_slice(SCRAM_SHA_256.as_bytes());
+    res.put_u8(0); // Mechanism name terminator
+    res.put_u8(0); // End of the mechanism list
+
+    write_all(stream, res).await
+}
+
+/// Send the SASL exchange data to the client,
+/// either AuthenticationSASLContinue or AuthenticationSASLFinal.
+pub async fn sasl_response<S>(stream: &mut S, code: i32, data: &[u8]) -> Result<(), Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let mut res = BytesMut::new();
+    res.put_u8(b'R');
+    res.put_i32(4 + 4 + data.len() as i32);
+    res.put_i32(code);
+    res.put_slice(data);
+
+    write_all(stream, res).await
+}
+
 /// Give the client the process_id and secret we generated
 /// used in query cancellation.
 pub async fn backend_key_data<S>(
@@ -689,3 +723,331 @@ impl BytesMutReader for Cursor<&BytesMut> {
         }
     }
 }
+
+/// Parse (F) message, prepares a statement.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Parse {
+    pub name: String,
+    query: String,
+    param_types: Vec<i32>,
+}
+
+impl TryFrom<&BytesMut> for Parse {
+    type Error = Error;
+
+    fn try_from(bytes: &BytesMut) -> Result<Parse, Error> {
+        let mut cursor = Cursor::new(bytes);
+        let _code = cursor.get_u8();
+        let _len = cursor.get_i32();
+        let name = cursor.read_string()?;
+        let query = cursor.read_string()?;
+
+        if cursor.remaining() < 2 {
+            return Err(Error::ParseBytesError("Parse".into()));
+        }
+
+        let num_params = cursor.get_i16();
+
+        if num_params < 0 || cursor.remaining() < num_params as usize * 4 {
+            return Err(Error::ParseBytesError("Parse".into()));
+        }
+
+        let param_types = (0..num_params).map(|_| cursor.get_i32()).collect();
+
+        Ok(Parse {
+            name,
+            query,
+            param_types,
+        })
+    }
+}
+
+impl From<&Parse> for BytesMut {
+    fn from(parse: &Parse) -> BytesMut {
+        let mut body = BytesMut::new();
+        body.put_slice(parse.name.as_bytes());
+        body.put_u8(0);
+        body.put_slice(parse.query.as_bytes());
+        body.put_u8(0);
+        body.put_i16(parse.param_types.len() as i16);
+
+        for param_type in parse.param_types.iter() {
+            body.put_i32(*param_type);
+        }
+
+        let mut res = BytesMut::with_capacity(body.len() + 5);
+        res.put_u8(b'P');
+        res.put_i32(body.len() as i32 + 4);
+        res.put(body);
+        res
+    }
+}
+
+impl Parse {
+    /// The unnamed statement only lives until the next Parse,
+    /// so it's never shared between clients.
+    pub fn anonymous(&self) -> bool {
+        self.name.is_empty()
+    }
+
+    /// The same statement under the name used on the servers. Identical statements
+    /// get the same name, so clients can share them.
+    pub fn rename(&self) -> Parse {
+        let mut hasher = DefaultHasher::new();
+        self.query.hash(&mut hasher);
+        self.param_types.hash(&mut hasher);
+
+        Parse {
+            name: format!("PGCAT_{:016x}", hasher.finish()),
+            query: self.query.clone(),
+            param_types: self.param_types.clone(),
+        }
+    }
+}
+
+/// Bind (F) message, creates a portal from a prepared statement.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bind {
+    portal: String,
+    pub statement: String,
+
+    /// Parameter formats, values and result formats, left as-is.
+    rest: BytesMut,
+}
+
+impl TryFrom<&BytesMut> for Bind {
+    type Error = Error;
+
+    fn try_from(bytes: &BytesMut) -> Result<Bind, Error> {
+        let mut cursor = Cursor::new(bytes);
+        let _code = cursor.get_u8();
+        let _len = cursor.get_i32();
+        let portal = cursor.read_string()?;
+        let statement = cursor.read_string()?;
+        let rest = BytesMut::from(&bytes[cursor.position() as usize..]);
+
+        Ok(Bind {
+            portal,
+            statement,
+            rest,
+        })
+    }
+}
+
+impl From<&Bind> for BytesMut {
+    fn from(bind: &Bind) -> BytesMut {
+        let mut body = BytesMut::new();
+        body.put_slice(bind.portal.as_bytes());
+        body.put_u8(0);
+        body.put_slice(bind.statement.as_bytes());
+        body.put_u8(0);
+        body.put_slice(&bind.rest);
+
+        let mut res = BytesMut::with_capacity(body.len() + 5);
+        res.put_u8(b'B');
+        res.put_i32(body.len() as i32 + 4);
+        res.put(body);
+        res
+    }
+}
+
+impl Bind {
+    /// The same message, binding another prepared statement.
+    pub fn rename(&self, statement: &str) -> Bind {
+        Bind {
+            portal: self.portal.clone(),
+            statement: statement.to_string(),
+            rest: self.rest.clone(),
+        }
+    }
+}
+
+/// Describe (F) or Close (F) message, naming a prepared statement ('S') or a portal ('P').
+#[derive(Clone, Debug, PartialEq)]
+pub struct Describe {
+    code: u8,
+    pub target: char,
+    pub name: String,
+}
+
+impl TryFrom<&BytesMut> for Describe {
+    type Error = Error;
+
+    fn try_from(bytes: &BytesMut) -> Result<Describe, Error> {
+        let mut cursor = Cursor::new(bytes);
+
+        if cursor.remaining() < 6 {
+            return Err(Error::ParseBytesError("Describe".into()));
+        }
+
+        let code = cursor.get_u8();
+        let _len = cursor.get_i32();
+        let target = cursor.get_u8() as char;
+        let name = cursor.read_string()?;
+
+        Ok(Describe { code, target, name })
+    }
+}
+
+impl From<&Describe> for BytesMut {
+    fn from(describe: &Describe) -> BytesMut {
+        let mut res = BytesMut::with_capacity(describe.name.len() + 7);
+        res.put_u8(describe.code);
+        res.put_i32(describe.name.len() as i32 + 6);
+        res.put_u8(describe.target as u8);
+        res.put_slice(describe.name.as_bytes());
+        res.put_u8(0);
+        res
+    }
+}
+
+impl Describe {
+    /// Close (F) message for the prepared statement.
+    pub fn close_statement(name: &str) -> Describe {
+        Describe {
+            code: b'C',
+            target: 'S',
+            name: name.to_string(),
+        }
+    }
+
+    pub fn is_close(&self) -> bool {
+        self.code == b'C'
+    }
+
+    /// The same message, naming another statement or portal.
+    pub fn rename(&self, name: &str) -> Describe {
+        Describe {
+            code: self.code,
+            target: self.target,
+            name: name.to_string(),
+        }
+    }
+}
+
+/// Create a ParseComplete message.
+pub fn parse_complete() -> BytesMut {
+    let mut res = BytesMut::with_capacity(5);
+    res.put_u8(b'1');
+    res.put_i32(4);
+    res
+}
+
+/// Create a CloseComplete message.
+pub fn close_complete() -> BytesMut {
+    let mut res = BytesMut::with_capacity(5);
+    res.put_u8(b'3');
+    res.put_i32(4);
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn bind(portal: &str, statement: &str) -> BytesMut {
+        let mut body = BytesMut::new();
+        body.put_slice(portal.as_bytes());
+        body.put_u8(0);
+        body.put_slice(statement.as_bytes());
+        body.put_u8(0);
+        body.put_i16(0); // parameter formats
+        body.put_i16(1); // parameter values
+        body.put_i32(2);
+        body.put_slice(b"42");
+        body.put_i16(0); // result formats
+
+        let mut res = BytesMut::new();
+        res.put_u8(b'B');
+        res.put_i32(body.len() as i32 + 4);
+        res.put(body);
+        res
+    }
+
+    #[test]
+    fn test_parse() {
+        let parse = Parse {
+            name: "s1".into(),
+            query: "SELECT $1".into(),
+            param_types: vec![23],
+        };
+        let bytes = BytesMut::from(&parse);
+
+        assert_eq!(bytes[0], b'P');
+        assert_eq!((&bytes[1..5]).get_i32() as usize, bytes.len() - 1);
+        assert_eq!(Parse::try_from(&bytes).unwrap(), parse);
+        assert!(!parse.anonymous());
+
+        // Truncated parameter types.
+        let truncated = BytesMut::from(&bytes[..bytes.len() - 2]);
+        assert!(Parse::try_from(&truncated).is_err());
+    }
+
+    #[test]
+    fn test_parse_rename() {
+        let parse = Parse {
+            name: "s1".into(),
+            query: "SELECT $1".into(),
+            param_types: vec![23],
+        };
+        let renamed = parse.rename();
+
+        assert!(renamed.name.starts_with("PGCAT_"));
+        assert_eq!(renamed.query, parse.query);
+
+        // Other clients preparing the same statement get the same name.
+        let other = Parse {
+            name: "other".into(),
+            ..parse.clone()
+        };
+        assert_eq!(other.rename().name, renamed.name);
+
+        // Different parameter types make a different statement.
+        let other = Parse {
+            param_types: vec![20],
+            ..parse.clone()
+        };
+        assert_ne!(other.rename().name, renamed.name);
+    }
+
+    #[test]
+    fn test_bind() {
+        let bytes = bind("p1", "s1");
+        let parsed = Bind::try_from(&bytes).unwrap();
+
+        assert_eq!(parsed.portal, "p1");
+        assert_eq!(parsed.statement, "s1");
+        assert_eq!(BytesMut::from(&parsed), bytes);
+
+        // Parameters are kept as they are.
+        let renamed = BytesMut::from(&parsed.rename("PGCAT_1"));
+        assert_eq!(renamed, bind("p1", "PGCAT_1"));
+    }
+
+    #[test]
+    fn test_describe() {
+        let close = BytesMut::from(&Describe::close_statement("s1"));
+        assert_eq!(&close[..], b"C\0\0\0\x08Ss1\0");
+
+        let parsed = Describe::try_from(&close).unwrap();
+        assert!(parsed.is_close());
+        assert_eq!(parsed.target, 'S');
+        assert_eq!(parsed.name, "s1");
+
+        let describe = Describe::try_from(&BytesMut::from(&b"D\0\0\0\x08Ps1\0"[..])).unwrap();
+        assert!(!describe.is_close());
+        assert_eq!(describe.target, 'P');
+        assert_eq!(
+            &BytesMut::from(&describe.rename("p2"))[..],
+            b"D\0\0\0\x08Pp2\0"
+        );
+
+        assert!(Describe::try_from(&BytesMut::from(&b"D\0\0\0\x04"[..])).is_err());
+    }
+
+    #[test]
+    fn test_complete() {
+        assert_eq!(&parse_complete()[..], b"1\0\0\0\x04");
+        assert_eq!(&close_complete()[..], b"3\0\0\0\x04");
+    }
+}
@@ -24,7 +24,7 @@ impl MirroredClient {
         let config = get_config();
         let default = std::time::Duration::from_millis(10_000).as_millis() as u64;
         let (connection_timeout, idle_timeout, _cfg) =
-            match config.pools.get(&self.address.pool_name) {
+            match config.pool_config(&self.address.pool_name) {
                 Some(cfg) => (
                     cfg.connect_timeout.unwrap_or(default),
                     cfg.idle_timeout.unwrap_or(default),
@@ -1,6 +1,6 @@
 use arc_swap::ArcSwap;
 use async_trait::async_trait;
-use bb8::{ManageConnection, Pool, PooledConnection, QueueStrategy};
+use bb8::{ManageConnection, Pool, PooledConnection, QueueStrategy, RunError};
 use bytes::{BufMut, BytesMut};
 use chrono::naive::NaiveDateTime;
 use log::{debug, error, info, warn};
@@ -9,10 +9,10 @@ use parking_lot::{Mutex, RwLock};
 use rand::seq::SliceRandom;
 use rand::thread_rng;
 use regex::Regex;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::{Display, Formatter};
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
+    atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
     Arc,
 };
 use std::time::Instant;
@@ -21,22 +21,32 @@ use tokio::sync::Notify;
 use crate::config::{
     get_config, Address, General, LoadBalancingMode, Plugins, PoolMode, Role, User,
 };
-use crate::errors::Error;
+use crate::errors::{Error, ServerIdentifier};
 
 use crate::auth_passthrough::AuthPassthrough;
 use crate::plugins::prewarmer;
 use crate::server::Server;
-use crate::sharding::ShardingFunction;
-use crate::stats::{AddressStats, ClientStats, ServerStats};
+use crate::sharding::{ShardKeys, ShardingFunction};
+use crate::stats::{get_client_stats, get_server_stats, AddressStats, ClientStats, ServerStats};
 
 pub type ProcessId = i32;
 pub type SecretKey = i32;
 pub type ServerHost = String;
 pub type ServerPort = u16;
 
+/// Replication lag of a replica in ms and how far it replayed the WAL.
+/// The lag is 0 if the replica replayed everything it received,
+/// so it doesn't grow while the primary is idle.
+const REPLICA_LAG_QUERY: &str = "SELECT CASE \
+    WHEN NOT pg_is_in_recovery() OR pg_last_wal_receive_lsn() = pg_last_wal_replay_lsn() THEN 0 \
+    ELSE COALESCE(EXTRACT(EPOCH FROM now() - pg_last_xact_replay_timestamp()) * 1000, 0) \
+    END::bigint, COALESCE(pg_last_wal_replay_lsn() - '0/0', 0)::bigint";
+
 pub type BanList = Arc<RwLock<Vec<HashMap<Address, (BanReason, NaiveDateTime)>>>>;
-pub type ClientServerMap =
-    Arc<Mutex<HashMap<(ProcessId, SecretKey), (ProcessId, SecretKey, ServerHost, ServerPort)>>>;
+/// Servers a client is using, one per shard for multi-shard queries.
+pub type ClientServerMap = Arc<
+    Mutex<HashMap<(ProcessId, SecretKey), Vec<(ProcessId, SecretKey, ServerHost, ServerPort)>>>,
+>;
 pub type PoolMap = HashMap<PoolIdentifier, ConnectionPool>;
 /// The connection pool, globally available.
 /// This is atomic and safe and read-optimized.
@@ -52,6 +62,7 @@ pub enum BanReason {
     FailedCheckout,
     StatementTimeout,
     AdminBan(i64),
+    ReplicaLag,
 }
 
 /// An identifier for a PgCat pool,
@@ -66,6 +77,18 @@ pub struct PoolIdentifier {
 }
 
 static POOL_REAPER_RATE: u64 = 30_000; // 30 seconds by default
+static AUTO_DB_REAPER_RATE: u64 = 1_000; // 1 second
+
+/// Unique ids for the server addresses of all pools.
+static ADDRESS_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Held while replacing the pools, so a pool created for `auto_db`
+/// isn't lost to a concurrent reload.
+static POOLS_LOCK: Lazy<tokio::sync::Mutex<()>> = Lazy::new(|| tokio::sync::Mutex::new(()));
+
+/// Held while checking server connection limits and registering the new connection,
+/// so concurrent connects can't go over the limits together.
+static SERVER_CONNECT_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
 
 impl PoolIdentifier {
     /// Create a new user/pool identifier.
@@ -114,9 +137,30 @@ pub struct PoolSettings {
     // Read from the primary as well or not.
     pub primary_reads_enabled: bool,
 
+    // Run read-only queries on multiple shards and merge the results.
+    pub scatter_gather_enabled: bool,
+
+    // How often to check which server is the primary, 0 if disabled.
+    pub topology_check_interval: u64,
+
+    // Maximum replication lag of replicas getting queries, 0 if disabled.
+    pub max_replica_lag: u64,
+
+    // How often to measure the replication lag.
+    pub replica_lag_check_interval: u64,
+
+    // How long to send reads to servers that replayed the client's last write, 0 if disabled.
+    pub read_your_writes_window: u64,
+
+    // Maximum number of clients waiting for a server connection, 0 if unlimited.
+    pub max_wait_queue: usize,
+
     // Sharding function.
     pub sharding_function: ShardingFunction,
 
+    // Keys of each shard, for the range and list sharding functions.
+    pub shard_keys: Arc<ShardKeys>,
+
     // Sharding key
     pub automatic_sharding_key: Option<String>,
 
@@ -158,7 +202,14 @@ impl Default for PoolSettings {
             default_role: None,
             query_parser_enabled: false,
             primary_reads_enabled: true,
+            scatter_gather_enabled: false,
+            topology_check_interval: 0,
+            max_replica_lag: 0,
+            replica_lag_check_interval: crate::config::Pool::default_replica_lag_check_interval(),
+            read_your_writes_window: 0,
+            max_wait_queue: 0,
             sharding_function: ShardingFunction::PgBigintHash,
+            shard_keys: Arc::default(),
             automatic_sharding_key: None,
             healthcheck_delay: General::default_healthcheck_delay(),
             healthcheck_timeout: General::default_healthcheck_timeout(),
@@ -182,7 +233,8 @@ pub struct ConnectionPool {
 
     /// The addresses (host, port, role) to handle
     /// failover and load balancing deterministically.
-    addresses: Vec<Vec<Address>>,
+    /// Roles are updated by the topology check after a failover.
+    addresses: Arc<RwLock<Vec<Vec<Address>>>>,
 
     /// List of banned addresses (see above)
     /// that should not be queried.
@@ -211,17 +263,73 @@ pub struct ConnectionPool {
 
     /// AuthInfo
     pub auth_hash: Arc<RwLock<Option<String>>>,
+
+    /// Number of clients waiting for a server connection.
+    waiting: Arc<AtomicUsize>,
+
+    /// Clients rejected because of `max_client_conn`.
+    client_conn_rejected: Arc<AtomicU64>,
+
+    /// Transactions rejected because of `max_wait_queue`.
+    wait_queue_rejected: Arc<AtomicU64>,
+
+    /// If the pool has been disabled, new clients can't connect to it.
+    disabled: Arc<AtomicBool>,
+
+    /// If the pool was created from the `auto_db` template.
+    auto_db: bool,
 }
 
 impl ConnectionPool {
     /// Construct the connection pool from the configuration.
     pub async fn from_config(client_server_map: ClientServerMap) -> Result<(), Error> {
         let config = get_config();
+        let _lock = POOLS_LOCK.lock().await;
+
+        let mut new_pools =
+            Self::create(config.pools.iter(), false, client_server_map.clone()).await?;
+
+        // Keep the pools created for auto_db, unless their database is configured now.
+        if let Some(template) = config.auto_db_template() {
+            let auto_db_configs = get_all_pools()
+                .into_iter()
+                .filter(|(identifier, pool)| {
+                    pool.auto_db && !config.pools.contains_key(&identifier.db)
+                })
+                .map(|(identifier, _)| {
+                    let pool_config = template.auto_db(&identifier.db, &identifier.user);
+                    (identifier.db, pool_config)
+                })
+                .collect::<Vec<_>>();
+
+            new_pools.extend(
+                Self::create(
+                    auto_db_configs
+                        .iter()
+                        .map(|(db, pool_config)| (db, pool_config)),
+                    true,
+                    client_server_map,
+                )
+                .await?,
+            );
+        }
+
+        POOLS.store(Arc::new(new_pools));
+        Ok(())
+    }
+
+    /// Create the pools of the database/user pairs of these pool configs,
+    /// or reuse the current ones if their config hasn't changed.
+    async fn create<'a>(
+        pool_configs: impl Iterator<Item = (&'a String, &'a crate::config::Pool)>,
+        auto_db: bool,
+        client_server_map: ClientServerMap,
+    ) -> Result<HashMap<PoolIdentifier, ConnectionPool>, Error> {
+        let config = get_config();
 
         let mut new_pools = HashMap::new();
-        let mut address_id: usize = 0;
 
-        for (pool_name, pool_config) in &config.pools {
+        for (pool_name, pool_config) in pool_configs {
             let new_pool_hash_value = pool_config.hash_value();
 
             // There is one pool per database/user pair.
@@ -229,16 +337,23 @@ impl ConnectionPool {
                 let old_pool_ref = get_pool(pool_name, &user.username);
                 let identifier = PoolIdentifier::new(pool_name, &user.username);
 
-                match old_pool_ref {
+                match &old_pool_ref {
                     Some(pool) => {
                         // If the pool hasn't changed, get existing reference and insert it into the new_pools.
                         // We replace all pools at the end, but if the reference is kept, the pool won't get re-created (bb8).
-                        if pool.config_hash == new_pool_hash_value {
+                        if pool.config_hash == new_pool_hash_value && pool.auto_db == auto_db {
                             info!(
                                 "[pool: {}][user: {}] has not changed",
                                 pool_name, user.username
                             );
-                            new_pools.insert(identifier.clone(), pool.clone());
+
+                            // General settings are not part of the pool config.
+                            let mut pool = pool.clone();
+                            pool.settings.healthcheck_delay = config.general.healthcheck_delay;
+                            pool.settings.healthcheck_timeout = config.general.healthcheck_timeout;
+                            pool.settings.ban_time = config.general.ban_time;
+
+                            new_pools.insert(identifier.clone(), pool);
                             continue;
                         }
                     }
@@ -280,7 +395,7 @@ impl ConnectionPool {
                                     continue;
                                 }
                                 mirror_addresses.push(Address {
-                                    id: address_id,
+                                    id: ADDRESS_ID.fetch_add(1, Ordering::Relaxed),
                                     database: shard.database.clone(),
                                     host: mirror_settings.host.clone(),
                                     port: mirror_settings.port,
@@ -289,16 +404,15 @@ impl ConnectionPool {
                                     replica_number,
                                     shard: shard_idx.parse::<usize>().unwrap(),
                                     username: user.username.clone(),
-                                    pool_name: pool_name.clone(),
+                                    pool_name: pool_name.to_string(),
                                     mirrors: vec![],
                                     stats: Arc::new(AddressStats::default()),
                                 });
-                                address_id += 1;
                             }
                         }
 
                         let address = Address {
-                            id: address_id,
+                            id: ADDRESS_ID.fetch_add(1, Ordering::Relaxed),
                             database: shard.database.clone(),
                             host: server.host.clone(),
                             port: server.port,
@@ -307,13 +421,11 @@ impl ConnectionPool {
                             replica_number,
                             shard: shard_idx.parse::<usize>().unwrap(),
                             username: user.username.clone(),
-                            pool_name: pool_name.clone(),
+                            pool_name: pool_name.to_string(),
                             mirrors: mirror_addresses,
                             stats: Arc::new(AddressStats::default()),
                         };
 
-                        address_id += 1;
-
                         if server.role == Role::Replica {
                             replica_number += 1;
                         }
@@ -429,7 +541,7 @@ impl ConnectionPool {
 
                 let pool = ConnectionPool {
                     databases: shards,
-                    addresses,
+                    addresses: Arc::new(RwLock::new(addresses)),
                     banlist: Arc::new(RwLock::new(banlist)),
                     config_hash: new_pool_hash_value,
                     server_info: Arc::new(RwLock::new(BytesMut::new())),
@@ -443,7 +555,7 @@ impl ConnectionPool {
                         // shards: pool_config.shards.clone(),
                         shards: shard_ids.len(),
                         user: user.clone(),
-                        db: pool_name.clone(),
+                        db: pool_name.to_string(),
                         default_role: match pool_config.default_role.as_str() {
                             "any" => None,
                             "replica" => Some(Role::Replica),
@@ -452,7 +564,14 @@ impl ConnectionPool {
                         },
                         query_parser_enabled: pool_config.query_parser_enabled,
                         primary_reads_enabled: pool_config.primary_reads_enabled,
+                        scatter_gather_enabled: pool_config.scatter_gather_enabled,
+                        topology_check_interval: pool_config.topology_check_interval,
+                        max_replica_lag: pool_config.max_replica_lag,
+                        replica_lag_check_interval: pool_config.replica_lag_check_interval,
+                        read_your_writes_window: pool_config.read_your_writes_window,
+                        max_wait_queue: pool_config.max_wait_queue,
                         sharding_function: pool_config.sharding_function,
+                        shard_keys: Arc::new(pool_config.shard_keys()),
                         automatic_sharding_key: pool_config.automatic_sharding_key.clone(),
                         healthcheck_delay: config.general.healthcheck_delay,
                         healthcheck_timeout: config.general.healthcheck_timeout,
@@ -477,6 +596,26 @@ impl ConnectionPool {
                     validated: Arc::new(AtomicBool::new(false)),
                     paused: Arc::new(AtomicBool::new(false)),
                     paused_waiter: Arc::new(Notify::new()),
+                    waiting: Arc::new(AtomicUsize::new(0)),
+                    // Keep counting rejections when the pool is recreated.
+                    client_conn_rejected: match &old_pool_ref {
+                        Some(pool) => pool.client_conn_rejected.clone(),
+                        None => Arc::new(AtomicU64::new(0)),
+                    },
+                    wait_queue_rejected: match &old_pool_ref {
+                        Some(pool) => pool.wait_queue_rejected.clone(),
+                        None => Arc::new(AtomicU64::new(0)),
+                    },
+                    // Stay disabled when the pool is recreated, or a user is added to it.
+                    disabled: match &old_pool_ref {
+                        Some(pool) => pool.disabled.clone(),
+                        None => Arc::new(AtomicBool::new(
+                            get_all_pools()
+                                .iter()
+                                .any(|(id, pool)| id.db == *pool_name && pool.disabled()),
+                        )),
+                    },
+                    auto_db,
                 };
 
                 // Connect to the servers to make sure pool configuration is valid
@@ -489,13 +628,50 @@ impl ConnectionPool {
                     });
                 }
 
+                if pool.settings.topology_check_interval > 0 {
+                    pool.spawn_check(pool.settings.topology_check_interval, |pool| async move {
+                        pool.check_topology().await
+                    });
+                }
+
+                if pool.settings.max_replica_lag > 0 || pool.settings.read_your_writes_window > 0 {
+                    pool.spawn_check(
+                        pool.settings.replica_lag_check_interval,
+                        |pool| async move { pool.check_replica_lag().await },
+                    );
+                }
+
                 // There is one pool per database/user pair.
-                new_pools.insert(PoolIdentifier::new(pool_name, &user.username), pool);
+                new_pools.insert(identifier, pool);
             }
         }
 
-        POOLS.store(Arc::new(new_pools.clone()));
-        Ok(())
+        Ok(new_pools)
+    }
+
+    /// Run a check on the pool in the background every `interval` ms,
+    /// until the pool is replaced by a config reload.
+    fn spawn_check<F, Fut>(&self, interval: u64, check: F)
+    where
+        F: Fn(ConnectionPool) -> Fut + Send + 'static,
+        Fut: std::future::Future<Output = ()> + Send,
+    {
+        let addresses = Arc::clone(&self.addresses);
+        let pool_name = self.settings.db.clone();
+        let username = self.settings.user.username.clone();
+
+        tokio::task::spawn(async move {
+            loop {
+                tokio::time::sleep(tokio::time::Duration::from_millis(interval)).await;
+
+                let pool = match get_pool(&pool_name, &username) {
+                    Some(pool) if Arc::ptr_eq(&pool.addresses, &addresses) => pool,
+                    _ => break,
+                };
+
+                check(pool).await;
+            }
+        });
     }
 
     /// Connect to all shards, grab server information, and possibly
@@ -573,6 +749,21 @@ impl ConnectionPool {
         self.paused.load(Ordering::Relaxed)
     }
 
+    /// Disable the pool, new clients can't connect to it.
+    pub fn disable(&self) {
+        self.disabled.store(true, Ordering::Relaxed);
+    }
+
+    /// Enable the pool, allowing new clients again.
+    pub fn enable(&self) {
+        self.disabled.store(false, Ordering::Relaxed);
+    }
+
+    /// Check if the pool is disabled.
+    pub fn disabled(&self) -> bool {
+        self.disabled.load(Ordering::Relaxed)
+    }
+
     /// Check if the pool is paused and wait until it's resumed.
     pub async fn wait_paused(&self) -> bool {
         let waiter = self.paused_waiter.notified();
@@ -585,18 +776,74 @@ impl ConnectionPool {
         paused
     }
 
+    /// Close the server connections of the pool: the idle ones now, and the ones
+    /// in use when they're returned to the pool. New ones are opened as needed.
+    pub async fn reconnect(&self) {
+        for server in get_server_stats().values() {
+            if server.pool_name() == self.settings.db
+                && server.username() == self.settings.user.username
+            {
+                server.close();
+            }
+        }
+
+        // Take the idle connections out of the pool, it closes them when we give them back.
+        for shard in self.databases.iter() {
+            for pool in shard.iter() {
+                let idle = pool.state().idle_connections;
+                let mut connections = Vec::new();
+
+                for _ in 0..idle {
+                    match tokio::time::timeout(tokio::time::Duration::ZERO, pool.get()).await {
+                        Ok(Ok(connection)) => connections.push(connection),
+                        _ => break,
+                    }
+                }
+            }
+        }
+    }
+
     /// Get a connection from the pool.
     pub async fn get(
         &self,
-        shard: usize,               // shard number
-        role: Option<Role>,         // primary or replica
-        client_stats: &ClientStats, // client id
+        shard: usize,                // shard number
+        role: Option<Role>,          // primary or replica
+        client_stats: &ClientStats,  // client id
+        min_replay_lsn: Option<u64>, // WAL position replicas must have replayed
     ) -> Result<(PooledConnection<'_, ServerPool>, Address), Error> {
-        let mut candidates: Vec<&Address> = self.addresses[shard]
+        // Don't let more clients wait for a connection.
+        let waiting = self.waiting.fetch_add(1, Ordering::Relaxed);
+        let _waiting = Waiting(&self.waiting);
+
+        if self.settings.max_wait_queue > 0 && waiting >= self.settings.max_wait_queue {
+            self.wait_queue_rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(Error::MaxWaitQueueReached);
+        }
+
+        let addresses = self.addresses.read()[shard].clone();
+
+        let mut candidates: Vec<Address> = addresses
             .iter()
             .filter(|address| address.role == role)
+            .filter(|address| replayed(address, min_replay_lsn))
+            .cloned()
             .collect();
 
+        // No replica replayed the client's last write yet, read it from the primary.
+        // Same if all the replicas are too far behind, they stay banned until they catch up.
+        if (candidates.is_empty() && min_replay_lsn.is_some())
+            || (role == Some(Role::Replica)
+                && !candidates.is_empty()
+                && candidates
+                    .iter()
+                    .all(|address| self.banned_for_lag(address)))
+        {
+            candidates = addresses
+                .into_iter()
+                .filter(|address| address.role == Role::Primary)
+                .collect();
+        }
+
         // We shuffle even if least_outstanding_queries is used to avoid imbalance
         // in cases where all candidates have more or less the same number of outstanding
         // queries
@@ -613,6 +860,8 @@ impl ConnectionPool {
         let now = Instant::now();
         client_stats.waiting();
 
+        let mut conn_limit_reached = false;
+
         while !candidates.is_empty() {
             // Get the next candidate
             let address = match candidates.pop() {
@@ -622,7 +871,7 @@ impl ConnectionPool {
 
             let mut force_healthcheck = false;
 
-            if self.is_banned(address) {
+            if self.is_banned(&address) {
                 if self.try_unban(&address).await {
                     force_healthcheck = true;
                 } else {
@@ -638,11 +887,25 @@ impl ConnectionPool {
             {
                 Ok(conn) => conn,
                 Err(err) => {
+                    // The server is fine, we are just not allowed to open more connections to it.
+                    if matches!(err, RunError::User(Error::MaxServerConnectionsReached))
+                        || address.stats.conn_limit_reached()
+                    {
+                        warn!(
+                            "Server connection limit reached for instance {:?}, error: {:?}",
+                            address, err
+                        );
+                        conn_limit_reached = true;
+                        client_stats.idle();
+                        client_stats.checkout_error();
+                        continue;
+                    }
+
                     error!(
                         "Connection checkout error for instance {:?}, error: {:?}",
                         address, err
                     );
-                    self.ban(address, BanReason::FailedCheckout, Some(client_stats));
+                    self.ban(&address, BanReason::FailedCheckout, Some(client_stats));
                     address.stats.error();
                     client_stats.idle();
                     client_stats.checkout_error();
@@ -653,6 +916,13 @@ impl ConnectionPool {
             // // Check if this server is alive with a health check.
             let server = &mut *conn;
 
+            // Closed by an admin while idle, the pool drops it when we return it.
+            if server.is_bad() {
+                debug!("Server {:?} is bad, getting another one", address);
+                candidates.push(address);
+                continue;
+            }
+
             // Will return error if timestamp is greater than current system time, which it should never be set to
             let require_healthcheck = force_healthcheck
                 || server.last_activity().elapsed().unwrap().as_millis()
@@ -669,11 +939,11 @@ impl ConnectionPool {
                     .checkout_time(checkout_time, client_stats.application_name());
                 server.stats().active(client_stats.application_name());
                 client_stats.active();
-                return Ok((conn, address.clone()));
+                return Ok((conn, address));
             }
 
             if self
-                .run_health_check(address, server, now, client_stats)
+                .run_health_check(&address, server, now, client_stats)
                 .await
             {
                 let checkout_time: u64 = now.elapsed().as_micros() as u64;
@@ -683,13 +953,18 @@ impl ConnectionPool {
                     .checkout_time(checkout_time, client_stats.application_name());
                 server.stats().active(client_stats.application_name());
                 client_stats.active();
-                return Ok((conn, address.clone()));
+                return Ok((conn, address));
             } else {
                 continue;
             }
         }
         client_stats.idle();
-        Err(Error::AllServersDown)
+
+        if conn_limit_reached {
+            Err(Error::MaxServerConnectionsReached)
+        } else {
+            Err(Error::AllServersDown)
+        }
     }
 
     async fn run_health_check(
@@ -790,6 +1065,14 @@ impl ConnectionPool {
         }
     }
 
+    /// The replica is banned because it's too far behind the primary.
+    fn banned_for_lag(&self, address: &Address) -> bool {
+        matches!(
+            self.banlist.read()[address.shard].get(address),
+            Some((BanReason::ReplicaLag, _))
+        )
+    }
+
     /// Determines trying to unban this server was successful
     pub async fn try_unban(&self, address: &Address) -> bool {
         // If somehow primary ends up being banned we should return true here
@@ -798,7 +1081,7 @@ impl ConnectionPool {
         }
 
         // Check if all replicas are banned, in that case unban all of them
-        let replicas_available = self.addresses[address.shard]
+        let replicas_available = self.addresses.read()[address.shard]
             .iter()
             .filter(|addr| addr.role == Role::Replica)
             .count();
@@ -812,9 +1095,11 @@ impl ConnectionPool {
         if all_replicas_banned {
             let mut write_guard = self.banlist.write();
             warn!("Unbanning all replicas.");
-            write_guard[address.shard].clear();
 
-            return true;
+            // Lagging replicas are unbanned by the replica lag check once they catch up.
+            write_guard[address.shard].retain(|_, (reason, _)| *reason == BanReason::ReplicaLag);
+
+            return !write_guard[address.shard].contains_key(address);
         }
 
         // Check if ban time is expired
@@ -826,6 +1111,8 @@ impl ConnectionPool {
                     BanReason::AdminBan(duration) => {
                         now.timestamp() - timestamp.timestamp() > *duration
                     }
+                    // Lifted by the replica lag check once the replica catches up.
+                    BanReason::ReplicaLag => false,
                     _ => now.timestamp() - timestamp.timestamp() > self.settings.ban_time,
                 }
             }
@@ -848,7 +1135,7 @@ impl ConnectionPool {
 
     /// Get the number of configured shards.
     pub fn shards(&self) -> usize {
-        self.databases.len()
+        self.addresses.read().len()
     }
 
     pub fn get_bans(&self) -> Vec<(Address, (BanReason, NaiveDateTime))> {
@@ -869,7 +1156,7 @@ impl ConnectionPool {
             for server in 0..self.servers(shard) {
                 let address = self.address(shard, server);
                 if address.host == host {
-                    addresses.push(address.clone());
+                    addresses.push(address);
                 }
             }
         }
@@ -879,7 +1166,7 @@ impl ConnectionPool {
     /// Get the number of servers (primary and replicas)
     /// configured for a shard.
     pub fn servers(&self, shard: usize) -> usize {
-        self.addresses[shard].len()
+        self.addresses.read()[shard].len()
     }
 
     /// Get the total number of servers (databases) we are connected to.
@@ -897,14 +1184,214 @@ impl ConnectionPool {
     }
 
     /// Get the address information for a shard server.
-    pub fn address(&self, shard: usize, server: usize) -> &Address {
-        &self.addresses[shard][server]
+    pub fn address(&self, shard: usize, server: usize) -> Address {
+        self.addresses.read()[shard][server].clone()
+    }
+
+    /// Check which server is the primary of each shard with `pg_is_in_recovery()`
+    /// and update the roles if they changed, e.g. after a failover.
+    pub async fn check_topology(&self) {
+        for shard in 0..self.shards() {
+            let mut primaries = Vec::new();
+
+            for server in 0..self.servers(shard) {
+                if self.in_recovery(shard, server).await == Some(false) {
+                    primaries.push(server);
+                }
+            }
+
+            match primaries[..] {
+                [primary] => self.set_primary(shard, primary),
+
+                // The primary is down or a replica is still being promoted.
+                [] => debug!(
+                    "[pool: {}][user: {}] No primary found in shard {}",
+                    self.settings.db, self.settings.user.username, shard
+                ),
+
+                _ => error!(
+                    "[pool: {}][user: {}] More than one server in shard {} is not in recovery, \
+                    not changing roles",
+                    self.settings.db, self.settings.user.username, shard
+                ),
+            }
+        }
+    }
+
+    /// Check if the server is a replica, returns None if we couldn't tell.
+    async fn in_recovery(&self, shard: usize, server: usize) -> Option<bool> {
+        match self
+            .query_row(shard, server, "SELECT pg_is_in_recovery()")
+            .await
+            .as_deref()
+        {
+            Some([value]) if value == "t" => Some(true),
+            Some([value]) if value == "f" => Some(false),
+            _ => None,
+        }
+    }
+
+    /// Measure the replication lag of the replicas and stop sending queries
+    /// to the ones that are further behind than `max_replica_lag`.
+    /// Also records how far they replayed the WAL for `read_your_writes_window`.
+    pub async fn check_replica_lag(&self) {
+        for shard in 0..self.shards() {
+            for server in 0..self.servers(shard) {
+                let address = self.address(shard, server);
+
+                if address.role != Role::Replica {
+                    address.stats.set_replica_lag(0);
+                    continue;
+                }
+
+                let row = match self.query_row(shard, server, REPLICA_LAG_QUERY).await {
+                    Some(row) => row,
+                    None => continue,
+                };
+
+                let (lag, replay_lsn) = match row
+                    .iter()
+                    .map(|value| value.parse::<u64>())
+                    .collect::<Result<Vec<u64>, _>>()
+                    .as_deref()
+                {
+                    Ok([lag, replay_lsn]) => (*lag, *replay_lsn),
+                    _ => continue,
+                };
+
+                address.stats.set_replica_lag(lag);
+                address.stats.set_replay_lsn(replay_lsn);
+
+                if self.settings.max_replica_lag == 0 {
+                    continue;
+                }
+
+                let banned_for_lag = self.banned_for_lag(&address);
+
+                if lag > self.settings.max_replica_lag {
+                    if !self.is_banned(&address) {
+                        warn!("Replica {} is {}ms behind the primary", address, lag);
+                        self.ban(&address, BanReason::ReplicaLag, None);
+                    }
+                } else if banned_for_lag {
+                    info!("Replica {} caught up with the primary", address);
+                    self.unban(&address);
+                }
+            }
+        }
+    }
+
+    /// Run a query returning a single row on a server, for background checks.
+    /// Returns None if the server couldn't answer.
+    async fn query_row(&self, shard: usize, server: usize, query: &str) -> Option<Vec<String>> {
+        let mut conn = match self.databases[shard][server].get().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                warn!(
+                    "Could not get a connection to {} to run `{}`, error: {:?}",
+                    self.address(shard, server),
+                    query,
+                    err
+                );
+                return None;
+            }
+        };
+
+        match tokio::time::timeout(
+            tokio::time::Duration::from_millis(self.settings.healthcheck_timeout),
+            conn.query_values(query),
+        )
+        .await
+        {
+            Ok(Ok(values)) => Some(values),
+
+            Ok(Err(err)) => {
+                warn!(
+                    "Failed to run `{}` on {}, error: {:?}",
+                    query,
+                    self.address(shard, server),
+                    err
+                );
+                conn.mark_bad();
+                None
+            }
+
+            Err(err) => {
+                warn!(
+                    "Timeout running `{}` on {}, error: {:?}",
+                    query,
+                    self.address(shard, server),
+                    err
+                );
+                conn.mark_bad();
+                None
+            }
+        }
+    }
+
+    /// Make the server the primary of the shard and all the others replicas.
+    fn set_primary(&self, shard: usize, primary: usize) {
+        let mut addresses = self.addresses.write();
+
+        if addresses[shard]
+            .iter()
+            .all(|address| (address.role == Role::Primary) == (address.address_index == primary))
+        {
+            return;
+        }
+
+        let mut changed = Vec::new();
+        let mut replica_number = 0;
+
+        for address in addresses[shard].iter_mut() {
+            let old_name = address.name();
+
+            address.role = match address.address_index == primary {
+                true => Role::Primary,
+                false => Role::Replica,
+            };
+            address.replica_number = replica_number;
+
+            if address.role == Role::Replica {
+                replica_number += 1;
+            }
+
+            if address.name() != old_name {
+                warn!(
+                    "[pool: {}][user: {}] {}:{} changed from {} to {}",
+                    address.pool_name,
+                    address.username,
+                    address.host,
+                    address.port,
+                    old_name,
+                    address.name()
+                );
+                changed.push(address.id);
+            }
+        }
+
+        // Bans are keyed by address, including the old role.
+        self.banlist.write()[shard].retain(|address, _| !changed.contains(&address.id));
     }
 
     pub fn server_info(&self) -> BytesMut {
         self.server_info.read().clone()
     }
 
+    /// Count a client rejected because of `max_client_conn`.
+    pub fn client_conn_rejected(&self) {
+        self.client_conn_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Number of clients and transactions rejected
+    /// because of `max_client_conn` and `max_wait_queue`.
+    pub fn rejected(&self) -> (u64, u64) {
+        (
+            self.client_conn_rejected.load(Ordering::Relaxed),
+            self.wait_queue_rejected.load(Ordering::Relaxed),
+        )
+    }
+
     fn busy_connection_count(&self, address: &Address) -> u32 {
         let state = self.pool_state(address.shard, address.address_index);
         let idle = state.idle_connections;
@@ -920,6 +1407,15 @@ impl ConnectionPool {
     }
 }
 
+/// A client waiting for a server connection, until dropped.
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 /// Wrapper for the bb8 connection pool.
 pub struct ServerPool {
     /// Server address.
@@ -977,10 +1473,27 @@ impl ManageConnection for ServerPool {
 
         let stats = Arc::new(ServerStats::new(
             self.address.clone(),
+            self.user.server_username(),
             tokio::time::Instant::now(),
         ));
 
-        stats.register(stats.clone());
+        {
+            let _lock = SERVER_CONNECT_LOCK.lock();
+
+            // Tells the clients waiting for a connection not to ban the server.
+            let conn_limit_reached =
+                server_conn_limit_reached(&self.address, self.user.server_username());
+            self.address
+                .stats
+                .set_conn_limit_reached(conn_limit_reached);
+
+            if conn_limit_reached {
+                debug!("Server connection limit reached for {:?}", self.address);
+                return Err(Error::MaxServerConnectionsReached);
+            }
+
+            stats.register(stats.clone());
+        }
 
         // Connect to the PostgreSQL server.
         match Server::startup(
@@ -1028,6 +1541,273 @@ impl ManageConnection for ServerPool {
     }
 }
 
+/// Check if another server connection to this address would go over the
+/// `max_db_connections` or `max_user_connections` limits. Connections
+/// of all pools to the same server are counted.
+fn server_conn_limit_reached(address: &Address, server_username: &str) -> bool {
+    let config = get_config();
+    let pool_config = config.pool_config(&address.pool_name);
+
+    let max_db_connections = pool_config
+        .and_then(|pool| pool.max_db_connections)
+        .unwrap_or(config.general.max_db_connections);
+    let max_user_connections = pool_config
+        .and_then(|pool| pool.max_user_connections)
+        .unwrap_or(config.general.max_user_connections);
+
+    if max_db_connections == 0 && max_user_connections == 0 {
+        return false;
+    }
+
+    let (db_connections, user_connections) =
+        server_conns(address, server_username, get_server_stats().values());
+
+    (max_db_connections > 0 && db_connections >= max_db_connections)
+        || (max_user_connections > 0 && user_connections >= max_user_connections)
+}
+
+/// Count the connections to the same server as the address: to the same database,
+/// and logged in as the same user, whichever pool they belong to.
+fn server_conns<'a>(
+    address: &Address,
+    server_username: &str,
+    servers: impl Iterator<Item = &'a Arc<ServerStats>>,
+) -> (usize, usize) {
+    let mut db_connections = 0;
+    let mut user_connections = 0;
+
+    for stats in servers {
+        let server = stats.address();
+
+        if server.host != address.host || server.port != address.port {
+            continue;
+        }
+
+        if server.database == address.database {
+            db_connections += 1;
+        }
+
+        if stats.server_username() == server_username {
+            user_connections += 1;
+        }
+    }
+
+    (db_connections, user_connections)
+}
+
+/// A database and user that aren't configured, but match the `auto_db` template.
+/// Clients authenticate against it, the pool is only created once they have.
+pub struct AutoDb {
+    pool_name: String,
+    pool_config: crate::config::Pool,
+
+    /// The template's user, for this database.
+    pub user: User,
+
+    /// The first server of the database, auth_query runs on it.
+    pub address: Address,
+
+    /// The user's password hash, from auth_query.
+    pub auth_hash: Arc<RwLock<Option<String>>>,
+}
+
+impl AutoDb {
+    /// Returns None if there is no template, if the database is configured,
+    /// or if auth_query doesn't know the user. Only the user's password hash
+    /// is fetched, no pool is created yet.
+    pub async fn new(db: &str, username: &str) -> Option<AutoDb> {
+        let config = get_config();
+
+        let template = match config.auto_db_template() {
+            Some(template) if !config.pools.contains_key(db) => template,
+            _ => return None,
+        };
+
+        let pool_config = template.auto_db(db, username);
+        let user = pool_config.users["0"].clone();
+
+        let server = pool_config
+            .shards
+            .values()
+            .next()
+            .and_then(|shard| shard.servers.first())?;
+
+        let address = Address {
+            id: ADDRESS_ID.fetch_add(1, Ordering::Relaxed),
+            database: db.to_string(),
+            host: server.host.clone(),
+            port: server.port,
+            role: server.role,
+            username: username.to_string(),
+            pool_name: db.to_string(),
+            ..Default::default()
+        };
+
+        let mut auth_hash = None;
+
+        // Check the user exists, the client authenticates with the hash.
+        if user.password.is_none() {
+            let auth_passthrough = AuthPassthrough::from_pool_config(&pool_config)?;
+
+            match auth_passthrough.fetch_hash(&address).await {
+                Ok(hash) => auth_hash = Some(hash),
+                Err(err) => {
+                    warn!(
+                        "[pool: {}][user: {}] not creating an auto_db pool: {:?}",
+                        db, username, err
+                    );
+                    return None;
+                }
+            }
+        }
+
+        Some(AutoDb {
+            pool_name: db.to_string(),
+            pool_config,
+            user,
+            address,
+            auth_hash: Arc::new(RwLock::new(auth_hash)),
+        })
+    }
+
+    /// How to fetch the user's password hash again, if it changed.
+    pub fn auth_passthrough(&self) -> Option<AuthPassthrough> {
+        AuthPassthrough::from_pool_config(&self.pool_config)
+    }
+
+    /// New clients can't connect to a disabled database.
+    pub fn disabled(&self) -> bool {
+        get_all_pools()
+            .iter()
+            .any(|(id, pool)| id.db == self.pool_name && pool.disabled())
+    }
+
+    /// Create the pool and share it with other clients. If another client
+    /// created one for the same database and user first, that one is returned.
+    pub async fn create(self, client_server_map: ClientServerMap) -> Result<ConnectionPool, Error> {
+        let identifier = PoolIdentifier::new(&self.pool_name, &self.user.username);
+
+        info!(
+            "[pool: {}][user: {}] creating pool from auto_db template {}",
+            self.pool_name,
+            self.user.username,
+            get_config().general.auto_db.as_ref().unwrap()
+        );
+
+        let mut new_pools = ConnectionPool::create(
+            std::iter::once((&self.pool_name, &self.pool_config)),
+            true,
+            client_server_map,
+        )
+        .await?;
+
+        let pool = match new_pools.remove(&identifier) {
+            Some(pool) => pool,
+            None => return Err(Error::BadConfig),
+        };
+
+        let _lock = POOLS_LOCK.lock().await;
+        let mut pools = get_all_pools();
+
+        if let Some(pool) = pools.get(&identifier) {
+            return Ok(pool.clone());
+        }
+
+        pools.insert(identifier, pool.clone());
+        POOLS.store(Arc::new(pools));
+
+        Ok(pool)
+    }
+}
+
+/// Remove the pools created for `auto_db` that had no clients
+/// for `auto_db_idle_timeout`.
+pub async fn reap_auto_db_pools() {
+    let mut idle_since: HashMap<PoolIdentifier, Instant> = HashMap::new();
+    let mut interval =
+        tokio::time::interval(tokio::time::Duration::from_millis(AUTO_DB_REAPER_RATE));
+
+    loop {
+        interval.tick().await;
+
+        let idle_timeout = get_config().general.auto_db_idle_timeout;
+        let in_use = pools_in_use();
+        let pools = get_all_pools();
+        let mut expired = Vec::new();
+
+        idle_since.retain(|identifier, _| pools.contains_key(identifier));
+
+        for (identifier, pool) in pools.iter() {
+            if !pool.auto_db || in_use.contains(identifier) {
+                idle_since.remove(identifier);
+                continue;
+            }
+
+            let since = idle_since
+                .entry(identifier.clone())
+                .or_insert_with(Instant::now);
+
+            if since.elapsed().as_millis() as u64 >= idle_timeout {
+                expired.push(identifier.clone());
+            }
+        }
+
+        if expired.is_empty() {
+            continue;
+        }
+
+        let _lock = POOLS_LOCK.lock().await;
+
+        // Clients may have connected in the meantime.
+        let in_use = pools_in_use();
+        let mut pools = get_all_pools();
+
+        for identifier in expired {
+            if in_use.contains(&identifier) {
+                continue;
+            }
+
+            info!(
+                "[pool: {}][user: {}] removing idle auto_db pool",
+                identifier.db, identifier.user
+            );
+
+            pools.remove(&identifier);
+            idle_since.remove(&identifier);
+        }
+
+        POOLS.store(Arc::new(pools));
+    }
+}
+
+#[cfg(test)]
+impl ConnectionPool {
+    /// A pool of these servers, that never connects to them.
+    pub fn with_addresses(addresses: Vec<Vec<Address>>) -> ConnectionPool {
+        ConnectionPool {
+            banlist: Arc::new(RwLock::new(vec![HashMap::new(); addresses.len()])),
+            addresses: Arc::new(RwLock::new(addresses)),
+            ..Default::default()
+        }
+    }
+}
+
+/// The server has the client's last write, the primary always does.
+fn replayed(address: &Address, min_replay_lsn: Option<u64>) -> bool {
+    match min_replay_lsn {
+        Some(lsn) => address.role == Role::Primary || address.stats.replay_lsn() >= lsn,
+        None => true,
+    }
+}
+
+/// The pools clients are connected to.
+fn pools_in_use() -> HashSet<PoolIdentifier> {
+    get_client_stats()
+        .values()
+        .map(|client| PoolIdentifier::new(&client.pool_name(), &client.username()))
+        .collect()
+}
+
 /// Get the connection pool
 pub fn get_pool(db: &str, user: &str) -> Option<ConnectionPool> {
     (*(*POOLS.load()))
@@ -1039,3 +1819,280 @@ pub fn get_pool(db: &str, user: &str) -> Option<ConnectionPool> {
 pub fn get_all_pools() -> HashMap<PoolIdentifier, ConnectionPool> {
     (*(*POOLS.load())).clone()
 }
+
+/// Connect once to every server of every pool in the config, as every user,
+/// without creating the pools. Logs the servers that can't be reached and
+/// returns true if all of them are.
+pub async fn check_servers() -> bool {
+    let config = get_config();
+    let mut failed = 0;
+
+    for (pool_name, pool_config) in &config.pools {
+        let connect_timeout = pool_config
+            .connect_timeout
+            .unwrap_or(config.general.connect_timeout);
+        let auth_passthrough = AuthPassthrough::from_pool_config(pool_config);
+
+        let mut shard_ids = pool_config.shards.keys().collect::<Vec<&String>>();
+        shard_ids.sort_by_key(|k| k.parse::<i64>().unwrap());
+
+        for user in pool_config.users.values() {
+            for shard_idx in &shard_ids {
+                let shard = &pool_config.shards[*shard_idx];
+
+                for (address_index, server) in shard.servers.iter().enumerate() {
+                    let address = Address {
+                        id: ADDRESS_ID.fetch_add(1, Ordering::Relaxed),
+                        database: shard.database.clone(),
+                        host: server.host.clone(),
+                        port: server.port,
+                        role: server.role,
+                        address_index,
+                        shard: shard_idx.parse::<usize>().unwrap(),
+                        username: user.username.clone(),
+                        pool_name: pool_name.clone(),
+                        ..Default::default()
+                    };
+
+                    let result = tokio::time::timeout(
+                        std::time::Duration::from_millis(connect_timeout),
+                        check_server(&address, user, &shard.database, &auth_passthrough),
+                    )
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(Error::ServerStartupError(
+                            "timed out".into(),
+                            ServerIdentifier::new(user.server_username(), &shard.database),
+                        ))
+                    });
+
+                    match result {
+                        Ok(()) => info!("[pool: {}]{} ok", pool_name, address),
+                        Err(err) => {
+                            error!(
+                                "[pool: {}]{} could not connect: {:?}",
+                                pool_name, address, err
+                            );
+                            failed += 1;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    if failed > 0 {
+        error!("Could not connect to {} server(s)", failed);
+    }
+
+    failed == 0
+}
+
+/// Connect to a server and disconnect right away.
+async fn check_server(
+    address: &Address,
+    user: &User,
+    database: &str,
+    auth_passthrough: &Option<AuthPassthrough>,
+) -> Result<(), Error> {
+    let auth_hash = match auth_passthrough {
+        Some(auth_passthrough) => Some(auth_passthrough.fetch_hash(address).await?),
+        None => None,
+    };
+
+    let stats = Arc::new(ServerStats::new(
+        address.clone(),
+        user.server_username(),
+        tokio::time::Instant::now(),
+    ));
+
+    Server::startup(
+        address,
+        user,
+        database,
+        Arc::new(Mutex::new(HashMap::new())),
+        stats,
+        Arc::new(RwLock::new(auth_hash)),
+        false,
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A pool of one shard with a primary and two replicas.
+    fn pool() -> ConnectionPool {
+        let addresses = (0..3)
+            .map(|index| Address {
+                id: index,
+                address_index: index,
+                replica_number: index.saturating_sub(1),
+                role: match index {
+                    0 => Role::Primary,
+                    _ => Role::Replica,
+                },
+                ..Default::default()
+            })
+            .collect();
+
+        ConnectionPool::with_addresses(vec![addresses])
+    }
+
+    fn names(pool: &ConnectionPool) -> Vec<String> {
+        pool.addresses.read()[0]
+            .iter()
+            .map(|address| address.name())
+            .collect()
+    }
+
+    #[test]
+    fn test_set_primary() {
+        let pool = pool();
+        let before = names(&pool);
+
+        // Same primary, nothing changes.
+        pool.set_primary(0, 0);
+        assert_eq!(names(&pool), before);
+
+        pool.ban(&pool.address(0, 1), BanReason::FailedCheckout, None);
+        pool.ban(&pool.address(0, 2), BanReason::FailedCheckout, None);
+
+        pool.set_primary(0, 1);
+        assert_eq!(
+            names(&pool),
+            vec![
+                "pool_name_shard_0_replica_0",
+                "pool_name_shard_0_primary",
+                "pool_name_shard_0_replica_1",
+            ]
+        );
+
+        // The new primary isn't banned, the replica that kept its name still is.
+        assert!(!pool.is_banned(&pool.address(0, 1)));
+        assert!(pool.is_banned(&pool.address(0, 2)));
+        assert_eq!(pool.banlist.read()[0].len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() {
+        let mut pool = pool();
+        pool.settings.db = "reconnect_db".into();
+        pool.settings.user.username = "reconnect_user".into();
+
+        let stats = |pool_name: &str, username: &str| {
+            let stats = Arc::new(ServerStats::new(
+                Address {
+                    pool_name: pool_name.into(),
+                    username: username.into(),
+                    ..Default::default()
+                },
+                username,
+                tokio::time::Instant::now(),
+            ));
+            stats.register(stats.clone());
+            stats
+        };
+
+        let servers = [
+            stats("reconnect_db", "reconnect_user"),
+            stats("reconnect_db", "other_user"),
+            stats("other_db", "reconnect_user"),
+        ];
+
+        pool.reconnect().await;
+
+        // Only the connections of the pool are closed.
+        assert_eq!(
+            servers
+                .iter()
+                .map(|server| server.closing())
+                .collect::<Vec<_>>(),
+            vec![true, false, false]
+        );
+
+        for server in servers {
+            server.disconnect();
+        }
+    }
+
+    #[test]
+    fn test_server_conns() {
+        let address = |pool_name: &str, database: &str, username: &str| Address {
+            pool_name: pool_name.into(),
+            database: database.into(),
+            username: username.into(),
+            ..Default::default()
+        };
+        let stats = |address: Address, server_username: &str| {
+            Arc::new(ServerStats::new(
+                address,
+                server_username,
+                tokio::time::Instant::now(),
+            ))
+        };
+
+        let servers = vec![
+            // Two pools logging in as the same server user.
+            stats(address("a", "db", "alice"), "app"),
+            stats(address("b", "db", "bob"), "app"),
+            stats(address("b", "other", "bob"), "bob"),
+            stats(
+                Address {
+                    port: 5433,
+                    ..address("a", "db", "alice")
+                },
+                "app",
+            ),
+        ];
+
+        assert_eq!(
+            server_conns(&address("a", "db", "alice"), "app", servers.iter()),
+            (2, 2)
+        );
+        assert_eq!(
+            server_conns(&address("c", "other", "carol"), "bob", servers.iter()),
+            (1, 1)
+        );
+        assert_eq!(
+            server_conns(&address("c", "new", "carol"), "carol", servers.iter()),
+            (0, 0)
+        );
+    }
+
+    #[test]
+    fn test_replayed() {
+        let pool = pool();
+        let (primary, replica) = (pool.address(0, 0), pool.address(0, 1));
+        replica.stats.set_replay_lsn(100);
+
+        assert!(replayed(&replica, None));
+        assert!(replayed(&replica, Some(99)));
+        assert!(replayed(&replica, Some(100)));
+        assert!(!replayed(&replica, Some(101)));
+        assert!(replayed(&primary, Some(101)));
+    }
+
+    #[tokio::test]
+    async fn test_unban_all_keeps_lagging_replicas() {
+        let pool = pool();
+        let (lagging, failed) = (pool.address(0, 1), pool.address(0, 2));
+
+        pool.ban(&lagging, BanReason::ReplicaLag, None);
+        assert!(!pool.try_unban(&lagging).await);
+
+        // All replicas are banned, only the ones that aren't lagging are unbanned.
+        pool.ban(&failed, BanReason::FailedCheckout, None);
+        assert!(pool.try_unban(&failed).await);
+        assert!(!pool.is_banned(&failed));
+        assert!(pool.is_banned(&lagging));
+
+        pool.ban(&failed, BanReason::FailedCheckout, None);
+        assert!(!pool.try_unban(&lagging).await);
+        assert!(pool.banned_for_lag(&lagging));
+        assert!(!pool.is_banned(&failed));
+    }
+}
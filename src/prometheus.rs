@@ -4,12 +4,14 @@ use log::{error, info, warn};
 use phf::phf_map;
 use std::collections::HashMap;
 use std::fmt;
-use std::net::SocketAddr;
+use std::net::TcpListener;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use tokio::sync::watch;
 
-use crate::config::Address;
-use crate::pool::{get_all_pools, PoolIdentifier};
+use crate::admin_api::admin_api;
+use crate::config::{get_config, Address};
+use crate::pool::{get_all_pools, ClientServerMap, PoolIdentifier};
 use crate::stats::pool::PoolStats;
 use crate::stats::{get_server_stats, ServerStats};
 
@@ -102,6 +104,14 @@ static METRIC_HELP_AND_TYPES_LOOKUP: phf::Map<&'static str, MetricHelpType> = ph
         help: "How many clients are idle",
         ty: "gauge",
     },
+    "pools_cl_rejected" => MetricHelpType {
+        help: "How many clients were rejected because of max_client_conn",
+        ty: "counter",
+    },
+    "pools_cl_wait_rejected" => MetricHelpType {
+        help: "How many transactions were rejected because of max_wait_queue",
+        ty: "counter",
+    },
     "pools_sv_idle" => MetricHelpType {
         help: "How many server connections are idle",
         ty: "gauge",
@@ -138,6 +148,10 @@ static METRIC_HELP_AND_TYPES_LOOKUP: phf::Map<&'static str, MetricHelpType> = ph
         help: "Number of errors",
         ty: "gauge",
     },
+    "servers_replica_lag" => MetricHelpType {
+        help: "Replication lag of the server in milliseconds",
+        ty: "gauge",
+    },
     "databases_pool_size" => MetricHelpType {
         help: "Maximum number of server connections",
         ty: "gauge",
@@ -243,9 +257,17 @@ impl<Value: fmt::Display> PrometheusMetric<Value> {
     }
 }
 
-async fn prometheus_stats(request: Request<Body>) -> Result<Response<Body>, hyper::http::Error> {
+async fn prometheus_stats(
+    request: Request<Body>,
+    client_server_map: ClientServerMap,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let config = get_config();
+
     match (request.method(), request.uri().path()) {
-        (&Method::GET, "/metrics") => {
+        (_, path) if config.general.enable_admin_api && path.starts_with("/api/") => {
+            admin_api(request, client_server_map).await
+        }
+        (&Method::GET, "/metrics") if config.general.enable_prometheus_exporter == Some(true) => {
             let mut lines = Vec::new();
             push_address_stats(&mut lines);
             push_pool_stats(&mut lines);
@@ -267,7 +289,7 @@ fn push_address_stats(lines: &mut Vec<String>) {
     for (_, pool) in get_all_pools() {
         for shard in 0..pool.shards() {
             for server in 0..pool.servers(shard) {
-                let address = pool.address(shard, server);
+                let address = &pool.address(shard, server);
                 let stats = &*address.stats;
                 for (key, value) in stats.clone() {
                     if let Some(prometheus_metric) =
@@ -305,7 +327,7 @@ fn push_database_stats(lines: &mut Vec<String>) {
         let pool_config = pool.settings.clone();
         for shard in 0..pool.shards() {
             for server in 0..pool.servers(shard) {
-                let address = pool.address(shard, server);
+                let address = &pool.address(shard, server);
                 let pool_state = pool.pool_state(shard, server);
 
                 let metrics = vec![
@@ -329,16 +351,16 @@ fn push_database_stats(lines: &mut Vec<String>) {
 // Adds relevant metrics shown in a SHOW SERVERS admin command.
 fn push_server_stats(lines: &mut Vec<String>) {
     let server_stats = get_server_stats();
-    let mut server_stats_by_addresses = HashMap::<String, Arc<ServerStats>>::new();
+    let mut server_stats_by_addresses = HashMap::<usize, Arc<ServerStats>>::new();
     for (_, stats) in server_stats {
-        server_stats_by_addresses.insert(stats.address_name(), stats);
+        server_stats_by_addresses.insert(stats.address_id(), stats);
     }
 
     for (_, pool) in get_all_pools() {
         for shard in 0..pool.shards() {
             for server in 0..pool.servers(shard) {
-                let address = pool.address(shard, server);
-                if let Some(server_info) = server_stats_by_addresses.get(&address.name()) {
+                let address = &pool.address(shard, server);
+                if let Some(server_info) = server_stats_by_addresses.get(&address.id) {
                     let metrics = [
                         (
                             "bytes_received",
@@ -357,6 +379,7 @@ fn push_server_stats(lines: &mut Vec<String>) {
                             "error_count",
                             server_info.error_count.load(Ordering::Relaxed),
                         ),
+                        ("replica_lag", address.stats.replica_lag()),
                     ];
                     for (key, value) in metrics {
                         if let Some(prometheus_metric) =
@@ -373,14 +396,48 @@ fn push_server_stats(lines: &mut Vec<String>) {
     }
 }
 
-pub async fn start_metric_server(http_addr: SocketAddr) {
-    let http_service_factory =
-        make_service_fn(|_conn| async { Ok::<_, hyper::Error>(service_fn(prometheus_stats)) });
-    let server = Server::bind(&http_addr).serve(http_service_factory);
-    info!(
-        "Exposing prometheus metrics on http://{}/metrics.",
-        http_addr
-    );
+/// Serve the Prometheus exporter and the admin API until a new pgcat takes over the listener.
+pub async fn start_metric_server(
+    listener: TcpListener,
+    client_server_map: ClientServerMap,
+    mut handed_off: watch::Receiver<bool>,
+) {
+    let http_service_factory = make_service_fn(move |_conn| {
+        let client_server_map = client_server_map.clone();
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |request| {
+                prometheus_stats(request, client_server_map.clone())
+            }))
+        }
+    });
+    let http_addr = match listener.local_addr() {
+        Ok(addr) => addr,
+        Err(err) => {
+            error!("Failed to run HTTP server: {}.", err);
+            return;
+        }
+    };
+    let server = match Server::from_tcp(listener) {
+        Ok(server) => server
+            .serve(http_service_factory)
+            .with_graceful_shutdown(async move {
+                let _ = handed_off.changed().await;
+            }),
+        Err(err) => {
+            error!("Failed to run HTTP server: {}.", err);
+            return;
+        }
+    };
+    let config = get_config();
+    if config.general.enable_prometheus_exporter == Some(true) {
+        info!(
+            "Exposing prometheus metrics on http://{}/metrics.",
+            http_addr
+        );
+    }
+    if config.general.enable_admin_api {
+        info!("Exposing the admin API on http://{}/api.", http_addr);
+    }
     if let Err(e) = server.await {
         error!("Failed to run HTTP server: {}.", e);
     }
@@ -12,11 +12,12 @@ use sqlparser::ast::{
 use sqlparser::dialect::PostgreSqlDialect;
 use sqlparser::parser::Parser;
 
-use crate::config::Role;
+use crate::config::{PoolMode, Role};
 use crate::errors::Error;
 use crate::messages::BytesMutReader;
 use crate::plugins::{Intercept, Plugin, PluginOutput, QueryLogger, TableAccess};
 use crate::pool::PoolSettings;
+use crate::scatter_gather::ScatterGather;
 use crate::sharding::Sharder;
 
 use std::cmp;
@@ -85,6 +86,17 @@ pub struct QueryRouter {
 
     // Placeholders from prepared statement.
     placeholders: Vec<i16>,
+
+    /// Plan for running the last inferred query on multiple shards.
+    scatter_gather: Option<ScatterGather>,
+
+    /// The client picked the shard itself with SET SHARD or SET SHARDING KEY,
+    /// so we don't send queries to multiple shards.
+    shard_pinned: bool,
+
+    /// A query since we last checked wrote, but the server will complete it
+    /// with a SELECT tag, e.g. CREATE TABLE AS or SELECT INTO.
+    select_writes: bool,
 }
 
 impl QueryRouter {
@@ -124,6 +136,9 @@ impl QueryRouter {
             primary_reads_enabled: None,
             pool_settings: PoolSettings::default(),
             placeholders: Vec::new(),
+            scatter_gather: None,
+            shard_pinned: false,
+            select_writes: false,
         }
     }
 
@@ -137,7 +152,10 @@ impl QueryRouter {
     }
 
     /// Try to parse a command and execute it.
-    pub fn try_execute_command(&mut self, message_buffer: &BytesMut) -> Option<(Command, String)> {
+    pub fn try_execute_command(
+        &mut self,
+        message_buffer: &BytesMut,
+    ) -> Result<Option<(Command, String)>, Error> {
         let mut message_cursor = Cursor::new(message_buffer);
 
         let code = message_cursor.get_u8() as char;
@@ -163,7 +181,7 @@ impl QueryRouter {
                             debug!("Setting shard to {:?}", shard_id);
                             self.set_shard(shard_id);
                             // Skip other command processing since a sharding command was found
-                            return None;
+                            return Ok(None);
                         }
                     }
 
@@ -177,9 +195,11 @@ impl QueryRouter {
                                 });
                         if let Some(sharding_key) = sharding_key {
                             debug!("Setting sharding_key to {:?}", sharding_key);
-                            self.set_sharding_key(sharding_key);
+                            if self.set_sharding_key(sharding_key).is_none() {
+                                return Err(Error::ShardingKeyNotInShard(sharding_key));
+                            }
                             // Skip other command processing since a sharding command was found
-                            return None;
+                            return Ok(None);
                         }
                     }
                 }
@@ -189,7 +209,7 @@ impl QueryRouter {
 
         // Only simple protocol supported for commands processed below
         if code != 'Q' {
-            return None;
+            return Ok(None);
         }
 
         let _len = message_cursor.get_i32() as usize;
@@ -197,12 +217,12 @@ impl QueryRouter {
 
         let regex_set = match CUSTOM_SQL_REGEX_SET.get() {
             Some(regex_set) => regex_set,
-            None => return None,
+            None => return Ok(None),
         };
 
         let regex_list = match CUSTOM_SQL_REGEX_LIST.get() {
             Some(regex_list) => regex_list,
-            None => return None,
+            None => return Ok(None),
         };
 
         let matches: Vec<_> = regex_set.matches(&query).into_iter().collect();
@@ -211,7 +231,7 @@ impl QueryRouter {
         // server it'll go to if the query parser is enabled.
         if matches.len() != 1 {
             debug!("Regular query, not a command");
-            return None;
+            return Ok(None);
         }
 
         let command = match matches[0] {
@@ -238,9 +258,9 @@ impl QueryRouter {
                 match regex_list[matches[0]].captures(&query) {
                     Some(captures) => match captures.get(1) {
                         Some(value) => value.as_str().to_string(),
-                        None => return None,
+                        None => return Ok(None),
                     },
-                    None => return None,
+                    None => return Ok(None),
                 }
             }
 
@@ -267,13 +287,20 @@ impl QueryRouter {
         match command {
             Command::SetShardingKey => {
                 // TODO: some error handling here
-                value = self
-                    .set_sharding_key(value.parse::<i64>().unwrap())
-                    .unwrap()
-                    .to_string();
+                let sharding_key = value.parse::<i64>().unwrap();
+
+                // The client is told when the key isn't in any shard, and stays on its shard.
+                value = match self.set_sharding_key(sharding_key) {
+                    Some(shard) => {
+                        self.shard_pinned = true;
+                        shard.to_string()
+                    }
+                    None => String::new(),
+                };
             }
 
             Command::SetShard => {
+                self.shard_pinned = true;
                 self.active_shard = match value.to_ascii_uppercase().as_ref() {
                     "ANY" => Some(rand::random::<usize>() % self.pool_settings.shards),
                     _ => Some(value.parse::<usize>().unwrap()),
@@ -328,7 +355,7 @@ impl QueryRouter {
             _ => (),
         }
 
-        Some((command, value))
+        Ok(Some((command, value)))
     }
 
     pub fn parse(message: &BytesMut) -> Result<Vec<sqlparser::ast::Statement>, Error> {
@@ -373,6 +400,9 @@ impl QueryRouter {
     pub fn infer(&mut self, ast: &Vec<sqlparser::ast::Statement>) -> Result<(), Error> {
         debug!("Inferring role");
 
+        self.scatter_gather = None;
+        self.select_writes |= ast.iter().any(Self::select_writes);
+
         if ast.is_empty() {
             // That's weird, no idea, let's go to primary
             self.active_role = Some(Role::Primary);
@@ -396,14 +426,14 @@ impl QueryRouter {
                             // or discard shard selection. If they point to the same shard though,
                             // we can let them through as-is.
                             // This is basically building a database now :)
-                            match self.infer_shard(query) {
-                                Some(shard) => {
-                                    self.active_shard = Some(shard);
-                                    debug!("Automatically using shard: {:?}", self.active_shard);
-                                }
+                            let shards = self.infer_shards(query)?;
 
-                                None => (),
-                            };
+                            if shards.len() == 1 {
+                                self.active_shard = shards.first().cloned();
+                                debug!("Automatically using shard: {:?}", self.active_shard);
+                            } else if ast.len() == 1 {
+                                self.scatter_gather = self.plan_scatter_gather(query, shards);
+                            }
                         }
 
                         None => (),
@@ -431,7 +461,7 @@ impl QueryRouter {
     ///
     /// N.B.: Only supports anonymous prepared statements since we don't
     /// keep a cache of them in PgCat.
-    pub fn infer_shard_from_bind(&mut self, message: &BytesMut) -> bool {
+    pub fn infer_shard_from_bind(&mut self, message: &BytesMut) -> Result<bool, Error> {
         debug!("Parsing bind message");
 
         let mut message_cursor = Cursor::new(message);
@@ -441,7 +471,7 @@ impl QueryRouter {
 
         if code != 'B' {
             debug!("Not a bind packet");
-            return false;
+            return Ok(false);
         }
 
         // Check message length
@@ -451,21 +481,23 @@ impl QueryRouter {
                 len,
                 message.len()
             );
-            return false;
+            return Ok(false);
         }
 
         // There are no shard keys in the prepared statement.
         if self.placeholders.is_empty() {
             debug!("There are no placeholders in the prepared statement that matched the automatic sharding key");
-            return false;
+            return Ok(false);
         }
 
         let sharder = Sharder::new(
             self.pool_settings.shards,
             self.pool_settings.sharding_function,
+            self.pool_settings.shard_keys.clone(),
         );
 
         let mut shards = BTreeSet::new();
+        let mut not_in_shard = None;
 
         let _portal = message_cursor.read_string();
         let _name = message_cursor.read_string();
@@ -545,22 +577,34 @@ impl QueryRouter {
                     _ => unreachable!(),
                 };
 
-                shards.insert(sharder.shard(value));
+                match sharder.shard(value) {
+                    Some(shard) => {
+                        shards.insert(shard);
+                    }
+                    None => {
+                        not_in_shard = Some(value);
+                        break;
+                    }
+                };
             }
         }
 
         self.placeholders.clear();
         self.placeholders.shrink_to_fit();
 
+        if let Some(value) = not_in_shard {
+            return Err(Error::ShardingKeyNotInShard(value));
+        }
+
         // We only support querying one shard at a time.
         // TODO: Support multi-shard queries some day.
         if shards.len() == 1 {
             debug!("Found one sharding key");
             self.set_shard(*shards.first().unwrap());
-            true
+            Ok(true)
         } else {
             debug!("Found no sharding keys");
-            false
+            Ok(false)
         }
     }
 
@@ -675,19 +719,14 @@ impl QueryRouter {
         result
     }
 
-    /// Try to figure out which shard the query should go to.
-    fn infer_shard(&mut self, query: &sqlparser::ast::Query) -> Option<usize> {
+    /// Try to figure out which shards the query should go to.
+    fn infer_shards(&mut self, query: &sqlparser::ast::Query) -> Result<BTreeSet<usize>, Error> {
         let mut shards = BTreeSet::new();
         let mut exprs = Vec::new();
 
         match &*query.body {
             SetExpr::Query(query) => {
-                match self.infer_shard(&*query) {
-                    Some(shard) => {
-                        shards.insert(shard);
-                    }
-                    None => (),
-                };
+                shards.extend(self.infer_shards(&*query)?);
             }
 
             // SELECT * FROM ...
@@ -744,6 +783,7 @@ impl QueryRouter {
                 let sharder = Sharder::new(
                     self.pool_settings.shards,
                     self.pool_settings.sharding_function,
+                    self.pool_settings.shard_keys.clone(),
                 );
 
                 // Look for sharding keys in either the join condition
@@ -756,10 +796,12 @@ impl QueryRouter {
 
                     for value in sharding_keys {
                         match value {
-                            ShardingKey::Value(value) => {
-                                let shard = sharder.shard(value);
-                                shards.insert(shard);
-                            }
+                            ShardingKey::Value(value) => match sharder.shard(value) {
+                                Some(shard) => {
+                                    shards.insert(shard);
+                                }
+                                None => return Err(Error::ShardingKeyNotInShard(value)),
+                            },
 
                             ShardingKey::Placeholder(position) => {
                                 self.placeholders.push(position);
@@ -773,21 +815,72 @@ impl QueryRouter {
 
         match shards.len() {
             // Didn't find a sharding key, you're on your own.
-            0 => {
-                debug!("No sharding keys found");
-                None
-            }
+            0 => debug!("No sharding keys found"),
+            1 => (),
+            _ => debug!("More than one sharding key found"),
+        };
+
+        Ok(shards)
+    }
 
-            1 => Some(shards.into_iter().last().unwrap()),
+    /// Plan running a read-only query on all the shards, or on the shards
+    /// matching the sharding keys found in the query, if enabled.
+    fn plan_scatter_gather(
+        &self,
+        query: &sqlparser::ast::Query,
+        shards: BTreeSet<usize>,
+    ) -> Option<ScatterGather> {
+        // In session mode, the client is already attached to a server on one shard.
+        if !self.pool_settings.scatter_gather_enabled
+            || self.pool_settings.pool_mode != PoolMode::Transaction
+            || self.pool_settings.shards < 2
+            || self.shard_pinned
+            || !self.placeholders.is_empty()
+        {
+            return None;
+        }
 
-            // TODO: support querying multiple shards (some day...)
-            _ => {
-                debug!("More than one sharding key found");
-                None
-            }
+        let sharded_table = self
+            .pool_settings
+            .automatic_sharding_key
+            .as_ref()?
+            .split('.')
+            .next()?
+            .to_string();
+
+        let shards = match shards.is_empty() {
+            true => (0..self.pool_settings.shards).collect(),
+            false => shards.into_iter().collect(),
+        };
+
+        let scatter_gather = ScatterGather::new(query, shards, &sharded_table);
+
+        debug!("Multi-shard query plan: {:?}", scatter_gather);
+
+        scatter_gather
+    }
+
+    /// Did a query inferred since the last call write, even though it completed with a SELECT tag.
+    pub fn take_select_writes(&mut self) -> bool {
+        std::mem::take(&mut self.select_writes)
+    }
+
+    fn select_writes(statement: &Statement) -> bool {
+        match statement {
+            Statement::CreateTable { query, .. } => query.is_some(),
+            Query(query) => match query.body.as_ref() {
+                SetExpr::Select(select) => select.into.is_some(),
+                _ => false,
+            },
+            _ => false,
         }
     }
 
+    /// Get the multi-shard plan for the last inferred query, if any.
+    pub fn take_scatter_gather(&mut self) -> Option<ScatterGather> {
+        self.scatter_gather.take()
+    }
+
     /// Add your plugins here and execute them.
     pub async fn execute_plugins(&self, ast: &Vec<Statement>) -> Result<PluginOutput, Error> {
         let plugins = match self.pool_settings.plugins {
@@ -834,12 +927,15 @@ impl QueryRouter {
         Ok(PluginOutput::Allow)
     }
 
+    /// Set the shard of the sharding key. Returns None, and keeps the current shard,
+    /// if the key isn't in any shard.
     fn set_sharding_key(&mut self, sharding_key: i64) -> Option<usize> {
         let sharder = Sharder::new(
             self.pool_settings.shards,
             self.pool_settings.sharding_function,
+            self.pool_settings.shard_keys.clone(),
         );
-        let shard = sharder.shard(sharding_key);
+        let shard = sharder.shard(sharding_key)?;
         self.set_shard(shard);
         self.active_shard
     }
@@ -856,6 +952,9 @@ impl QueryRouter {
 
     pub fn set_shard(&mut self, shard: usize) {
         self.active_shard = Some(shard);
+
+        // The query has a shard after all.
+        self.scatter_gather = None;
     }
 
     /// Should we attempt to parse queries?
@@ -896,6 +995,7 @@ mod test {
     use crate::messages::simple_query;
     use crate::sharding::ShardingFunction;
     use bytes::BufMut;
+    use std::sync::Arc;
 
     #[test]
     fn test_defaults() {
@@ -909,10 +1009,18 @@ mod test {
     fn test_infer_replica() {
         QueryRouter::setup();
         let mut qr = QueryRouter::new();
-        assert!(qr.try_execute_command(&simple_query("SET SERVER ROLE TO 'auto'")) != None);
+        assert!(
+            qr.try_execute_command(&simple_query("SET SERVER ROLE TO 'auto'"))
+                .unwrap()
+                != None
+        );
         assert!(qr.query_parser_enabled());
 
-        assert!(qr.try_execute_command(&simple_query("SET PRIMARY READS TO off")) != None);
+        assert!(
+            qr.try_execute_command(&simple_query("SET PRIMARY READS TO off"))
+                .unwrap()
+                != None
+        );
 
         let queries = vec![
             simple_query("SELECT * FROM items WHERE id = 5"),
@@ -948,12 +1056,41 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_take_select_writes() {
+        QueryRouter::setup();
+        let mut qr = QueryRouter::new();
+
+        for query in ["SELECT * FROM items", "INSERT INTO items (id) VALUES (5)"] {
+            assert!(qr
+                .infer(&QueryRouter::parse(&simple_query(query)).unwrap())
+                .is_ok());
+            assert!(!qr.take_select_writes());
+        }
+
+        for query in [
+            "CREATE TABLE copy AS SELECT * FROM items",
+            "SELECT * INTO copy FROM items",
+            "BEGIN; CREATE TABLE copy AS SELECT * FROM items",
+        ] {
+            assert!(qr
+                .infer(&QueryRouter::parse(&simple_query(query)).unwrap())
+                .is_ok());
+            assert!(qr.take_select_writes());
+            assert!(!qr.take_select_writes());
+        }
+    }
+
     #[test]
     fn test_infer_primary_reads_enabled() {
         QueryRouter::setup();
         let mut qr = QueryRouter::new();
         let query = simple_query("SELECT * FROM items WHERE id = 5");
-        assert!(qr.try_execute_command(&simple_query("SET PRIMARY READS TO on")) != None);
+        assert!(
+            qr.try_execute_command(&simple_query("SET PRIMARY READS TO on"))
+                .unwrap()
+                != None
+        );
 
         assert!(qr.infer(&QueryRouter::parse(&query).unwrap()).is_ok());
         assert_eq!(qr.role(), None);
@@ -963,8 +1100,13 @@ mod test {
     fn test_infer_parse_prepared() {
         QueryRouter::setup();
         let mut qr = QueryRouter::new();
-        qr.try_execute_command(&simple_query("SET SERVER ROLE TO 'auto'"));
-        assert!(qr.try_execute_command(&simple_query("SET PRIMARY READS TO off")) != None);
+        qr.try_execute_command(&simple_query("SET SERVER ROLE TO 'auto'"))
+            .unwrap();
+        assert!(
+            qr.try_execute_command(&simple_query("SET PRIMARY READS TO off"))
+                .unwrap()
+                != None
+        );
 
         let prepared_stmt = BytesMut::from(
             &b"WITH t AS (SELECT * FROM items WHERE name = $1) SELECT * FROM t WHERE id = $2\0"[..],
@@ -1058,7 +1200,7 @@ mod test {
         // SetShardingKey
         let query = simple_query("SET SHARDING KEY TO 13");
         assert_eq!(
-            qr.try_execute_command(&query),
+            qr.try_execute_command(&query).unwrap(),
             Some((Command::SetShardingKey, String::from("0")))
         );
         assert_eq!(qr.shard(), 0);
@@ -1066,7 +1208,7 @@ mod test {
         // SetShard
         let query = simple_query("SET SHARD TO '1'");
         assert_eq!(
-            qr.try_execute_command(&query),
+            qr.try_execute_command(&query).unwrap(),
             Some((Command::SetShard, String::from("1")))
         );
         assert_eq!(qr.shard(), 1);
@@ -1074,7 +1216,7 @@ mod test {
         // ShowShard
         let query = simple_query("SHOW SHARD");
         assert_eq!(
-            qr.try_execute_command(&query),
+            qr.try_execute_command(&query).unwrap(),
             Some((Command::ShowShard, String::from("1")))
         );
 
@@ -1092,7 +1234,7 @@ mod test {
         for (idx, role) in roles.iter().enumerate() {
             let query = simple_query(&format!("SET SERVER ROLE TO '{}'", role));
             assert_eq!(
-                qr.try_execute_command(&query),
+                qr.try_execute_command(&query).unwrap(),
                 Some((Command::SetServerRole, String::from(*role)))
             );
             assert_eq!(qr.role(), verify_roles[idx],);
@@ -1101,7 +1243,7 @@ mod test {
             // ShowServerRole
             let query = simple_query("SHOW SERVER ROLE");
             assert_eq!(
-                qr.try_execute_command(&query),
+                qr.try_execute_command(&query).unwrap(),
                 Some((Command::ShowServerRole, String::from(*role)))
             );
         }
@@ -1114,11 +1256,13 @@ mod test {
                 qr.try_execute_command(&simple_query(&format!(
                     "SET PRIMARY READS TO {}",
                     primary_reads
-                ))),
+                )))
+                .unwrap(),
                 Some((Command::SetPrimaryReads, String::from(*primary_reads)))
             );
             assert_eq!(
-                qr.try_execute_command(&simple_query("SHOW PRIMARY READS")),
+                qr.try_execute_command(&simple_query("SHOW PRIMARY READS"))
+                    .unwrap(),
                 Some((
                     Command::ShowPrimaryReads,
                     String::from(primary_reads_enabled[idx])
@@ -1132,9 +1276,13 @@ mod test {
         QueryRouter::setup();
         let mut qr = QueryRouter::new();
         let query = simple_query("SET SERVER ROLE TO 'auto'");
-        assert!(qr.try_execute_command(&simple_query("SET PRIMARY READS TO off")) != None);
+        assert!(
+            qr.try_execute_command(&simple_query("SET PRIMARY READS TO off"))
+                .unwrap()
+                != None
+        );
 
-        assert!(qr.try_execute_command(&query) != None);
+        assert!(qr.try_execute_command(&query).unwrap() != None);
         assert!(qr.query_parser_enabled());
         assert_eq!(qr.role(), None);
 
@@ -1148,7 +1296,7 @@ mod test {
 
         assert!(qr.query_parser_enabled());
         let query = simple_query("SET SERVER ROLE TO 'default'");
-        assert!(qr.try_execute_command(&query) != None);
+        assert!(qr.try_execute_command(&query).unwrap() != None);
         assert!(!qr.query_parser_enabled());
     }
 
@@ -1164,7 +1312,14 @@ mod test {
             default_role: Some(Role::Replica),
             query_parser_enabled: true,
             primary_reads_enabled: false,
+            scatter_gather_enabled: false,
+            topology_check_interval: 0,
+            max_replica_lag: 0,
+            replica_lag_check_interval: 1000,
+            read_your_writes_window: 0,
+            max_wait_queue: 0,
             sharding_function: ShardingFunction::PgBigintHash,
+            shard_keys: Arc::default(),
             automatic_sharding_key: Some(String::from("test.id")),
             healthcheck_delay: PoolSettings::default().healthcheck_delay,
             healthcheck_timeout: PoolSettings::default().healthcheck_timeout,
@@ -1193,11 +1348,11 @@ mod test {
         assert!(!qr.primary_reads_enabled());
 
         let q1 = simple_query("SET SERVER ROLE TO 'primary'");
-        assert!(qr.try_execute_command(&q1) != None);
+        assert!(qr.try_execute_command(&q1).unwrap() != None);
         assert_eq!(qr.active_role.unwrap(), Role::Primary);
 
         let q2 = simple_query("SET SERVER ROLE TO 'default'");
-        assert!(qr.try_execute_command(&q2) != None);
+        assert!(qr.try_execute_command(&q2).unwrap() != None);
         assert_eq!(qr.active_role.unwrap(), pool_settings.default_role);
     }
 
@@ -1239,7 +1394,14 @@ mod test {
             default_role: Some(Role::Replica),
             query_parser_enabled: true,
             primary_reads_enabled: false,
+            scatter_gather_enabled: false,
+            topology_check_interval: 0,
+            max_replica_lag: 0,
+            replica_lag_check_interval: 1000,
+            read_your_writes_window: 0,
+            max_wait_queue: 0,
             sharding_function: ShardingFunction::PgBigintHash,
+            shard_keys: Arc::default(),
             automatic_sharding_key: None,
             healthcheck_delay: PoolSettings::default().healthcheck_delay,
             healthcheck_timeout: PoolSettings::default().healthcheck_timeout,
@@ -1262,17 +1424,17 @@ mod test {
 
         // Make sure setting it works
         let q1 = simple_query("/* shard_id: 1 */ select 1 from foo;");
-        assert!(qr.try_execute_command(&q1) == None);
+        assert!(qr.try_execute_command(&q1).unwrap() == None);
         assert_eq!(qr.active_shard, Some(1));
 
         // And make sure changing it works
         let q2 = simple_query("/* shard_id: 0 */ select 1 from foo;");
-        assert!(qr.try_execute_command(&q2) == None);
+        assert!(qr.try_execute_command(&q2).unwrap() == None);
         assert_eq!(qr.active_shard, Some(0));
 
         // Validate setting by shard with expected shard copied from sharding.rs tests
         let q2 = simple_query("/* sharding_key: 6 */ select 1 from foo;");
-        assert!(qr.try_execute_command(&q2) == None);
+        assert!(qr.try_execute_command(&q2).unwrap() == None);
         assert_eq!(qr.active_shard, Some(2));
     }
 
@@ -1390,11 +1552,81 @@ mod test {
             .is_ok());
         assert_eq!(qr.placeholders.len(), 1);
 
-        assert!(qr.infer_shard_from_bind(&bind));
+        assert!(qr.infer_shard_from_bind(&bind).unwrap());
         assert_eq!(qr.shard(), 2);
         assert!(qr.placeholders.is_empty());
     }
 
+    #[test]
+    fn test_sharding_key_not_in_shard() {
+        QueryRouter::setup();
+
+        let mut shard_keys = crate::sharding::ShardKeys::default();
+        shard_keys.add_range(0, 0..1000);
+        shard_keys.add_range(1, 1000..2000);
+
+        let mut qr = QueryRouter::new();
+        qr.pool_settings.shards = 2;
+        qr.pool_settings.sharding_function = ShardingFunction::Range;
+        qr.pool_settings.shard_keys = Arc::new(shard_keys);
+        qr.pool_settings.automatic_sharding_key = Some("data.id".to_string());
+        qr.pool_settings.sharding_key_regex =
+            Some(Regex::new(r"/\* sharding_key: (\d+) \*/").unwrap());
+
+        // In the query.
+        assert!(qr
+            .infer(
+                &QueryRouter::parse(&simple_query("SELECT * FROM data WHERE id = 1500")).unwrap()
+            )
+            .is_ok());
+        assert_eq!(qr.shard(), 1);
+
+        assert_eq!(
+            qr.infer(
+                &QueryRouter::parse(&simple_query("SELECT * FROM data WHERE id = 2500")).unwrap()
+            ),
+            Err(Error::ShardingKeyNotInShard(2500))
+        );
+        assert_eq!(qr.shard(), 1);
+
+        // In the parameters of a prepared statement.
+        let stmt = "SELECT * FROM data WHERE id = $1";
+
+        let mut bind = BytesMut::from(&b"B"[..]);
+
+        let mut payload = BytesMut::from(&b"\0\0"[..]);
+        payload.put_i16(0);
+        payload.put_i16(1);
+        payload.put_i32(4);
+        payload.put(&b"2500"[..]);
+        payload.put_i16(0);
+
+        bind.put_i32(payload.len() as i32 + 4);
+        bind.put(payload);
+
+        assert!(qr
+            .infer(&QueryRouter::parse(&simple_query(stmt)).unwrap())
+            .is_ok());
+        assert_eq!(
+            qr.infer_shard_from_bind(&bind),
+            Err(Error::ShardingKeyNotInShard(2500))
+        );
+        assert!(qr.placeholders.is_empty());
+        assert_eq!(qr.shard(), 1);
+
+        // In a comment.
+        let query = simple_query("/* sharding_key: 500 */ SELECT 1");
+        assert_eq!(qr.try_execute_command(&query), Ok(None));
+        assert_eq!(qr.shard(), 0);
+
+        let query = simple_query("/* sharding_key: 2500 */ SELECT 1");
+        assert_eq!(
+            qr.try_execute_command(&query),
+            Err(Error::ShardingKeyNotInShard(2500))
+        );
+        assert_eq!(qr.shard(), 0);
+    }
+
     #[tokio::test]
     async fn test_table_access_plugin() {
         use crate::config::{Plugins, TableAccess};
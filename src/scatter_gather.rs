@@ -0,0 +1,977 @@
+/// Run read-only queries on several shards at once and merge
+/// the results into a single response for the client.
+use bytes::{Buf, BufMut, BytesMut};
+use log::debug;
+use sqlparser::ast::{
+    visit_expressions, Expr, Function, Ident, Query, Select, SelectItem, SetExpr, TableFactor,
+    Value,
+};
+
+use crate::errors::Error;
+use crate::messages::{command_complete, simple_query};
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::ops::ControlFlow;
+
+/// Aggregates we know how to combine from partial per-shard results.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Aggregate {
+    Count,
+    Sum,
+    Min,
+    Max,
+}
+
+/// Aggregate functions we can't combine, e.g. AVG. Queries
+/// using them are sent to one shard like before.
+const UNSUPPORTED_AGGREGATES: [&str; 16] = [
+    "avg",
+    "array_agg",
+    "string_agg",
+    "bool_and",
+    "bool_or",
+    "every",
+    "bit_and",
+    "bit_or",
+    "json_agg",
+    "jsonb_agg",
+    "json_object_agg",
+    "jsonb_object_agg",
+    "stddev",
+    "variance",
+    "percentile_cont",
+    "percentile_disc",
+];
+
+/// Result column used to sort merged rows.
+#[derive(Debug, Clone, PartialEq)]
+enum Column {
+    /// Position in the row.
+    Position(usize),
+
+    /// Name in the RowDescription, used with `SELECT *`.
+    Name(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct SortKey {
+    column: Column,
+    asc: bool,
+    nulls_first: bool,
+}
+
+/// A column value from a DataRow, None is NULL.
+type Field = Option<Vec<u8>>;
+
+/// What a shard sent back for the query.
+#[derive(Default)]
+struct ShardResponse {
+    row_description: Option<BytesMut>,
+    rows: Vec<Vec<Field>>,
+    error: Option<BytesMut>,
+    notices: Vec<BytesMut>,
+}
+
+/// Plan for a query executed on multiple shards.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScatterGather {
+    /// Shards the query is sent to.
+    shards: Vec<usize>,
+
+    /// Query to send to the shards if it had to be rewritten.
+    query: Option<String>,
+
+    /// ORDER BY applied to the merged rows.
+    sort: Vec<SortKey>,
+
+    /// LIMIT and OFFSET applied to the merged rows.
+    limit: Option<usize>,
+    offset: usize,
+
+    /// How each column is combined if the query is aggregating,
+    /// None for columns that are part of the group key.
+    aggregates: Option<Vec<Option<Aggregate>>>,
+
+    /// Remove duplicate rows, for DISTINCT and GROUP BY without aggregates.
+    distinct: bool,
+}
+
+impl ScatterGather {
+    /// Plan the query for the given shards. Returns None if we can't merge
+    /// the results correctly, in which case the query should go to one shard.
+    /// Only queries reading from `sharded_table` are considered ("*" matches any table).
+    pub fn new(query: &Query, shards: Vec<usize>, sharded_table: &str) -> Option<ScatterGather> {
+        if query.fetch.is_some() || !query.locks.is_empty() {
+            return None;
+        }
+
+        // Data-modifying CTEs, e.g. WITH d AS (DELETE ... RETURNING ...) SELECT ...,
+        // would write on every shard.
+        if !Self::read_only(query) {
+            return None;
+        }
+
+        let select = match &*query.body {
+            SetExpr::Select(select) => select,
+            _ => return None,
+        };
+
+        // SELECT INTO is a write and HAVING filters partial aggregates.
+        if select.into.is_some() || select.having.is_some() || select.top.is_some() {
+            return None;
+        }
+
+        if !Self::reads_table(select, sharded_table) {
+            debug!("Query doesn't read from {}, not scattering", sharded_table);
+            return None;
+        }
+
+        let wildcard = select.projection.iter().any(|item| {
+            matches!(
+                item,
+                SelectItem::Wildcard(_) | SelectItem::QualifiedWildcard(..)
+            )
+        });
+
+        let mut aggregates = Vec::new();
+
+        for item in select.projection.iter() {
+            let aggregate = match item {
+                SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => {
+                    match Self::aggregate(expr) {
+                        Ok(aggregate) => aggregate,
+                        Err(_) => return None,
+                    }
+                }
+                _ => None,
+            };
+
+            aggregates.push(aggregate);
+        }
+
+        let aggregating = aggregates.iter().any(|aggregate| aggregate.is_some());
+
+        if aggregating && wildcard {
+            return None;
+        }
+
+        // Rows from different shards are grouped again by the GROUP BY columns,
+        // so they must all be returned to the client.
+        for expr in select.group_by.iter() {
+            match Self::column(expr, &select.projection) {
+                Some(Column::Position(position)) if !wildcard && aggregates[position].is_none() => {
+                }
+                _ => return None,
+            }
+        }
+
+        let mut sort = Vec::new();
+
+        for order_by in query.order_by.iter() {
+            let asc = order_by.asc.unwrap_or(true);
+
+            sort.push(SortKey {
+                column: Self::column(&order_by.expr, &select.projection)?,
+                asc,
+                // Postgres puts NULLs last in ascending order.
+                nulls_first: order_by.nulls_first.unwrap_or(!asc),
+            });
+        }
+
+        let limit = match &query.limit {
+            Some(expr) => Some(Self::number(expr)?),
+            None => None,
+        };
+
+        let offset = match &query.offset {
+            Some(offset) => Self::number(&offset.value)?,
+            None => 0,
+        };
+
+        // Shards must return everything we need to apply LIMIT and OFFSET on the merged rows.
+        let query_for_shards = if aggregating && (limit.is_some() || offset > 0) {
+            let mut query = query.clone();
+            query.limit = None;
+            query.offset = None;
+            Some(query.to_string())
+        } else if offset > 0 {
+            let mut query = query.clone();
+            query.limit =
+                limit.map(|limit| Expr::Value(Value::Number((limit + offset).to_string(), false)));
+            query.offset = None;
+            Some(query.to_string())
+        } else {
+            None
+        };
+
+        Some(ScatterGather {
+            shards,
+            query: query_for_shards,
+            sort,
+            limit,
+            offset,
+            aggregates: if aggregating { Some(aggregates) } else { None },
+            distinct: select.distinct || (!aggregating && !select.group_by.is_empty()),
+        })
+    }
+
+    /// Check the query and its CTEs only read.
+    fn read_only(query: &Query) -> bool {
+        let ctes_read_only = match &query.with {
+            Some(with) => with
+                .cte_tables
+                .iter()
+                .all(|cte| Self::read_only(&cte.query)),
+            None => true,
+        };
+
+        ctes_read_only && Self::set_expr_read_only(&query.body)
+    }
+
+    fn set_expr_read_only(set_expr: &SetExpr) -> bool {
+        match set_expr {
+            SetExpr::Insert(_) | SetExpr::Update(_) => false,
+            SetExpr::Query(query) => Self::read_only(query),
+            SetExpr::SetOperation { left, right, .. } => {
+                Self::set_expr_read_only(left) && Self::set_expr_read_only(right)
+            }
+            _ => true,
+        }
+    }
+
+    /// Shards the query should be sent to.
+    pub fn shards(&self) -> &Vec<usize> {
+        &self.shards
+    }
+
+    /// The Query message to send to the shards.
+    pub fn message(&self, message: &BytesMut) -> BytesMut {
+        match &self.query {
+            Some(query) => simple_query(query),
+            None => message.clone(),
+        }
+    }
+
+    /// Merge the responses to the query from all shards into one response,
+    /// ending with ReadyForQuery.
+    pub fn merge(&self, responses: Vec<BytesMut>) -> Result<BytesMut, Error> {
+        let mut responses = responses
+            .into_iter()
+            .map(Self::parse_response)
+            .collect::<Result<Vec<ShardResponse>, Error>>()?;
+
+        let mut result = BytesMut::new();
+
+        for response in responses.iter_mut() {
+            for notice in response.notices.drain(..) {
+                result.put(notice);
+            }
+        }
+
+        // Any shard failing fails the whole query.
+        if let Some(error) = responses
+            .iter_mut()
+            .find_map(|response| response.error.take())
+        {
+            result.put(error);
+            result.put(Self::ready_for_query());
+            return Ok(result);
+        }
+
+        let row_description = match responses
+            .iter_mut()
+            .find_map(|response| response.row_description.take())
+        {
+            Some(row_description) => row_description,
+            None => {
+                return Err(Error::ProtocolSyncError(
+                    "Multi-shard query returned no rows description".into(),
+                ))
+            }
+        };
+
+        let columns = Self::parse_row_description(&row_description)?;
+
+        let mut rows = responses
+            .into_iter()
+            .flat_map(|response| response.rows)
+            .collect::<Vec<Vec<Field>>>();
+
+        if let Some(aggregates) = &self.aggregates {
+            rows = Self::combine(rows, aggregates, &columns);
+        } else if self.distinct {
+            let mut seen = std::collections::HashSet::new();
+            rows.retain(|row| seen.insert(row.clone()));
+        }
+
+        if !self.sort.is_empty() {
+            let mut sort = Vec::new();
+
+            for key in self.sort.iter() {
+                let position = match &key.column {
+                    Column::Position(position) => *position,
+                    Column::Name(name) => {
+                        match columns.iter().position(|(column, _)| column == name) {
+                            Some(position) => position,
+                            None => {
+                                return Err(Error::BadQuery(format!(
+                                "ORDER BY column \"{}\" must be selected in multi-shard queries",
+                                name
+                            )))
+                            }
+                        }
+                    }
+                };
+
+                if position >= columns.len() {
+                    return Err(Error::BadQuery(format!(
+                        "ORDER BY position {} is not in select list",
+                        position + 1
+                    )));
+                }
+
+                sort.push((position, key));
+            }
+
+            rows.sort_by(|a, b| {
+                for (position, key) in sort.iter() {
+                    let ordering = match (&a[*position], &b[*position]) {
+                        (None, None) => Ordering::Equal,
+                        (None, Some(_)) if key.nulls_first => Ordering::Less,
+                        (None, Some(_)) => Ordering::Greater,
+                        (Some(_), None) if key.nulls_first => Ordering::Greater,
+                        (Some(_), None) => Ordering::Less,
+                        (Some(a), Some(b)) => {
+                            let ordering = compare(a, b, columns[*position].1);
+                            if key.asc {
+                                ordering
+                            } else {
+                                ordering.reverse()
+                            }
+                        }
+                    };
+
+                    if ordering != Ordering::Equal {
+                        return ordering;
+                    }
+                }
+
+                Ordering::Equal
+            });
+        }
+
+        let rows = rows
+            .into_iter()
+            .skip(self.offset)
+            .take(self.limit.unwrap_or(usize::MAX))
+            .collect::<Vec<Vec<Field>>>();
+
+        result.put(row_description);
+
+        for row in rows.iter() {
+            result.put(Self::data_row(row));
+        }
+
+        result.put(command_complete(&format!("SELECT {}", rows.len())));
+        result.put(Self::ready_for_query());
+
+        Ok(result)
+    }
+
+    /// Check that the query reads from the sharded table.
+    fn reads_table(select: &Select, table: &str) -> bool {
+        let matches = |relation: &TableFactor| match relation {
+            TableFactor::Table { name, .. } => {
+                table == "*"
+                    || name
+                        .0
+                        .last()
+                        .map(|ident| ident.value == table)
+                        .unwrap_or(false)
+            }
+            _ => false,
+        };
+
+        select.from.iter().any(|from| {
+            matches(&from.relation) || from.joins.iter().any(|join| matches(&join.relation))
+        })
+    }
+
+    /// Which aggregate the expression is, if any. Fails if the
+    /// expression uses aggregates or window functions we can't combine.
+    fn aggregate(expr: &Expr) -> Result<Option<Aggregate>, ()> {
+        if let Expr::Function(function) = expr {
+            if function.over.is_none() && !function.distinct {
+                let aggregate = match Self::function_name(function).as_str() {
+                    "count" => Some(Aggregate::Count),
+                    "sum" => Some(Aggregate::Sum),
+                    "min" => Some(Aggregate::Min),
+                    "max" => Some(Aggregate::Max),
+                    _ => None,
+                };
+
+                // Postgres doesn't allow nesting aggregates, so the arguments are fine.
+                if aggregate.is_some() {
+                    return Ok(aggregate);
+                }
+            }
+        }
+
+        // Aggregates inside of other expressions, e.g. count(*) + 1, can't be combined.
+        let found = visit_expressions(expr, |expr| {
+            if let Expr::Function(function) = expr {
+                let name = Self::function_name(function);
+
+                if function.over.is_some()
+                    || ["count", "sum", "min", "max"].contains(&name.as_str())
+                    || UNSUPPORTED_AGGREGATES.contains(&name.as_str())
+                {
+                    return ControlFlow::Break(());
+                }
+            }
+
+            ControlFlow::Continue(())
+        });
+
+        match found {
+            ControlFlow::Break(_) => Err(()),
+            ControlFlow::Continue(_) => Ok(None),
+        }
+    }
+
+    fn function_name(function: &Function) -> String {
+        function
+            .name
+            .0
+            .last()
+            .map(|ident| ident.value.to_lowercase())
+            .unwrap_or_default()
+    }
+
+    /// Find the result column for an ORDER BY or GROUP BY expression.
+    fn column(expr: &Expr, projection: &[SelectItem]) -> Option<Column> {
+        let wildcard = projection.iter().any(|item| {
+            matches!(
+                item,
+                SelectItem::Wildcard(_) | SelectItem::QualifiedWildcard(..)
+            )
+        });
+
+        // ORDER BY 1
+        if let Expr::Value(Value::Number(position, _)) = expr {
+            return match position.parse::<usize>() {
+                // With a wildcard, we'll know if the position is valid when we get the result.
+                Ok(position) if position >= 1 && (wildcard || position <= projection.len()) => {
+                    Some(Column::Position(position - 1))
+                }
+                _ => None,
+            };
+        }
+
+        let name = match expr {
+            Expr::Identifier(ident) => Some(Self::ident_name(ident)),
+            Expr::CompoundIdentifier(idents) => idents.last().map(Self::ident_name),
+            _ => None,
+        };
+
+        if !wildcard {
+            for (position, item) in projection.iter().enumerate() {
+                let found = match item {
+                    SelectItem::UnnamedExpr(item_expr) => {
+                        item_expr == expr
+                            || (name.is_some() && Self::output_name(item_expr) == name)
+                    }
+                    SelectItem::ExprWithAlias {
+                        expr: item_expr,
+                        alias,
+                    } => item_expr == expr || Some(Self::ident_name(alias)) == name,
+                    _ => false,
+                };
+
+                if found {
+                    return Some(Column::Position(position));
+                }
+            }
+
+            None
+        } else {
+            name.map(Column::Name)
+        }
+    }
+
+    /// Column name Postgres will return for an expression.
+    fn output_name(expr: &Expr) -> Option<String> {
+        match expr {
+            Expr::Identifier(ident) => Some(Self::ident_name(ident)),
+            Expr::CompoundIdentifier(idents) => idents.last().map(Self::ident_name),
+            _ => None,
+        }
+    }
+
+    /// Unquoted identifiers are folded to lower case.
+    fn ident_name(ident: &Ident) -> String {
+        match ident.quote_style {
+            Some(_) => ident.value.clone(),
+            None => ident.value.to_lowercase(),
+        }
+    }
+
+    fn number(expr: &Expr) -> Option<usize> {
+        match expr {
+            Expr::Value(Value::Number(value, _)) => value.parse::<usize>().ok(),
+            _ => None,
+        }
+    }
+
+    /// Group rows by the non-aggregate columns and combine the aggregates.
+    fn combine(
+        rows: Vec<Vec<Field>>,
+        aggregates: &[Option<Aggregate>],
+        columns: &[(String, i32)],
+    ) -> Vec<Vec<Field>> {
+        let mut groups: Vec<Vec<Field>> = Vec::new();
+        let mut index: HashMap<Vec<Field>, usize> = HashMap::new();
+
+        for row in rows {
+            let key = row
+                .iter()
+                .zip(aggregates.iter())
+                .filter(|(_, aggregate)| aggregate.is_none())
+                .map(|(field, _)| field.clone())
+                .collect::<Vec<Field>>();
+
+            match index.get(&key) {
+                Some(position) => {
+                    let group = &mut groups[*position];
+
+                    for (i, aggregate) in aggregates.iter().enumerate() {
+                        if let Some(aggregate) = aggregate {
+                            let type_oid = columns.get(i).map(|column| column.1).unwrap_or(0);
+                            group[i] = combine_values(*aggregate, &group[i], &row[i], type_oid);
+                        }
+                    }
+                }
+
+                None => {
+                    index.insert(key, groups.len());
+                    groups.push(row);
+                }
+            }
+        }
+
+        groups
+    }
+
+    fn parse_response(response: BytesMut) -> Result<ShardResponse, Error> {
+        let mut result = ShardResponse::default();
+        let mut response = response;
+
+        while response.len() >= 5 {
+            let code = response[0] as char;
+            let len = (&response[1..5]).get_i32() as usize;
+
+            if response.len() < len + 1 {
+                return Err(Error::ProtocolSyncError(
+                    "Incomplete message from server in multi-shard query".into(),
+                ));
+            }
+
+            let message = response.split_to(len + 1);
+
+            match code {
+                'T' => result.row_description = Some(message),
+                'D' => result.rows.push(Self::parse_data_row(&message)?),
+                'E' if result.error.is_none() => result.error = Some(message),
+                'N' => result.notices.push(message),
+
+                // CommandComplete, ReadyForQuery, etc. are generated for the merged result.
+                _ => (),
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn parse_data_row(message: &BytesMut) -> Result<Vec<Field>, Error> {
+        let mut cursor = &message[5..];
+        let mut row = Vec::new();
+
+        if cursor.remaining() < 2 {
+            return Err(Error::ProtocolSyncError("DataRow".into()));
+        }
+
+        let count = cursor.get_i16();
+
+        for _ in 0..count {
+            if cursor.remaining() < 4 {
+                return Err(Error::ProtocolSyncError("DataRow".into()));
+            }
+
+            let len = cursor.get_i32();
+
+            if len < 0 {
+                row.push(None);
+            } else {
+                let len = len as usize;
+
+                if cursor.remaining() < len {
+                    return Err(Error::ProtocolSyncError("DataRow".into()));
+                }
+
+                row.push(Some(cursor[..len].to_vec()));
+                cursor.advance(len);
+            }
+        }
+
+        Ok(row)
+    }
+
+    /// Column names and type OIDs.
+    fn parse_row_description(message: &BytesMut) -> Result<Vec<(String, i32)>, Error> {
+        let mut cursor = &message[5..];
+        let mut columns = Vec::new();
+
+        if cursor.remaining() < 2 {
+            return Err(Error::ProtocolSyncError("RowDescription".into()));
+        }
+
+        let count = cursor.get_i16();
+
+        for _ in 0..count {
+            let end = match cursor.iter().position(|c| *c == 0) {
+                Some(end) => end,
+                None => return Err(Error::ProtocolSyncError("RowDescription".into())),
+            };
+
+            let name = String::from_utf8_lossy(&cursor[..end]).to_string();
+            cursor.advance(end + 1);
+
+            // table oid (4), column number (2), type oid (4), type size (2), type modifier (4), format (2)
+            if cursor.remaining() < 18 {
+                return Err(Error::ProtocolSyncError("RowDescription".into()));
+            }
+
+            cursor.advance(6);
+            let type_oid = cursor.get_i32();
+            cursor.advance(8);
+
+            columns.push((name, type_oid));
+        }
+
+        Ok(columns)
+    }
+
+    fn data_row(row: &[Field]) -> BytesMut {
+        let mut data_row = BytesMut::new();
+
+        data_row.put_i16(row.len() as i16);
+
+        for field in row {
+            match field {
+                Some(value) => {
+                    data_row.put_i32(value.len() as i32);
+                    data_row.put_slice(value);
+                }
+                None => data_row.put_i32(-1),
+            }
+        }
+
+        let mut res = BytesMut::with_capacity(data_row.len() + 5);
+        res.put_u8(b'D');
+        res.put_i32(data_row.len() as i32 + 4);
+        res.put(data_row);
+
+        res
+    }
+
+    fn ready_for_query() -> BytesMut {
+        let mut res = BytesMut::with_capacity(6);
+        res.put_u8(b'Z');
+        res.put_i32(5);
+        res.put_u8(b'I');
+        res
+    }
+}
+
+/// Type OIDs of numbers: int8, int2, int4, oid, float4, float8, numeric.
+const NUMERIC_TYPES: [i32; 7] = [20, 21, 23, 26, 700, 701, 1700];
+
+/// Type OIDs of floating point numbers: float4, float8.
+const FLOAT_TYPES: [i32; 2] = [700, 701];
+
+/// Compare two text values of the given type. Numbers are compared by value,
+/// everything else byte by byte, which matches the "C" collation.
+fn compare(a: &[u8], b: &[u8], type_oid: i32) -> Ordering {
+    if NUMERIC_TYPES.contains(&type_oid) {
+        if let (Some(a), Some(b)) = (parse_decimal(a), parse_decimal(b)) {
+            let scale = a.1.max(b.1);
+            if let (Some(a), Some(b)) = (rescale(a, scale), rescale(b, scale)) {
+                return a.cmp(&b);
+            }
+        }
+
+        if let (Some(a), Some(b)) = (parse_float(a), parse_float(b)) {
+            return a.partial_cmp(&b).unwrap_or(Ordering::Equal);
+        }
+    }
+
+    a.cmp(b)
+}
+
+/// Combine two partial aggregates.
+fn combine_values(aggregate: Aggregate, a: &Field, b: &Field, type_oid: i32) -> Field {
+    let (a, b) = match (a, b) {
+        (Some(a), Some(b)) => (a, b),
+        (Some(a), None) => return Some(a.clone()),
+        (None, b) => return b.clone(),
+    };
+
+    match aggregate {
+        Aggregate::Count | Aggregate::Sum => Some(add(a, b, type_oid).into_bytes()),
+        Aggregate::Min => match compare(a, b, type_oid) {
+            Ordering::Greater => Some(b.clone()),
+            _ => Some(a.clone()),
+        },
+        Aggregate::Max => match compare(a, b, type_oid) {
+            Ordering::Less => Some(b.clone()),
+            _ => Some(a.clone()),
+        },
+    }
+}
+
+/// Add two numbers in their text representation, without losing precision
+/// for integers and numeric.
+fn add(a: &[u8], b: &[u8], type_oid: i32) -> String {
+    if !FLOAT_TYPES.contains(&type_oid) {
+        if let (Some(a), Some(b)) = (parse_decimal(a), parse_decimal(b)) {
+            let scale = a.1.max(b.1);
+
+            if let (Some(x), Some(y)) = (rescale(a, scale), rescale(b, scale)) {
+                if let Some(sum) = x.checked_add(y) {
+                    return format_decimal(sum, scale);
+                }
+            }
+        }
+    }
+
+    match (parse_float(a), parse_float(b)) {
+        (Some(a), Some(b)) => (a + b).to_string(),
+        _ => String::from_utf8_lossy(a).to_string(),
+    }
+}
+
+/// Parse a decimal number into its digits and scale, e.g. "-1.50" is (-150, 2).
+fn parse_decimal(value: &[u8]) -> Option<(i128, u32)> {
+    let value = std::str::from_utf8(value).ok()?;
+    let (integer, fraction) = match value.split_once('.') {
+        Some((integer, fraction)) => (integer, fraction),
+        None => (value, ""),
+    };
+
+    let (negative, integer) = match integer.strip_prefix('-') {
+        Some(integer) => (true, integer),
+        None => (false, integer),
+    };
+
+    if integer.is_empty() && fraction.is_empty()
+        || !integer.chars().all(|c| c.is_ascii_digit())
+        || !fraction.chars().all(|c| c.is_ascii_digit())
+    {
+        return None;
+    }
+
+    let digits = format!("{}{}", integer, fraction).parse::<i128>().ok()?;
+
+    Some((
+        if negative { -digits } else { digits },
+        fraction.len() as u32,
+    ))
+}
+
+fn rescale(value: (i128, u32), scale: u32) -> Option<i128> {
+    value.0.checked_mul(10i128.checked_pow(scale - value.1)?)
+}
+
+fn format_decimal(value: i128, scale: u32) -> String {
+    if scale == 0 {
+        return value.to_string();
+    }
+
+    let digits = format!("{:0width$}", value.abs(), width = scale as usize + 1);
+    let (integer, fraction) = digits.split_at(digits.len() - scale as usize);
+
+    format!(
+        "{}{}.{}",
+        if value < 0 { "-" } else { "" },
+        integer,
+        fraction
+    )
+}
+
+fn parse_float(value: &[u8]) -> Option<f64> {
+    std::str::from_utf8(value).ok()?.parse::<f64>().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::messages::{data_row, row_description, DataType};
+    use sqlparser::dialect::PostgreSqlDialect;
+    use sqlparser::parser::Parser;
+
+    fn plan(query: &str) -> Option<ScatterGather> {
+        let ast = Parser::parse_sql(&PostgreSqlDialect {}, query).unwrap();
+
+        match &ast[0] {
+            sqlparser::ast::Statement::Query(query) => {
+                ScatterGather::new(query, vec![0, 1], "data")
+            }
+            _ => None,
+        }
+    }
+
+    fn response(columns: &Vec<(&str, DataType)>, rows: Vec<Vec<&str>>) -> BytesMut {
+        let mut response = row_description(columns);
+
+        for row in rows.iter() {
+            response.put(data_row(&row.iter().map(|v| v.to_string()).collect()));
+        }
+
+        response.put(command_complete(&format!("SELECT {}", rows.len())));
+        response.put(ScatterGather::ready_for_query());
+        response
+    }
+
+    fn rows(merged: BytesMut) -> Vec<Vec<String>> {
+        ScatterGather::parse_response(merged)
+            .unwrap()
+            .rows
+            .into_iter()
+            .map(|row| {
+                row.into_iter()
+                    .map(|v| String::from_utf8(v.unwrap()).unwrap())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_plan() {
+        assert!(plan("SELECT * FROM data").is_some());
+        assert!(plan("SELECT count(*), sum(amount) FROM data").is_some());
+        assert!(plan("SELECT * FROM other").is_none());
+        assert!(plan("SELECT avg(amount) FROM data").is_none());
+        assert!(plan("SELECT 1 FROM data UNION SELECT 2 FROM data").is_none());
+        assert!(
+            plan("SELECT value, count(*) FROM data GROUP BY value HAVING count(*) > 1").is_none()
+        );
+
+        // Writes in CTEs would run on every shard.
+        assert!(plan("WITH d AS (SELECT * FROM data) SELECT * FROM data").is_some());
+        assert!(
+            plan("WITH d AS (INSERT INTO other VALUES (1) RETURNING *) SELECT * FROM data")
+                .is_none()
+        );
+        assert!(
+            plan("WITH d AS (UPDATE other SET id = 1 RETURNING *) SELECT * FROM data").is_none()
+        );
+
+        // DELETE in a CTE isn't parsed, so the query is never scattered.
+        assert!(Parser::parse_sql(
+            &PostgreSqlDialect {},
+            "WITH d AS (DELETE FROM other RETURNING *) SELECT * FROM data"
+        )
+        .is_err());
+
+        // LIMIT and OFFSET can't be pushed down as-is.
+        let scatter_gather = plan("SELECT id FROM data ORDER BY id LIMIT 2 OFFSET 3").unwrap();
+        assert_eq!(
+            scatter_gather.query,
+            Some("SELECT id FROM data ORDER BY id LIMIT 5".into())
+        );
+    }
+
+    #[test]
+    fn test_merge_sorted() {
+        let columns = vec![("id", DataType::Int4), ("value", DataType::Text)];
+        let scatter_gather = plan("SELECT id, value FROM data ORDER BY id DESC LIMIT 3").unwrap();
+
+        let merged = scatter_gather
+            .merge(vec![
+                response(&columns, vec![vec!["10", "a"], vec!["2", "b"]]),
+                response(&columns, vec![vec!["9", "c"], vec!["3", "d"]]),
+            ])
+            .unwrap();
+
+        assert_eq!(
+            rows(merged),
+            vec![
+                vec!["10".to_string(), "a".into()],
+                vec!["9".into(), "c".into()],
+                vec!["3".into(), "d".into()]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_aggregates() {
+        let columns = vec![
+            ("value", DataType::Text),
+            ("count", DataType::Int4),
+            ("sum", DataType::Numeric),
+            ("max", DataType::Int4),
+        ];
+        let scatter_gather = plan(
+            "SELECT value, count(*), sum(amount), max(id) FROM data GROUP BY value ORDER BY value",
+        )
+        .unwrap();
+
+        let merged = scatter_gather
+            .merge(vec![
+                response(
+                    &columns,
+                    vec![vec!["a", "2", "1.25", "9"], vec!["b", "1", "3", "2"]],
+                ),
+                response(&columns, vec![vec!["a", "3", "0.75", "10"]]),
+            ])
+            .unwrap();
+
+        assert_eq!(
+            rows(merged),
+            vec![
+                vec!["a".to_string(), "5".into(), "2.00".into(), "10".into()],
+                vec!["b".into(), "1".into(), "3".into(), "2".into()]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_error() {
+        let columns = vec![("id", DataType::Int4)];
+        let scatter_gather = plan("SELECT id FROM data").unwrap();
+
+        let mut error = BytesMut::new();
+        error.put_u8(b'E');
+        error.put_i32(4 + 1);
+        error.put_u8(0);
+        error.put(ScatterGather::ready_for_query());
+
+        let merged = scatter_gather
+            .merge(vec![response(&columns, vec![vec!["1"]]), error])
+            .unwrap();
+
+        assert_eq!(merged[0], b'E');
+        assert!(rows(merged).is_empty());
+    }
+
+    #[test]
+    fn test_add() {
+        assert_eq!(add(b"1.5", b"-2.25", 1700), "-0.75");
+        assert_eq!(
+            add(b"9223372036854775807", b"1", 1700),
+            "9223372036854775808"
+        );
+        assert_eq!(add(b"0.5", b"0.25", 701), "0.75");
+    }
+}
@@ -5,15 +5,30 @@
 use base64::{engine::general_purpose, Engine as _};
 use bytes::BytesMut;
 use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
 use rand::{self, Rng};
 use sha2::digest::FixedOutput;
 use sha2::{Digest, Sha256};
 
+use std::collections::HashMap;
 use std::fmt::Write;
 
 use crate::constants::*;
 use crate::errors::Error;
 
+/// Verifiers built from plaintext passwords, so logins don't pay for the
+/// key derivation every time. Keyed by username, with the SHA-256 of the
+/// password they were built from. Cleared when the config is reloaded.
+static VERIFIERS: Lazy<Mutex<VerifierCache>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+type VerifierCache = HashMap<String, (Vec<u8>, ScramVerifier)>;
+
+/// Forget the cached verifiers, the passwords may have changed.
+pub fn clear_verifiers() {
+    VERIFIERS.lock().clear();
+}
+
 /// Normalize a password string. Postgres
 /// passwords don't have to be UTF-8.
 fn normalize(pass: &[u8]) -> Vec<u8> {
@@ -214,6 +229,249 @@ impl ScramSha256 {
     }
 }
 
+/// SCRAM secret as stored by Postgres in pg_authid.rolpassword, i.e.
+/// `SCRAM-SHA-256$<iterations>:<salt>$<StoredKey>:<ServerKey>`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScramVerifier {
+    iterations: u32,
+    salt: Vec<u8>,
+    stored_key: Vec<u8>,
+    server_key: Vec<u8>,
+}
+
+impl ScramVerifier {
+    /// Check if the secret looks like a SCRAM verifier.
+    pub fn is_verifier(secret: &str) -> bool {
+        secret.starts_with(&format!("{}$", SCRAM_SHA_256))
+    }
+
+    /// Parse a verifier in the Postgres format.
+    pub fn parse(secret: &str) -> Result<ScramVerifier, Error> {
+        let bad = || Error::AuthError("SCRAM verifier is malformed".into());
+
+        let secret = secret
+            .strip_prefix(&format!("{}$", SCRAM_SHA_256))
+            .ok_or_else(bad)?;
+        let (params, keys) = secret.split_once('$').ok_or_else(bad)?;
+        let (iterations, salt) = params.split_once(':').ok_or_else(bad)?;
+        let (stored_key, server_key) = keys.split_once(':').ok_or_else(bad)?;
+
+        let decode = |value: &str| general_purpose::STANDARD.decode(value).map_err(|_| bad());
+
+        Ok(ScramVerifier {
+            iterations: iterations.parse::<u32>().map_err(|_| bad())?,
+            salt: decode(salt)?,
+            stored_key: decode(stored_key)?,
+            server_key: decode(server_key)?,
+        })
+    }
+
+    /// Build a verifier from a plaintext password with a random salt,
+    /// the same way Postgres does it.
+    pub fn from_password(password: &str) -> ScramVerifier {
+        let salt: [u8; 16] = rand::random();
+        Self::from_password_and_salt(password, &salt, 4096)
+    }
+
+    /// Same as `from_password`, but the verifier is built once per user, off the
+    /// async executor, and reused for the following logins, like Postgres stores it.
+    pub async fn cached(username: &str, password: &str) -> Result<ScramVerifier, Error> {
+        let digest = Sha256::digest(password.as_bytes()).to_vec();
+
+        if let Some((cached_digest, verifier)) = VERIFIERS.lock().get(username) {
+            if *cached_digest == digest {
+                return Ok(verifier.clone());
+            }
+        }
+
+        let password = password.to_string();
+        let verifier =
+            match tokio::task::spawn_blocking(move || Self::from_password(&password)).await {
+                Ok(verifier) => verifier,
+                Err(err) => return Err(Error::AuthError(format!("SCRAM verifier: {:?}", err))),
+            };
+
+        // Another login may have built one in the meantime, keep the first one.
+        let mut verifiers = VERIFIERS.lock();
+        let (cached_digest, cached_verifier) = verifiers
+            .entry(username.to_string())
+            .or_insert((digest.clone(), verifier.clone()));
+
+        if *cached_digest == digest {
+            Ok(cached_verifier.clone())
+        } else {
+            *cached_digest = digest;
+            *cached_verifier = verifier.clone();
+            Ok(verifier)
+        }
+    }
+
+    /// Build a verifier from a plaintext password with a known salt.
+    pub fn from_password_and_salt(password: &str, salt: &[u8], iterations: u32) -> ScramVerifier {
+        let salted_password = ScramSha256::hi(&normalize(password.as_bytes()), salt, iterations);
+
+        let client_key = hmac_sha256(&salted_password, b"Client Key");
+        let stored_key = Sha256::digest(client_key).to_vec();
+        let server_key = hmac_sha256(&salted_password, b"Server Key");
+
+        ScramVerifier {
+            iterations,
+            salt: salt.to_vec(),
+            stored_key,
+            server_key,
+        }
+    }
+}
+
+/// Server side of the SCRAM exchange, used to authenticate clients
+/// connecting to us. It takes 2 client messages to complete.
+pub struct ScramSha256Server {
+    verifier: ScramVerifier,
+    nonce: String,
+    gs2_header: String,
+    client_first_bare: String,
+    server_first: String,
+}
+
+impl ScramSha256Server {
+    /// Create the server state from a verifier. It'll automatically
+    /// generate the server part of the nonce.
+    pub fn new(verifier: ScramVerifier) -> ScramSha256Server {
+        let nonce: [u8; 18] = rand::random();
+        Self::from_nonce(verifier, &general_purpose::STANDARD.encode(nonce))
+    }
+
+    /// Used for testing.
+    pub fn from_nonce(verifier: ScramVerifier, nonce: &str) -> ScramSha256Server {
+        ScramSha256Server {
+            verifier,
+            nonce: nonce.to_string(),
+            gs2_header: String::new(),
+            client_first_bare: String::new(),
+            server_first: String::new(),
+        }
+    }
+
+    /// Handle the client-first-message (SASLInitialResponse data) and
+    /// produce the server-first-message.
+    pub fn server_first(&mut self, message: &[u8]) -> Result<BytesMut, Error> {
+        let message = String::from_utf8_lossy(message);
+
+        // We don't support channel binding, so only "n" and "y" are acceptable.
+        let (cbind_flag, rest) = message
+            .split_once(',')
+            .ok_or_else(|| Error::ProtocolSyncError("SCRAM".into()))?;
+
+        if cbind_flag != "n" && cbind_flag != "y" {
+            return Err(Error::ProtocolSyncError(
+                "SCRAM channel binding is not supported".into(),
+            ));
+        }
+
+        let (authzid, client_first_bare) = rest
+            .split_once(',')
+            .ok_or_else(|| Error::ProtocolSyncError("SCRAM".into()))?;
+
+        let client_nonce = client_first_bare
+            .split(',')
+            .find_map(|part| part.strip_prefix("r="))
+            .ok_or_else(|| Error::ProtocolSyncError("SCRAM".into()))?;
+
+        self.gs2_header = format!("{},{},", cbind_flag, authzid);
+        self.client_first_bare = client_first_bare.to_string();
+        self.nonce = format!("{}{}", client_nonce, self.nonce);
+        self.server_first = format!(
+            "r={},s={},i={}",
+            self.nonce,
+            general_purpose::STANDARD.encode(&self.verifier.salt),
+            self.verifier.iterations
+        );
+
+        Ok(BytesMut::from(self.server_first.as_bytes()))
+    }
+
+    /// Verify the client-final-message (SASLResponse data) and
+    /// produce the server-final-message.
+    pub fn server_final(&mut self, message: &[u8]) -> Result<BytesMut, Error> {
+        let message = String::from_utf8_lossy(message);
+
+        let (without_proof, proof) = message
+            .rsplit_once(",p=")
+            .ok_or_else(|| Error::ProtocolSyncError("SCRAM".into()))?;
+
+        let mut channel_binding = None;
+        let mut nonce = None;
+
+        for part in without_proof.split(',') {
+            if let Some(value) = part.strip_prefix("c=") {
+                channel_binding = Some(value);
+            } else if let Some(value) = part.strip_prefix("r=") {
+                nonce = Some(value);
+            }
+        }
+
+        if channel_binding != Some(&general_purpose::STANDARD.encode(self.gs2_header.as_bytes()))
+            || nonce != Some(&self.nonce)
+        {
+            return Err(Error::ProtocolSyncError("SCRAM".into()));
+        }
+
+        let proof = match general_purpose::STANDARD.decode(proof) {
+            Ok(proof) => proof,
+            Err(_) => return Err(Error::ProtocolSyncError("SCRAM".into())),
+        };
+
+        let auth_message = format!(
+            "{},{},{}",
+            self.client_first_bare, self.server_first, without_proof
+        );
+
+        let client_signature = hmac_sha256(&self.verifier.stored_key, auth_message.as_bytes());
+
+        if proof.len() != client_signature.len() {
+            return Err(Error::AuthError("SCRAM proof mismatch".into()));
+        }
+
+        // Recover the client key from the proof and check it against what we have stored.
+        let client_key = proof
+            .iter()
+            .zip(client_signature)
+            .map(|(proof, signature)| proof ^ signature)
+            .collect::<Vec<u8>>();
+
+        if !constant_time_eq(
+            Sha256::digest(client_key).as_slice(),
+            self.verifier.stored_key.as_slice(),
+        ) {
+            return Err(Error::AuthError("SCRAM proof mismatch".into()));
+        }
+
+        let server_signature = hmac_sha256(&self.verifier.server_key, auth_message.as_bytes());
+
+        Ok(BytesMut::from(
+            format!("v={}", general_purpose::STANDARD.encode(server_signature)).as_bytes(),
+        ))
+    }
+}
+
+/// HMAC-SHA-256 of the message with the given key.
+fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut hmac =
+        Hmac::<Sha256>::new_from_slice(key).expect("HMAC is able to accept all key sizes");
+    hmac.update(message);
+    hmac.finalize().into_bytes().to_vec()
+}
+
+/// Compare secrets in constant time, so the comparison doesn't tell
+/// how many leading bytes match.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
 /// Parse the server challenge.
 struct Message {
     nonce: String,
@@ -322,4 +580,80 @@ mod test {
             .finish(&BytesMut::from(server_final.as_bytes()))
             .unwrap();
     }
+
+    // same recorded exchange, from the server side
+    #[test]
+    fn server_exchange() {
+        let client_first = "n,,n=,r=9IZ2O01zb9IgiIZ1WJ/zgpJB";
+        let server_first =
+            "r=9IZ2O01zb9IgiIZ1WJ/zgpJBjx/oIRLs02gGSHcw1KEty3eY,s=fs3IXBy7U7+IvVjZ,i\
+             =4096";
+        let client_final =
+            "c=biws,r=9IZ2O01zb9IgiIZ1WJ/zgpJBjx/oIRLs02gGSHcw1KEty3eY,p=AmNKosjJzS3\
+             1NTlQYNs5BTeQjdHdk7lOflDo5re2an8=";
+        let server_final = "v=U+ppxD5XUKtradnv8e2MkeupiA8FU87Sg8CXzXHDAzw=";
+
+        let salt = general_purpose::STANDARD
+            .decode("fs3IXBy7U7+IvVjZ")
+            .unwrap();
+        let verifier = ScramVerifier::from_password_and_salt("foobar", &salt, 4096);
+
+        // Going through the Postgres format must not lose anything.
+        let secret = format!(
+            "SCRAM-SHA-256$4096:fs3IXBy7U7+IvVjZ${}:{}",
+            general_purpose::STANDARD.encode(&verifier.stored_key),
+            general_purpose::STANDARD.encode(&verifier.server_key)
+        );
+        assert!(ScramVerifier::is_verifier(&secret));
+        assert_eq!(ScramVerifier::parse(&secret).unwrap(), verifier);
+
+        let mut scram = ScramSha256Server::from_nonce(verifier.clone(), "jx/oIRLs02gGSHcw1KEty3eY");
+
+        let result = scram.server_first(client_first.as_bytes()).unwrap();
+        assert_eq!(std::str::from_utf8(&result).unwrap(), server_first);
+
+        let result = scram.server_final(client_final.as_bytes()).unwrap();
+        assert_eq!(std::str::from_utf8(&result).unwrap(), server_final);
+
+        // Wrong password.
+        let verifier = ScramVerifier::from_password_and_salt("barfoo", &salt, 4096);
+        let mut scram = ScramSha256Server::from_nonce(verifier, "jx/oIRLs02gGSHcw1KEty3eY");
+        scram.server_first(client_first.as_bytes()).unwrap();
+        assert!(scram.server_final(client_final.as_bytes()).is_err());
+    }
+
+    #[tokio::test]
+    async fn cached_verifier() {
+        let verifier = ScramVerifier::cached("cached_user", "foobar")
+            .await
+            .unwrap();
+
+        // Same salt, so the client can reuse its salted password.
+        assert_eq!(
+            ScramVerifier::cached("cached_user", "foobar")
+                .await
+                .unwrap(),
+            verifier
+        );
+
+        assert_eq!(
+            ScramVerifier::from_password_and_salt("foobar", &verifier.salt, 4096),
+            verifier
+        );
+
+        // A new password replaces the verifier of the user.
+        let changed = ScramVerifier::cached("cached_user", "barfoo")
+            .await
+            .unwrap();
+        assert_ne!(changed, verifier);
+        assert_eq!(VERIFIERS.lock()["cached_user"].1, changed);
+    }
+
+    #[test]
+    fn constant_time() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secrets"));
+        assert!(constant_time_eq(b"", b""));
+    }
 }
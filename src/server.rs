@@ -5,25 +5,29 @@ use fallible_iterator::FallibleIterator;
 use log::{debug, error, info, trace, warn};
 use parking_lot::{Mutex, RwLock};
 use postgres_protocol::message;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::Read;
 use std::net::IpAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::sync::Arc;
 use std::time::SystemTime;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, BufStream};
 use tokio::net::TcpStream;
-use tokio_rustls::rustls::{OwnedTrustAnchor, RootCertStore};
-use tokio_rustls::{client::TlsStream, TlsConnector};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio_rustls::client::TlsStream;
 
-use crate::config::{get_config, Address, User};
+use crate::config::{get_config, Address, ServerTlsMode, User};
 use crate::constants::*;
 use crate::dns_cache::{AddrSet, CACHED_RESOLVER};
 use crate::errors::{Error, ServerIdentifier};
 use crate::messages::*;
 use crate::mirrors::MirroringManager;
 use crate::pool::ClientServerMap;
-use crate::scram::ScramSha256;
+use crate::scram::{ScramSha256, ScramVerifier};
+use crate::session_parameters::SessionParameters;
 use crate::stats::ServerStats;
+use crate::tls::server_tls_connector;
 use std::io::Write;
 
 use pin_project::pin_project;
@@ -38,6 +42,11 @@ pub enum StreamInner {
         #[pin]
         stream: TlsStream<TcpStream>,
     },
+    #[cfg(unix)]
+    Unix {
+        #[pin]
+        stream: UnixStream,
+    },
 }
 
 impl AsyncWrite for StreamInner {
@@ -50,6 +59,8 @@ impl AsyncWrite for StreamInner {
         match this {
             SteamInnerProj::Tls { stream } => stream.poll_write(cx, buf),
             SteamInnerProj::Plain { stream } => stream.poll_write(cx, buf),
+            #[cfg(unix)]
+            SteamInnerProj::Unix { stream } => stream.poll_write(cx, buf),
         }
     }
 
@@ -61,6 +72,8 @@ impl AsyncWrite for StreamInner {
         match this {
             SteamInnerProj::Tls { stream } => stream.poll_flush(cx),
             SteamInnerProj::Plain { stream } => stream.poll_flush(cx),
+            #[cfg(unix)]
+            SteamInnerProj::Unix { stream } => stream.poll_flush(cx),
         }
     }
 
@@ -72,6 +85,8 @@ impl AsyncWrite for StreamInner {
         match this {
             SteamInnerProj::Tls { stream } => stream.poll_shutdown(cx),
             SteamInnerProj::Plain { stream } => stream.poll_shutdown(cx),
+            #[cfg(unix)]
+            SteamInnerProj::Unix { stream } => stream.poll_shutdown(cx),
         }
     }
 }
@@ -86,6 +101,8 @@ impl AsyncRead for StreamInner {
         match this {
             SteamInnerProj::Tls { stream } => stream.poll_read(cx, buf),
             SteamInnerProj::Plain { stream } => stream.poll_read(cx, buf),
+            #[cfg(unix)]
+            SteamInnerProj::Unix { stream } => stream.poll_read(cx, buf),
         }
     }
 }
@@ -99,6 +116,167 @@ impl StreamInner {
                 w.write(buf)
             }
             StreamInner::Plain { stream } => stream.try_write(buf),
+            #[cfg(unix)]
+            StreamInner::Unix { stream } => stream.try_write(buf),
+        }
+    }
+}
+
+/// Server Unix socket path if the host is a directory, like in libpq.
+fn unix_socket_path(host: &str, port: u16) -> Option<String> {
+    if host.starts_with('/') {
+        Some(format!("{}/.s.PGSQL.{}", host.trim_end_matches('/'), port))
+    } else {
+        None
+    }
+}
+
+/// Did the command with this CommandComplete tag change data. SELECT INTO and
+/// CREATE TABLE AS also complete with a SELECT tag, the query router finds those.
+fn writes(command_tag: &str) -> bool {
+    let command = command_tag
+        .trim_end_matches('\0')
+        .split(' ')
+        .next()
+        .unwrap_or_default();
+
+    !matches!(
+        command,
+        "SELECT"
+            | "SHOW"
+            | "BEGIN"
+            | "START"
+            | "COMMIT"
+            | "ROLLBACK"
+            | "SAVEPOINT"
+            | "RELEASE"
+            | "SET"
+            | "RESET"
+            | "FETCH"
+            | "MOVE"
+            | "DECLARE"
+            | "CLOSE"
+            | "EXPLAIN"
+            | "PREPARE"
+            | "DEALLOCATE"
+            | "DISCARD"
+            | "LISTEN"
+            | "UNLISTEN"
+            | "NOTIFY"
+    )
+}
+
+/// Connect to the server over its Unix socket. TLS is not used over Unix sockets.
+#[cfg(unix)]
+async fn connect_unix(path: &str) -> Result<StreamInner, Error> {
+    match UnixStream::connect(path).await {
+        Ok(stream) => Ok(StreamInner::Unix { stream }),
+        Err(err) => {
+            error!("Could not connect to server socket {}: {}", path, err);
+            Err(Error::SocketError(format!(
+                "Could not connect to server socket {}: {}",
+                path, err
+            )))
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(path: &str) -> Result<StreamInner, Error> {
+    error!(
+        "Could not connect to server socket {}: Unix sockets are not supported",
+        path
+    );
+    Err(Error::SocketError("Unix sockets are not supported".into()))
+}
+
+/// Connect to the server over TCP, and negotiate TLS if configured.
+async fn connect_tcp(address: &Address) -> Result<StreamInner, Error> {
+    let mut stream = match TcpStream::connect(&format!("{}:{}", &address.host, address.port)).await
+    {
+        Ok(stream) => stream,
+        Err(err) => {
+            error!("Could not connect to server: {}", err);
+            return Err(Error::SocketError(format!(
+                "Could not connect to server: {}",
+                err
+            )));
+        }
+    };
+
+    // TCP timeouts.
+    configure_socket(&stream);
+
+    let config = get_config();
+
+    let server_tls = config.server_tls(&address.pool_name);
+
+    if server_tls.mode != ServerTlsMode::Disable {
+        // Request a TLS connection
+        ssl_request(&mut stream).await?;
+
+        let response = match stream.read_u8().await {
+            Ok(response) => response as char,
+            Err(err) => {
+                return Err(Error::SocketError(format!(
+                    "Server socket error: {:?}",
+                    err
+                )))
+            }
+        };
+
+        match response {
+            // Server supports TLS
+            'S' => {
+                debug!("Connecting to server using TLS ({})", server_tls.mode);
+
+                let connector = server_tls_connector(&server_tls)?;
+                let stream = match connector
+                    .connect(address.host.as_str().try_into().unwrap(), stream)
+                    .await
+                {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        error!("Server TLS error with {}: {:?}", address, err);
+                        return Err(Error::SocketError(format!("Server TLS error: {:?}", err)));
+                    }
+                };
+
+                Ok(StreamInner::Tls { stream })
+            }
+
+            // Server does not support TLS
+            'N' if server_tls.mode == ServerTlsMode::Prefer => Ok(StreamInner::Plain { stream }),
+
+            'N' => {
+                error!(
+                    "Server {} does not support TLS, but server_tls_mode is {}",
+                    address, server_tls.mode
+                );
+                Err(Error::SocketError(format!(
+                    "Server does not support TLS, but server_tls_mode is {}",
+                    server_tls.mode
+                )))
+            }
+
+            // Something else?
+            m => Err(Error::SocketError(format!(
+                "Unknown message: {}",
+                m as char
+            ))),
+        }
+    } else {
+        Ok(StreamInner::Plain { stream })
+    }
+}
+
+impl AsRawFd for StreamInner {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            StreamInner::Tls { stream } => stream.get_ref().0.as_raw_fd(),
+            StreamInner::Plain { stream } => stream.as_raw_fd(),
+            #[cfg(unix)]
+            StreamInner::Unix { stream } => stream.as_raw_fd(),
         }
     }
 }
@@ -145,6 +323,68 @@ impl std::fmt::Display for CleanupState {
     }
 }
 
+/// Prepared statements created on a server connection by the clients using it.
+/// Past the limit, the least recently used ones are closed.
+#[derive(Debug, Default)]
+pub struct PreparedStatementCache {
+    /// Statements, with when they were last used.
+    statements: HashMap<String, u64>,
+
+    /// Incremented every time a statement is used.
+    uses: u64,
+}
+
+impl PreparedStatementCache {
+    /// The statement was prepared on this connection. It counts as used.
+    pub fn contains(&mut self, name: &str) -> bool {
+        self.uses += 1;
+
+        match self.statements.get_mut(name) {
+            Some(used) => {
+                *used = self.uses;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn insert(&mut self, name: &str) {
+        self.uses += 1;
+        self.statements.insert(name.to_string(), self.uses);
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.statements.remove(name);
+    }
+
+    pub fn clear(&mut self) {
+        self.statements.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.statements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.statements.is_empty()
+    }
+
+    /// Forget the least recently used statement, except the ones in `keep`,
+    /// and return its name so it can be closed on the server.
+    pub fn evict(&mut self, keep: &HashSet<String>) -> Option<String> {
+        let name = self
+            .statements
+            .iter()
+            .filter(|(name, _)| !keep.contains(*name))
+            .min_by_key(|(_, used)| **used)
+            .map(|(name, _)| name.clone())?;
+
+        self.statements.remove(&name);
+
+        Some(name)
+    }
+}
+
 /// Server state.
 pub struct Server {
     /// Server host, e.g. localhost,
@@ -198,6 +438,25 @@ pub struct Server {
 
     /// Should clean up dirty connections?
     cleanup_connections: bool,
+
+    /// Prepared statements created on this connection by the clients using it.
+    prepared_statements: PreparedStatementCache,
+
+    /// Session parameters the clients using this connection changed with SET.
+    session_parameters: SessionParameters,
+
+    /// The server returned an error since we last sent it something.
+    error_received: bool,
+
+    /// Commands the server completed since we last sent it something, and how many were SET.
+    commands_completed: usize,
+    sets_completed: usize,
+
+    /// A command since the last checkin changed data, and wasn't rolled back.
+    wrote: bool,
+
+    /// The last command the server completed was a ROLLBACK.
+    rolled_back: bool,
 }
 
 impl Server {
@@ -216,7 +475,10 @@ impl Server {
         let mut addr_set: Option<AddrSet> = None;
 
         // If we are caching addresses and hostname is not an IP
-        if cached_resolver.enabled() && address.host.parse::<IpAddr>().is_err() {
+        if cached_resolver.enabled()
+            && address.host.parse::<IpAddr>().is_err()
+            && unix_socket_path(&address.host, address.port).is_none()
+        {
             debug!("Resolving {}", &address.host);
             addr_set = match cached_resolver.lookup_ip(&address.host).await {
                 Ok(ok) => {
@@ -230,94 +492,9 @@ impl Server {
             }
         };
 
-        let mut stream =
-            match TcpStream::connect(&format!("{}:{}", &address.host, address.port)).await {
-                Ok(stream) => stream,
-                Err(err) => {
-                    error!("Could not connect to server: {}", err);
-                    return Err(Error::SocketError(format!(
-                        "Could not connect to server: {}",
-                        err
-                    )));
-                }
-            };
-
-        // TCP timeouts.
-        configure_socket(&stream);
-
-        let config = get_config();
-
-        let mut stream = if config.general.server_tls {
-            // Request a TLS connection
-            ssl_request(&mut stream).await?;
-
-            let response = match stream.read_u8().await {
-                Ok(response) => response as char,
-                Err(err) => {
-                    return Err(Error::SocketError(format!(
-                        "Server socket error: {:?}",
-                        err
-                    )))
-                }
-            };
-
-            match response {
-                // Server supports TLS
-                'S' => {
-                    debug!("Connecting to server using TLS");
-
-                    let mut root_store = RootCertStore::empty();
-                    root_store.add_server_trust_anchors(
-                        webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
-                            OwnedTrustAnchor::from_subject_spki_name_constraints(
-                                ta.subject,
-                                ta.spki,
-                                ta.name_constraints,
-                            )
-                        }),
-                    );
-
-                    let mut tls_config = rustls::ClientConfig::builder()
-                        .with_safe_defaults()
-                        .with_root_certificates(root_store)
-                        .with_no_client_auth();
-
-                    // Equivalent to sslmode=prefer which is fine most places.
-                    // If you want verify-full, change `verify_server_certificate` to true.
-                    if !config.general.verify_server_certificate {
-                        let mut dangerous = tls_config.dangerous();
-                        dangerous.set_certificate_verifier(Arc::new(
-                            crate::tls::NoCertificateVerification {},
-                        ));
-                    }
-
-                    let connector = TlsConnector::from(Arc::new(tls_config));
-                    let stream = match connector
-                        .connect(address.host.as_str().try_into().unwrap(), stream)
-                        .await
-                    {
-                        Ok(stream) => stream,
-                        Err(err) => {
-                            return Err(Error::SocketError(format!("Server TLS error: {:?}", err)))
-                        }
-                    };
-
-                    StreamInner::Tls { stream }
-                }
-
-                // Server does not support TLS
-                'N' => StreamInner::Plain { stream },
-
-                // Something else?
-                m => {
-                    return Err(Error::SocketError(format!(
-                        "Unknown message: {}",
-                        m as char
-                    )));
-                }
-            }
-        } else {
-            StreamInner::Plain { stream }
+        let mut stream = match unix_socket_path(&address.host, address.port) {
+            Some(path) => connect_unix(&path).await?,
+            None => connect_tcp(address).await?,
         };
 
         // let (read, write) = split(stream);
@@ -326,10 +503,7 @@ impl Server {
         trace!("Sending StartupMessage");
 
         // StartupMessage
-        let username = match user.server_username {
-            Some(ref server_username) => server_username,
-            None => &user.username,
-        };
+        let username = user.server_username();
 
         let password = match user.server_password {
             Some(ref server_password) => Some(server_password),
@@ -420,6 +594,12 @@ impl Server {
                                 None => {
                                     let option_hash = (*auth_hash.read()).clone();
                                     match option_hash {
+                                        Some(hash) if ScramVerifier::is_verifier(&hash) => return Err(
+                                            Error::ServerAuthError(
+                                                "Auth passthrough (auth_query) returned a SCRAM verifier, which can't be used for md5 auth. Set the server password in cleartext".into(),
+                                                server_identifier
+                                            )
+                                        ),
                                         Some(hash) =>
                                             md5_password_with_hash(
                                                 &mut stream,
@@ -666,6 +846,9 @@ impl Server {
                         }
                     };
 
+                    // Let admins close the connection.
+                    stats.socket(Some(stream.as_raw_fd()));
+
                     let mut server = Server {
                         address: address.clone(),
                         stream: BufStream::new(stream),
@@ -692,6 +875,13 @@ impl Server {
                             )),
                         },
                         cleanup_connections,
+                        prepared_statements: PreparedStatementCache::default(),
+                        session_parameters: SessionParameters::default(),
+                        error_received: false,
+                        commands_completed: 0,
+                        sets_completed: 0,
+                        wrote: false,
+                        rolled_back: false,
                     };
 
                     server.set_name("pgcat").await?;
@@ -720,14 +910,19 @@ impl Server {
         process_id: i32,
         secret_key: i32,
     ) -> Result<(), Error> {
-        let mut stream = match TcpStream::connect(&format!("{}:{}", host, port)).await {
-            Ok(stream) => stream,
-            Err(err) => {
-                error!("Could not connect to server: {}", err);
-                return Err(Error::SocketError("Error reading cancel message".into()));
-            }
+        let mut stream = match unix_socket_path(host, port) {
+            Some(path) => connect_unix(&path).await?,
+            None => match TcpStream::connect(&format!("{}:{}", host, port)).await {
+                Ok(stream) => {
+                    configure_socket(&stream);
+                    StreamInner::Plain { stream }
+                }
+                Err(err) => {
+                    error!("Could not connect to server: {}", err);
+                    return Err(Error::SocketError("Error reading cancel message".into()));
+                }
+            },
         };
-        configure_socket(&stream);
 
         debug!("Sending CancelRequest");
 
@@ -744,6 +939,9 @@ impl Server {
     pub async fn send(&mut self, messages: &BytesMut) -> Result<(), Error> {
         self.mirror_send(messages);
         self.stats().data_sent(messages.len());
+        self.error_received = false;
+        self.commands_completed = 0;
+        self.sets_completed = 0;
 
         match write_all_flush(&mut self.stream, &messages).await {
             Ok(_) => {
@@ -801,6 +999,11 @@ impl Server {
                         // Idle, transaction over.
                         'I' => {
                             self.in_transaction = false;
+
+                            // Nothing the transaction did was kept.
+                            if self.rolled_back {
+                                self.wrote = false;
+                            }
                         }
 
                         // Some error occurred, the transaction was rolled back.
@@ -829,11 +1032,20 @@ impl Server {
                     let mut command_tag = String::new();
                     match message.reader().read_to_string(&mut command_tag) {
                         Ok(_) => {
+                            self.commands_completed += 1;
+                            self.rolled_back = command_tag == "ROLLBACK\0";
+
+                            if writes(&command_tag) {
+                                self.wrote = true;
+                            }
+
                             // Non-exhaustive list of commands that are likely to change session variables/resources
                             // which can leak between clients. This is a best effort to block bad clients
                             // from poisoning a transaction-mode pool by setting inappropriate session variables
                             match command_tag.as_str() {
                                 "SET\0" => {
+                                    self.sets_completed += 1;
+
                                     // We don't detect set statements in transactions
                                     // No great way to differentiate between set and set local
                                     // As a result, we will miss cases when set statements are used in transactions
@@ -847,6 +1059,14 @@ impl Server {
                                     debug!("Server connection marked for clean up");
                                     self.cleanup_state.needs_cleanup_prepare = true;
                                 }
+                                "DISCARD ALL\0" => {
+                                    self.prepared_statements.clear();
+                                    self.session_parameters = SessionParameters::default();
+                                }
+                                "DEALLOCATE\0" | "DEALLOCATE ALL\0" => {
+                                    // We don't know which ones are gone.
+                                    self.prepared_statements.clear();
+                                }
                                 _ => (),
                             }
                         }
@@ -857,6 +1077,11 @@ impl Server {
                     }
                 }
 
+                // ErrorResponse
+                'E' => {
+                    self.error_received = true;
+                }
+
                 // DataRow
                 'D' => {
                     // More data is available after this message, this is not the end of the reply.
@@ -929,6 +1154,9 @@ impl Server {
         if self.bad {
             return self.bad;
         };
+        if self.stats.closing() {
+            return true;
+        }
         let cached_resolver = CACHED_RESOLVER.load();
         if cached_resolver.enabled() {
             if let Some(addr_set) = &self.addr_set {
@@ -962,15 +1190,27 @@ impl Server {
         let mut guard = self.client_server_map.lock();
         guard.insert(
             (process_id, secret_key),
-            (
+            vec![(
                 self.process_id,
                 self.secret_key,
                 self.address.host.clone(),
                 self.address.port,
-            ),
+            )],
         );
     }
 
+    /// Claim the server in addition to the ones the client already claimed,
+    /// so a multi-shard query can be cancelled on all the shards.
+    pub fn claim_also(&mut self, process_id: i32, secret_key: i32) {
+        let mut guard = self.client_server_map.lock();
+        guard.entry((process_id, secret_key)).or_default().push((
+            self.process_id,
+            self.secret_key,
+            self.address.host.clone(),
+            self.address.port,
+        ));
+    }
+
     /// Execute an arbitrary query against the server.
     /// It will use the simple query protocol.
     /// Result will not be returned, so this is useful for things like `SET` or `ROLLBACK`.
@@ -992,6 +1232,22 @@ impl Server {
         Ok(())
     }
 
+    /// Execute an arbitrary query against the server
+    /// and return the values of the rows it returned.
+    pub async fn query_values(&mut self, query: &str) -> Result<Vec<String>, Error> {
+        debug!("Running `{}` on server {:?}", query, self.address);
+
+        self.send(&simple_query(query)).await?;
+
+        let mut message = self.recv().await?;
+
+        while self.data_available {
+            message.put(self.recv().await?);
+        }
+
+        parse_query_message(&mut message).await
+    }
+
     /// Perform any necessary cleanup before putting the server
     /// connection back in the pool
     pub async fn checkin_cleanup(&mut self) -> Result<(), Error> {
@@ -1009,6 +1265,12 @@ impl Server {
         // to avoid leaking state between clients. For performance reasons we only
         // send `DISCARD ALL` if we think the session is altered instead of just sending
         // it before each checkin.
+        // The parameters the clients set are reset by the next one, unless
+        // track_session_parameters was disabled with RELOAD.
+        if !self.session_parameters.is_empty() && !get_config().general.track_session_parameters {
+            self.cleanup_state.needs_cleanup_set = true;
+        }
+
         if self.cleanup_state.needs_cleanup() && self.cleanup_connections {
             warn!("Server returned with session state altered, discarding state ({}) for application {}", self.cleanup_state, self.application_name);
             self.query("DISCARD ALL").await?;
@@ -1016,9 +1278,99 @@ impl Server {
             self.cleanup_state.reset();
         }
 
+        self.wrote = false;
+
         Ok(())
     }
 
+    /// Change the session parameters set with SET to the client's, returning
+    /// the ParameterStatus messages the server sent while doing it. If they can't
+    /// be changed, the connection is marked bad, the client can't use it.
+    pub async fn sync_parameters(
+        &mut self,
+        parameters: &SessionParameters,
+    ) -> Result<BytesMut, Error> {
+        let query = match parameters.replay(&self.session_parameters) {
+            Some(query) => query,
+            None => return Ok(BytesMut::new()),
+        };
+
+        debug!("Replaying `{}` on server {:?}", query, self.address);
+
+        // The parameters stay on the connection, we change them again
+        // for the next client if we need to.
+        let cleanup_state = self.cleanup_state;
+        let mut parameter_status = BytesMut::new();
+
+        self.send(&simple_query(&query)).await?;
+
+        loop {
+            let mut response = self.recv().await?;
+
+            while response.len() >= 5 {
+                let len = (&response[1..5]).get_i32() as usize;
+                let message = response.split_to((len + 1).min(response.len()));
+
+                if message[0] == b'S' {
+                    parameter_status.put(message);
+                }
+            }
+
+            if !self.data_available {
+                break;
+            }
+        }
+
+        self.cleanup_state = cleanup_state;
+
+        // The statements run in one implicit transaction, so none of them were applied.
+        if self.error_received {
+            error!(
+                "Could not replay session parameters `{}` on server {:?}",
+                query, self.address
+            );
+            self.mark_bad();
+            return Err(Error::ServerError);
+        }
+
+        self.session_parameters = parameters.clone();
+
+        Ok(parameter_status)
+    }
+
+    /// The client changed its session parameters on this connection.
+    pub fn set_session_parameters(&mut self, parameters: &SessionParameters) {
+        self.session_parameters = parameters.clone();
+    }
+
+    /// A SET statement changed the session, the connection needs a cleanup before checkin.
+    pub fn needs_cleanup_set(&self) -> bool {
+        self.cleanup_state.needs_cleanup_set
+    }
+
+    /// The SET statements that change the tracked session parameters don't need a cleanup,
+    /// the next client replays its own parameters over them.
+    pub fn set_needs_cleanup_set(&mut self, needs_cleanup_set: bool) {
+        self.cleanup_state.needs_cleanup_set = needs_cleanup_set;
+    }
+
+    /// The server returned an error in response to the last messages we sent.
+    pub fn error_received(&self) -> bool {
+        self.error_received
+    }
+
+    /// A command the server completed since the last checkin changed data,
+    /// and the transaction it ran in wasn't rolled back.
+    pub fn wrote(&self) -> bool {
+        self.wrote
+    }
+
+    /// How many commands the server completed in response to the last messages
+    /// we sent, and how many of them were SET.
+    pub fn commands_completed(&self) -> (usize, usize) {
+        (self.commands_completed, self.sets_completed)
+    }
+
     /// A shorthand for `SET application_name = $1`.
     pub async fn set_name(&mut self, name: &str) -> Result<(), Error> {
         if self.application_name != name {
@@ -1053,6 +1405,11 @@ impl Server {
         self.last_activity
     }
 
+    /// Prepared statements created on this connection by the clients using it.
+    pub fn prepared_statements(&mut self) -> &mut PreparedStatementCache {
+        &mut self.prepared_statements
+    }
+
     // Marks a connection as needing DISCARD ALL at checkin
     pub fn mark_dirty(&mut self) {
         self.cleanup_state.set_true();
@@ -1180,6 +1537,9 @@ impl Drop for Server {
     fn drop(&mut self) {
         self.mirror_disconnect();
 
+        // The socket is about to be closed.
+        self.stats.socket(None);
+
         // Update statistics
         self.stats.disconnect();
 
@@ -1209,3 +1569,131 @@ impl Drop for Server {
         );
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    /// A server connection to the other end of a socket pair.
+    fn server(stats: Arc<ServerStats>) -> (Server, UnixStream) {
+        let (stream, peer) = UnixStream::pair().unwrap();
+        stats.socket(Some(stream.as_raw_fd()));
+
+        let server = Server {
+            address: Address::default(),
+            stream: BufStream::new(StreamInner::Unix { stream }),
+            buffer: BytesMut::new(),
+            server_info: BytesMut::new(),
+            process_id: 0,
+            secret_key: 0,
+            in_transaction: false,
+            data_available: false,
+            bad: false,
+            cleanup_state: CleanupState::new(),
+            client_server_map: ClientServerMap::default(),
+            connected_at: chrono::offset::Utc::now().naive_utc(),
+            stats,
+            application_name: String::new(),
+            last_activity: SystemTime::now(),
+            mirror_manager: None,
+            addr_set: None,
+            cleanup_connections: false,
+            prepared_statements: PreparedStatementCache::default(),
+            session_parameters: SessionParameters::default(),
+            error_received: false,
+            commands_completed: 0,
+            sets_completed: 0,
+            wrote: false,
+            rolled_back: false,
+        };
+
+        (server, peer)
+    }
+
+    #[tokio::test]
+    async fn test_close() {
+        let stats = Arc::new(ServerStats::default());
+        let (server, mut peer) = server(stats.clone());
+        assert!(!server.is_bad());
+
+        // Not used again, but the connection stays open until it's dropped.
+        stats.close();
+        assert!(stats.closing());
+        assert!(server.is_bad());
+
+        let mut buf = [0; 5];
+        assert!(
+            tokio::time::timeout(tokio::time::Duration::from_millis(50), peer.read(&mut buf))
+                .await
+                .is_err()
+        );
+
+        // Terminate message on drop.
+        drop(server);
+        peer.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"X\0\0\0\x04");
+    }
+
+    #[tokio::test]
+    async fn test_kill() {
+        let stats = Arc::new(ServerStats::default());
+        let (server, mut peer) = server(stats.clone());
+
+        // The socket is shut down right away.
+        stats.kill();
+        assert!(server.is_bad());
+
+        let mut buf = [0; 5];
+        assert_eq!(peer.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[test]
+    fn test_writes() {
+        for tag in [
+            "INSERT 0 1\0",
+            "UPDATE 3\0",
+            "DELETE 0\0",
+            "COPY 5\0",
+            "CREATE TABLE\0",
+        ] {
+            assert!(writes(tag), "{}", tag);
+        }
+
+        for tag in [
+            "SELECT 1\0",
+            "BEGIN\0",
+            "COMMIT\0",
+            "SET\0",
+            "START TRANSACTION\0",
+        ] {
+            assert!(!writes(tag), "{}", tag);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wrote() {
+        let (mut server, mut peer) = server(Arc::new(ServerStats::default()));
+
+        // INSERT in a transaction.
+        peer.write_all(b"C\0\0\0\x0fINSERT 0 1\0Z\0\0\0\x05T")
+            .await
+            .unwrap();
+        server.recv().await.unwrap();
+        assert!(server.wrote());
+
+        // The transaction is rolled back.
+        peer.write_all(b"C\0\0\0\x0dROLLBACK\0Z\0\0\0\x05I")
+            .await
+            .unwrap();
+        server.recv().await.unwrap();
+        assert!(!server.wrote());
+
+        // INSERT that commits.
+        peer.write_all(b"C\0\0\0\x0fINSERT 0 1\0Z\0\0\0\x05I")
+            .await
+            .unwrap();
+        server.recv().await.unwrap();
+        assert!(server.wrote());
+    }
+}
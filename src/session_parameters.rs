@@ -0,0 +1,407 @@
+/// Track session parameters clients change with SET, so they can be
+/// replayed on whichever server the client is using in transaction mode.
+use sqlparser::dialect::PostgreSqlDialect;
+use sqlparser::tokenizer::{Token, Tokenizer, Whitespace};
+use std::collections::BTreeMap;
+
+/// A change to the session parameters made by a statement.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    /// Parameter name, its value as written and the statement itself.
+    Set(String, String, String),
+    Reset(String),
+    ResetAll,
+}
+
+/// Session parameters set with SET, by parameter name.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SessionParameters {
+    parameters: BTreeMap<String, String>,
+}
+
+impl SessionParameters {
+    /// Find the SET and RESET statements in a simple query. SET LOCAL and
+    /// transaction characteristics only last until the end of the transaction.
+    pub fn parse(query: &str) -> Vec<Change> {
+        split(query)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|statement| parse_statement(&statement))
+            .collect()
+    }
+
+    /// All the SET statements in the query are either tracked or only last
+    /// until the end of the transaction. The server must have completed as many
+    /// commands, and as many SET commands, as we found statements, or we may
+    /// have missed some.
+    pub fn tracked(query: &str, commands_completed: (usize, usize)) -> bool {
+        let statements = match split(query) {
+            Some(statements) => statements,
+            None => return false,
+        };
+
+        let sets = statements
+            .iter()
+            .filter(|statement| match strip_keyword(statement, "set") {
+                // SET CONSTRAINTS has its own command tag.
+                Some(rest) => strip_keyword(rest, "constraints").is_none(),
+                None => false,
+            })
+            .count();
+
+        (statements.len(), sets) == commands_completed
+            && statements.iter().all(|statement| {
+                strip_keyword(statement, "set").is_none()
+                    || transaction_scoped(statement)
+                    || parse_statement(statement).is_some()
+            })
+    }
+
+    pub fn apply(&mut self, change: Change) {
+        match change {
+            Change::Set(name, _, statement) => {
+                self.parameters.insert(name, statement);
+            }
+            Change::Reset(name) => {
+                self.parameters.remove(&name);
+            }
+            Change::ResetAll => self.parameters.clear(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.parameters.is_empty()
+    }
+
+    /// Query that changes a session with the `current` parameters
+    /// to have these parameters instead, if they're different.
+    pub fn replay(&self, current: &SessionParameters) -> Option<String> {
+        let mut query = String::new();
+
+        for name in current.parameters.keys() {
+            if !self.parameters.contains_key(name) {
+                query.push_str(&format!("RESET {};", name));
+            }
+        }
+
+        for (name, statement) in self.parameters.iter() {
+            if current.parameters.get(name) != Some(statement) {
+                query.push_str(statement);
+                query.push(';');
+            }
+        }
+
+        match query.is_empty() {
+            true => None,
+            false => Some(query),
+        }
+    }
+}
+
+/// Split a query into statements the way Postgres lexes it, without the comments.
+/// Returns None if the query can't be lexed, e.g. it has an unterminated quote.
+fn split(query: &str) -> Option<Vec<String>> {
+    let tokens = Tokenizer::new(&PostgreSqlDialect {}, query)
+        .tokenize_with_location()
+        .ok()?;
+
+    // Byte offset of the start of each line, the tokens are located by line and column.
+    let mut lines = vec![0];
+    lines.extend(query.match_indices('\n').map(|(offset, _)| offset + 1));
+
+    let offset = |line: u64, column: u64| {
+        let start = lines[line as usize - 1];
+        query[start..]
+            .char_indices()
+            .nth(column as usize - 1)
+            .map_or(query.len(), |(offset, _)| start + offset)
+    };
+
+    let mut statements = Vec::new();
+    let mut statement = String::new();
+
+    for (i, token) in tokens.iter().enumerate() {
+        let start = offset(token.location.line, token.location.column);
+        let end = match tokens.get(i + 1) {
+            Some(next) => offset(next.location.line, next.location.column),
+            None => query.len(),
+        };
+
+        match token.token {
+            Token::SemiColon => {
+                statements.push(statement.trim().to_string());
+                statement.clear();
+            }
+
+            Token::Whitespace(Whitespace::SingleLineComment { .. })
+            | Token::Whitespace(Whitespace::MultiLineComment(_)) => statement.push(' '),
+
+            _ => statement.push_str(&query[start..end]),
+        }
+    }
+
+    statements.push(statement.trim().to_string());
+    statements.retain(|statement| !statement.is_empty());
+    Some(statements)
+}
+
+/// The rest of the text if it starts with the keyword.
+fn strip_keyword<'a>(text: &'a str, keyword: &str) -> Option<&'a str> {
+    let word = text.split_whitespace().next()?;
+
+    match word.eq_ignore_ascii_case(keyword) {
+        true => Some(text[word.len()..].trim_start()),
+        false => None,
+    }
+}
+
+/// SET LOCAL and SET TRANSACTION end with the transaction.
+fn transaction_scoped(statement: &str) -> bool {
+    let rest = match strip_keyword(statement, "set") {
+        Some(rest) => rest,
+        None => return false,
+    };
+
+    if strip_keyword(rest, "local").is_some() {
+        return true;
+    }
+
+    let rest = strip_keyword(rest, "session").unwrap_or(rest);
+
+    ["transaction", "constraints"]
+        .iter()
+        .any(|keyword| strip_keyword(rest, keyword).is_some())
+}
+
+fn parse_statement(statement: &str) -> Option<Change> {
+    if let Some(rest) = strip_keyword(statement, "reset") {
+        if strip_keyword(rest, "all").is_some() {
+            return Some(Change::ResetAll);
+        }
+
+        let name = match rest
+            .to_lowercase()
+            .split_whitespace()
+            .collect::<Vec<&str>>()[..]
+        {
+            ["time", "zone"] => "timezone".to_string(),
+            ["session", "authorization"] => "session_authorization".to_string(),
+            [name] => name.to_string(),
+            _ => return None,
+        };
+
+        return Some(Change::Reset(name));
+    }
+
+    if transaction_scoped(statement) {
+        return None;
+    }
+
+    let mut rest = strip_keyword(statement, "set")?;
+
+    if let Some(session) = strip_keyword(rest, "session") {
+        rest = session;
+    }
+
+    let special = [
+        ("time", "timezone"),
+        ("schema", "search_path"),
+        ("names", "client_encoding"),
+        ("role", "role"),
+        ("authorization", "session_authorization"),
+    ];
+
+    let (name, value) = match special
+        .iter()
+        .find_map(|(keyword, name)| Some((name.to_string(), strip_keyword(rest, keyword)?)))
+    {
+        Some((name, value)) if name == "timezone" => (name, strip_keyword(value, "zone")?),
+        Some((name, value)) => (name, value),
+
+        // SET name TO value, SET name = value.
+        None => {
+            let end = rest.find(|c: char| c.is_whitespace() || c == '=')?;
+            let (name, value) = rest.split_at(end);
+            let value = value.trim_start();
+
+            let value = match value.strip_prefix('=') {
+                Some(value) => value.trim_start(),
+                None => strip_keyword(value, "to")?,
+            };
+
+            (name.to_lowercase(), value)
+        }
+    };
+
+    let value = value.trim();
+
+    if value.is_empty() {
+        return None;
+    }
+
+    // SET name TO DEFAULT is the same as RESET name.
+    if value.eq_ignore_ascii_case("default") {
+        return Some(Change::Reset(name));
+    }
+
+    Some(Change::Set(name, value.to_string(), statement.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            SessionParameters::parse("SET search_path TO app, public"),
+            vec![Change::Set(
+                "search_path".into(),
+                "app, public".into(),
+                "SET search_path TO app, public".into()
+            )]
+        );
+
+        assert_eq!(
+            SessionParameters::parse("set session TimeZone='UTC'; SELECT ';'; RESET ALL"),
+            vec![
+                Change::Set(
+                    "timezone".into(),
+                    "'UTC'".into(),
+                    "set session TimeZone='UTC'".into()
+                ),
+                Change::ResetAll
+            ]
+        );
+
+        assert_eq!(
+            SessionParameters::parse("SET TIME ZONE 'Europe/Paris'"),
+            vec![Change::Set(
+                "timezone".into(),
+                "'Europe/Paris'".into(),
+                "SET TIME ZONE 'Europe/Paris'".into()
+            )]
+        );
+
+        assert_eq!(
+            SessionParameters::parse("SET statement_timeout = DEFAULT"),
+            vec![Change::Reset("statement_timeout".into())]
+        );
+
+        // A comment can't hide a statement.
+        assert_eq!(
+            SessionParameters::parse("SET search_path TO app; -- don't\nSET ROLE admin"),
+            vec![
+                Change::Set(
+                    "search_path".into(),
+                    "app".into(),
+                    "SET search_path TO app".into()
+                ),
+                Change::Set("role".into(), "admin".into(), "SET ROLE admin".into())
+            ]
+        );
+
+        assert!(SessionParameters::parse("SET LOCAL statement_timeout = 5").is_empty());
+        assert!(
+            SessionParameters::parse("SET TRANSACTION ISOLATION LEVEL SERIALIZABLE").is_empty()
+        );
+        assert!(SessionParameters::parse("SELECT 1").is_empty());
+    }
+
+    #[test]
+    fn test_split() {
+        assert_eq!(
+            split("SET search_path TO app; -- don't\nSET ROLE admin").unwrap(),
+            vec!["SET search_path TO app", "SET ROLE admin"]
+        );
+        assert_eq!(
+            split("/* a; 'b */ SELECT $$;'$$, E'\\';', 'it''s;'; SELECT 2").unwrap(),
+            vec!["SELECT $$;'$$, E'\\';', 'it''s;'", "SELECT 2"]
+        );
+        assert_eq!(
+            split("SELECT 'é'; SET a = 'ü' /* ; */ ;\r\n -- ;").unwrap(),
+            vec!["SELECT 'é'", "SET a = 'ü'"]
+        );
+        assert_eq!(split("SELECT 'unterminated; SET ROLE admin"), None);
+    }
+
+    #[test]
+    fn test_tracked() {
+        assert!(SessionParameters::tracked(
+            "SET search_path TO app; SELECT 1",
+            (2, 1)
+        ));
+        assert!(SessionParameters::tracked(
+            "SET LOCAL work_mem = '64MB'",
+            (1, 1)
+        ));
+        assert!(SessionParameters::tracked(
+            "SET TRANSACTION ISOLATION LEVEL SERIALIZABLE",
+            (1, 1)
+        ));
+        assert!(SessionParameters::tracked(
+            "SET search_path TO app; -- don't\nSET ROLE admin",
+            (2, 2)
+        ));
+        assert!(SessionParameters::tracked("RESET ALL", (1, 0)));
+        assert!(SessionParameters::tracked("SELECT 'SET x'", (1, 0)));
+        assert!(SessionParameters::tracked(
+            "SET CONSTRAINTS ALL DEFERRED",
+            (1, 0)
+        ));
+
+        // These stay on the connection and aren't replayed for the client.
+        assert!(!SessionParameters::tracked(
+            "SET SESSION CHARACTERISTICS AS TRANSACTION READ ONLY",
+            (1, 1)
+        ));
+        assert!(!SessionParameters::tracked(
+            "SET search_path TO app; SET work_mem",
+            (2, 2)
+        ));
+
+        // The server ran statements we didn't see.
+        assert!(!SessionParameters::tracked(
+            "SET search_path TO app",
+            (2, 2)
+        ));
+        assert!(!SessionParameters::tracked(
+            "SET search_path TO app; SELECT 1",
+            (2, 2)
+        ));
+        assert!(!SessionParameters::tracked("SELECT 'unterminated", (0, 0)));
+    }
+
+    #[test]
+    fn test_replay() {
+        let mut client = SessionParameters::default();
+        let mut server = SessionParameters::default();
+
+        assert_eq!(client.replay(&server), None);
+
+        for change in SessionParameters::parse("SET search_path TO app; SET work_mem = '64MB'") {
+            client.apply(change);
+        }
+
+        assert_eq!(
+            client.replay(&server),
+            Some("SET search_path TO app;SET work_mem = '64MB';".into())
+        );
+
+        server.apply(Change::Set(
+            "timezone".into(),
+            "'UTC'".into(),
+            "SET timezone = 'UTC'".into(),
+        ));
+        server.apply(Change::Set(
+            "search_path".into(),
+            "app".into(),
+            "SET search_path TO app".into(),
+        ));
+
+        assert_eq!(
+            client.replay(&server),
+            Some("RESET timezone;SET work_mem = '64MB';".into())
+        );
+    }
+}
@@ -1,6 +1,9 @@
 use serde_derive::{Deserialize, Serialize};
 /// Implements various sharding functions.
 use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Arc;
 
 /// See: <https://github.com/postgres/postgres/blob/27b77ecf9f4d5be211900eda54d8155ada50d696/src/include/catalog/partition.h#L20>.
 const PARTITION_HASH_SEED: u64 = 0x7A5B22367996DCFD;
@@ -12,6 +15,10 @@ pub enum ShardingFunction {
     PgBigintHash,
     #[serde(alias = "sha1", alias = "Sha1")]
     Sha1,
+    #[serde(alias = "range", alias = "Range")]
+    Range,
+    #[serde(alias = "list", alias = "List")]
+    List,
 }
 
 impl ToString for ShardingFunction {
@@ -19,10 +26,51 @@ impl ToString for ShardingFunction {
         match *self {
             ShardingFunction::PgBigintHash => "pg_bigint_hash".to_string(),
             ShardingFunction::Sha1 => "sha1".to_string(),
+            ShardingFunction::Range => "range".to_string(),
+            ShardingFunction::List => "list".to_string(),
         }
     }
 }
 
+/// The keys of each shard, used by the range and list sharding functions.
+#[derive(Debug, Clone, Default)]
+pub struct ShardKeys {
+    /// Key ranges and their shard, sorted by their first key.
+    ranges: Vec<(Range<i64>, usize)>,
+
+    /// Shard of each key.
+    keys: HashMap<i64, usize>,
+}
+
+impl ShardKeys {
+    /// Keys from the first one to the last one (exclusive) go to the shard.
+    pub fn add_range(&mut self, shard: usize, range: Range<i64>) {
+        self.ranges.push((range, shard));
+        self.ranges.sort_by_key(|(range, _)| range.start);
+    }
+
+    /// These keys go to the shard.
+    pub fn add_keys(&mut self, shard: usize, keys: &[i64]) {
+        for key in keys {
+            self.keys.insert(*key, shard);
+        }
+    }
+
+    fn range(&self, key: i64) -> Option<usize> {
+        // Ranges don't overlap, so the first one ending after the key is the only one that can contain it.
+        let index = self.ranges.partition_point(|(range, _)| range.end <= key);
+
+        match self.ranges.get(index) {
+            Some((range, shard)) if range.contains(&key) => Some(*shard),
+            _ => None,
+        }
+    }
+
+    fn list(&self, key: i64) -> Option<usize> {
+        self.keys.get(&key).copied()
+    }
+}
+
 /// The sharder.
 pub struct Sharder {
     /// Number of shards in the cluster.
@@ -30,22 +78,33 @@ pub struct Sharder {
 
     /// The sharding function in use.
     sharding_function: ShardingFunction,
+
+    /// Keys of each shard, for the range and list sharding functions.
+    shard_keys: Arc<ShardKeys>,
 }
 
 impl Sharder {
     /// Create new instance of the sharder.
-    pub fn new(shards: usize, sharding_function: ShardingFunction) -> Sharder {
+    pub fn new(
+        shards: usize,
+        sharding_function: ShardingFunction,
+        shard_keys: Arc<ShardKeys>,
+    ) -> Sharder {
         Sharder {
             shards,
             sharding_function,
+            shard_keys,
         }
     }
 
-    /// Compute the shard given sharding key.
-    pub fn shard(&self, key: i64) -> usize {
+    /// Compute the shard given sharding key. Returns None if the key
+    /// isn't in any shard, which only happens with the range and list sharding functions.
+    pub fn shard(&self, key: i64) -> Option<usize> {
         match self.sharding_function {
-            ShardingFunction::PgBigintHash => self.pg_bigint_hash(key),
-            ShardingFunction::Sha1 => self.sha1(key),
+            ShardingFunction::PgBigintHash => Some(self.pg_bigint_hash(key)),
+            ShardingFunction::Sha1 => Some(self.sha1(key)),
+            ShardingFunction::Range => self.shard_keys.range(key),
+            ShardingFunction::List => self.shard_keys.list(key),
         }
     }
 
@@ -166,42 +225,42 @@ mod test {
     // confirming that we implemented Postgres BIGINT hashing correctly.
     #[test]
     fn test_pg_bigint_hash() {
-        let sharder = Sharder::new(5, ShardingFunction::PgBigintHash);
+        let sharder = Sharder::new(5, ShardingFunction::PgBigintHash, Arc::default());
 
         let shard_0 = vec![1, 4, 5, 14, 19, 39, 40, 46, 47, 53];
 
         for v in shard_0 {
-            assert_eq!(sharder.shard(v), 0);
+            assert_eq!(sharder.shard(v).unwrap(), 0);
         }
 
         let shard_1 = vec![2, 3, 11, 17, 21, 23, 30, 49, 51, 54];
 
         for v in shard_1 {
-            assert_eq!(sharder.shard(v), 1);
+            assert_eq!(sharder.shard(v).unwrap(), 1);
         }
 
         let shard_2 = vec![6, 7, 15, 16, 18, 20, 25, 28, 34, 35];
 
         for v in shard_2 {
-            assert_eq!(sharder.shard(v), 2);
+            assert_eq!(sharder.shard(v).unwrap(), 2);
         }
 
         let shard_3 = vec![8, 12, 13, 22, 29, 31, 33, 36, 41, 43];
 
         for v in shard_3 {
-            assert_eq!(sharder.shard(v), 3);
+            assert_eq!(sharder.shard(v).unwrap(), 3);
         }
 
         let shard_4 = vec![9, 10, 24, 26, 27, 32, 37, 38, 42, 45];
 
         for v in shard_4 {
-            assert_eq!(sharder.shard(v), 4);
+            assert_eq!(sharder.shard(v).unwrap(), 4);
         }
     }
 
     #[test]
     fn test_sha1_hash() {
-        let sharder = Sharder::new(12, ShardingFunction::Sha1);
+        let sharder = Sharder::new(12, ShardingFunction::Sha1, Arc::default());
         let ids = vec![
             0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19,
         ];
@@ -210,7 +269,38 @@ mod test {
         ];
 
         for (i, id) in ids.iter().enumerate() {
-            assert_eq!(sharder.shard(*id), shards[i]);
+            assert_eq!(sharder.shard(*id).unwrap(), shards[i]);
         }
     }
+
+    #[test]
+    fn test_range() {
+        let mut shard_keys = ShardKeys::default();
+        shard_keys.add_range(1, 1000..2000);
+        shard_keys.add_range(0, 0..1000);
+        shard_keys.add_range(2, 5000..i64::MAX);
+
+        let sharder = Sharder::new(3, ShardingFunction::Range, Arc::new(shard_keys));
+
+        assert_eq!(sharder.shard(0), Some(0));
+        assert_eq!(sharder.shard(999), Some(0));
+        assert_eq!(sharder.shard(1000), Some(1));
+        assert_eq!(sharder.shard(1999), Some(1));
+        assert_eq!(sharder.shard(2000), None);
+        assert_eq!(sharder.shard(-1), None);
+        assert_eq!(sharder.shard(5000), Some(2));
+    }
+
+    #[test]
+    fn test_list() {
+        let mut shard_keys = ShardKeys::default();
+        shard_keys.add_keys(0, &[1, 3, 5]);
+        shard_keys.add_keys(1, &[2, 4]);
+
+        let sharder = Sharder::new(2, ShardingFunction::List, Arc::new(shard_keys));
+
+        assert_eq!(sharder.shard(3), Some(0));
+        assert_eq!(sharder.shard(4), Some(1));
+        assert_eq!(sharder.shard(6), None);
+    }
 }
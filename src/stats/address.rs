@@ -24,6 +24,15 @@ pub struct AddressStats {
 
     // Determines if the averages have been updated since the last time they were reported
     pub averages_updated: Arc<AtomicBool>,
+
+    // Replication lag in ms, measured if max_replica_lag is set
+    replica_lag: Arc<AtomicU64>,
+
+    // WAL position replayed by a replica, measured if read_your_writes_window is set
+    replay_lsn: Arc<AtomicU64>,
+
+    // The last new connection was refused because of max_db_connections or max_user_connections
+    conn_limit_reached: Arc<AtomicBool>,
 }
 
 impl IntoIterator for AddressStats {
@@ -218,6 +227,30 @@ impl AddressStats {
         self.current.errors.store(0, Ordering::Relaxed);
     }
 
+    pub fn replica_lag(&self) -> u64 {
+        self.replica_lag.load(Ordering::Relaxed)
+    }
+
+    pub fn set_replica_lag(&self, lag: u64) {
+        self.replica_lag.store(lag, Ordering::Relaxed);
+    }
+
+    pub fn replay_lsn(&self) -> u64 {
+        self.replay_lsn.load(Ordering::Relaxed)
+    }
+
+    pub fn set_replay_lsn(&self, lsn: u64) {
+        self.replay_lsn.store(lsn, Ordering::Relaxed);
+    }
+
+    pub fn conn_limit_reached(&self) -> bool {
+        self.conn_limit_reached.load(Ordering::Relaxed)
+    }
+
+    pub fn set_conn_limit_reached(&self, reached: bool) {
+        self.conn_limit_reached.store(reached, Ordering::Relaxed);
+    }
+
     pub fn populate_row(&self, row: &mut Vec<String>) {
         for (_key, value) in self.clone() {
             row.push(value.to_string());
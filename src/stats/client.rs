@@ -2,6 +2,7 @@ use super::{get_reporter, Reporter};
 use atomic_enum::atomic_enum;
 use std::sync::atomic::*;
 use std::sync::Arc;
+use tokio::sync::Notify;
 use tokio::time::Instant;
 /// The various states that a client can be in
 #[atomic_enum]
@@ -52,6 +53,9 @@ pub struct ClientStats {
 
     /// Number of errors made by this client
     pub error_count: Arc<AtomicU64>,
+
+    /// Killed by an admin, the client must disconnect.
+    kill: Arc<Notify>,
 }
 
 impl Default for ClientStats {
@@ -68,6 +72,7 @@ impl Default for ClientStats {
             transaction_count: Arc::new(AtomicU64::new(0)),
             query_count: Arc::new(AtomicU64::new(0)),
             error_count: Arc::new(AtomicU64::new(0)),
+            kill: Arc::new(Notify::new()),
             reporter: get_reporter(),
         }
     }
@@ -151,6 +156,16 @@ impl ClientStats {
         self.transaction_count.fetch_add(1, Ordering::Relaxed);
     }
 
+    /// Disconnect the client the next time it waits for a message.
+    pub fn kill(&self) {
+        self.kill.notify_one();
+    }
+
+    /// Wait until the client is killed.
+    pub async fn killed(&self) {
+        self.kill.notified().await
+    }
+
     // Helper methods for show clients
     pub fn connect_time(&self) -> Instant {
         self.connect_time
@@ -22,6 +22,9 @@ pub struct PoolStats {
     pub sv_tested: u64,
     pub sv_login: u64,
     pub maxwait: u64,
+    pub cl_rejected: u64,
+    pub cl_wait_rejected: u64,
+    pub disabled: bool,
 }
 impl PoolStats {
     pub fn new(identifier: PoolIdentifier, mode: PoolMode) -> Self {
@@ -38,6 +41,9 @@ impl PoolStats {
             sv_tested: 0,
             sv_login: 0,
             maxwait: 0,
+            cl_rejected: 0,
+            cl_wait_rejected: 0,
+            disabled: false,
         }
     }
 
@@ -47,10 +53,10 @@ impl PoolStats {
         let server_map = super::get_server_stats();
 
         for (identifier, pool) in get_all_pools() {
-            map.insert(
-                identifier.clone(),
-                PoolStats::new(identifier, pool.settings.pool_mode),
-            );
+            let mut pool_stats = PoolStats::new(identifier.clone(), pool.settings.pool_mode);
+            (pool_stats.cl_rejected, pool_stats.cl_wait_rejected) = pool.rejected();
+            pool_stats.disabled = pool.disabled();
+            map.insert(identifier, pool_stats);
         }
 
         for client in client_map.values() {
@@ -105,6 +111,9 @@ impl PoolStats {
             ("sv_login", DataType::Numeric),
             ("maxwait", DataType::Numeric),
             ("maxwait_us", DataType::Numeric),
+            ("cl_rejected", DataType::Numeric),
+            ("cl_wait_rejected", DataType::Numeric),
+            ("disabled", DataType::Numeric),
         ];
     }
 
@@ -124,6 +133,9 @@ impl PoolStats {
             self.sv_login.to_string(),
             (self.maxwait / 1_000_000).to_string(),
             (self.maxwait % 1_000_000).to_string(),
+            self.cl_rejected.to_string(),
+            self.cl_wait_rejected.to_string(),
+            (self.disabled as u8).to_string(),
         ];
     }
 }
@@ -145,6 +157,8 @@ impl IntoIterator for PoolStats {
             ("sv_login".to_string(), self.sv_login),
             ("maxwait".to_string(), self.maxwait / 1_000_000),
             ("maxwait_us".to_string(), self.maxwait % 1_000_000),
+            ("cl_rejected".to_string(), self.cl_rejected),
+            ("cl_wait_rejected".to_string(), self.cl_wait_rejected),
         ]
         .into_iter()
     }
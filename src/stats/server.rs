@@ -2,7 +2,10 @@ use super::AddressStats;
 use super::{get_reporter, Reporter};
 use crate::config::Address;
 use atomic_enum::atomic_enum;
-use parking_lot::RwLock;
+use log::warn;
+use nix::sys::socket::{shutdown, Shutdown};
+use parking_lot::{Mutex, RwLock};
+use std::os::unix::io::RawFd;
 use std::sync::atomic::*;
 use std::sync::Arc;
 use tokio::time::Instant;
@@ -35,6 +38,7 @@ pub struct ServerStats {
 
     /// Context information, only to be read
     address: Address,
+    server_username: String,
     connect_time: Instant,
 
     reporter: Reporter,
@@ -47,6 +51,12 @@ pub struct ServerStats {
     pub transaction_count: Arc<AtomicU64>,
     pub query_count: Arc<AtomicU64>,
     pub error_count: Arc<AtomicU64>,
+
+    /// The connection must be closed instead of being used again.
+    closing: Arc<AtomicBool>,
+
+    /// Socket of the connection, while it's open.
+    socket: Arc<Mutex<Option<RawFd>>>,
 }
 
 impl Default for ServerStats {
@@ -55,6 +65,7 @@ impl Default for ServerStats {
             server_id: 0,
             application_name: Arc::new(RwLock::new(String::new())),
             address: Address::default(),
+            server_username: String::new(),
             connect_time: Instant::now(),
             state: Arc::new(AtomicServerState::new(ServerState::Login)),
             bytes_sent: Arc::new(AtomicU64::new(0)),
@@ -62,15 +73,18 @@ impl Default for ServerStats {
             transaction_count: Arc::new(AtomicU64::new(0)),
             query_count: Arc::new(AtomicU64::new(0)),
             error_count: Arc::new(AtomicU64::new(0)),
+            closing: Arc::new(AtomicBool::new(false)),
+            socket: Arc::new(Mutex::new(None)),
             reporter: get_reporter(),
         }
     }
 }
 
 impl ServerStats {
-    pub fn new(address: Address, connect_time: Instant) -> Self {
+    pub fn new(address: Address, server_username: &str, connect_time: Instant) -> Self {
         Self {
             address,
+            server_username: server_username.to_string(),
             connect_time,
             server_id: rand::random::<i32>(),
             ..Default::default()
@@ -120,6 +134,46 @@ impl ServerStats {
         self.set_application(application_name);
     }
 
+    /// Reports the socket of the server connection, or that it's about to be closed.
+    pub fn socket(&self, socket: Option<RawFd>) {
+        *self.socket.lock() = socket;
+    }
+
+    /// Kill the server connection: close its socket, so the server ends the session
+    /// and whoever is using the connection gets an error, and make sure it's
+    /// not used again.
+    pub fn kill(&self) {
+        self.close();
+
+        // The lock keeps the connection from closing the socket while we shut it down.
+        if let Some(socket) = *self.socket.lock() {
+            if let Err(err) = shutdown(socket, Shutdown::Both) {
+                warn!(
+                    "Could not close server {} socket: {:?}",
+                    self.server_id, err
+                );
+            }
+        }
+    }
+
+    /// Close the server connection instead of returning it to the pool.
+    pub fn close(&self) {
+        self.closing.store(true, Ordering::Relaxed);
+    }
+
+    pub fn closing(&self) -> bool {
+        self.closing.load(Ordering::Relaxed)
+    }
+
+    pub fn address(&self) -> &Address {
+        &self.address
+    }
+
+    /// The user the connection is logged in as on the server.
+    pub fn server_username(&self) -> &str {
+        &self.server_username
+    }
+
     pub fn address_stats(&self) -> Arc<AddressStats> {
         self.address.stats.clone()
     }
@@ -144,6 +198,14 @@ impl ServerStats {
         self.address.username.clone()
     }
 
+    pub fn address_id(&self) -> usize {
+        self.address.id
+    }
+
+    pub fn replica_lag(&self) -> u64 {
+        self.address.stats.replica_lag()
+    }
+
     pub fn address_name(&self) -> String {
         self.address.name()
     }
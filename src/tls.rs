@@ -1,5 +1,9 @@
 // Stream wrapper.
 
+use arc_swap::ArcSwapOption;
+use log::{error, info};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
 use rustls_pemfile::{certs, read_one, Item};
 use std::iter;
 use std::path::Path;
@@ -7,12 +11,13 @@ use std::sync::Arc;
 use std::time::SystemTime;
 use tokio_rustls::rustls::{
     self,
-    client::{ServerCertVerified, ServerCertVerifier},
-    Certificate, PrivateKey, ServerName,
+    client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+    server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient},
+    Certificate, CertificateError, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerName,
 };
-use tokio_rustls::TlsAcceptor;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
 
-use crate::config::get_config;
+use crate::config::{get_config, ServerTls, ServerTlsMode};
 use crate::errors::Error;
 
 // TLS
@@ -44,24 +49,76 @@ impl Tls {
     pub fn new() -> Result<Self, Error> {
         let config = get_config();
 
-        let certs = match load_certs(Path::new(&config.general.tls_certificate.unwrap())) {
-            Ok(certs) => certs,
-            Err(_) => return Err(Error::TlsError),
+        let certificate = config.general.tls_certificate.clone().unwrap_or_default();
+        let private_key = config.general.tls_private_key.clone().unwrap_or_default();
+
+        let certs = match load_certs(Path::new(&certificate)) {
+            Ok(certs) if !certs.is_empty() => certs,
+            Ok(_) => {
+                error!("No certificates found in {}", certificate);
+                return Err(Error::TlsError);
+            }
+            Err(err) => {
+                error!("Could not load certificate {}: {:?}", certificate, err);
+                return Err(Error::TlsError);
+            }
         };
 
-        let mut keys = match load_keys(Path::new(&config.general.tls_private_key.unwrap())) {
-            Ok(keys) => keys,
-            Err(_) => return Err(Error::TlsError),
+        let key = match load_keys(Path::new(&private_key)) {
+            Ok(mut keys) if !keys.is_empty() => keys.remove(0),
+            Ok(_) => {
+                error!("No private key found in {}", private_key);
+                return Err(Error::TlsError);
+            }
+            Err(err) => {
+                error!("Could not load private key {}: {:?}", private_key, err);
+                return Err(Error::TlsError);
+            }
         };
 
-        let config = match rustls::ServerConfig::builder()
-            .with_safe_defaults()
-            .with_no_client_auth()
-            .with_single_cert(certs, keys.remove(0))
-            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))
-        {
+        let builder = rustls::ServerConfig::builder().with_safe_defaults();
+
+        // Verify client certificates if we have a CA to check them with.
+        let builder = match config.general.tls_client_ca_certificate {
+            Some(ref ca) => {
+                let mut roots = RootCertStore::empty();
+
+                match load_certs(Path::new(ca)) {
+                    Ok(ca_certs) => {
+                        for cert in ca_certs.iter() {
+                            if let Err(err) = roots.add(cert) {
+                                error!("Invalid CA certificate in {}: {:?}", ca, err);
+                                return Err(Error::TlsError);
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        error!("Could not load client CA certificates {}: {:?}", ca, err);
+                        return Err(Error::TlsError);
+                    }
+                }
+
+                if config.general.tls_client_certificate_required {
+                    builder
+                        .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+                } else {
+                    builder.with_client_cert_verifier(
+                        AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed(),
+                    )
+                }
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let config = match builder.with_single_cert(certs, key) {
             Ok(c) => c,
-            Err(_) => return Err(Error::TlsError),
+            Err(err) => {
+                error!(
+                    "Certificate {} does not match private key {}: {:?}",
+                    certificate, private_key, err
+                );
+                return Err(Error::TlsError);
+            }
         };
 
         Ok(Tls {
@@ -70,6 +127,314 @@ impl Tls {
     }
 }
 
+/// The acceptor clients negotiate TLS with, built from the certificate files
+/// when the config is loaded and whenever they change.
+static TLS: Lazy<ArcSwapOption<Tls>> = Lazy::new(|| ArcSwapOption::from(None));
+
+/// Modification times of the certificate files the acceptor was built from.
+static TLS_FILES: Lazy<Mutex<Vec<Option<SystemTime>>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Get the current TLS acceptor, if TLS is configured.
+pub fn get_tls() -> Option<Arc<Tls>> {
+    TLS.load_full()
+}
+
+/// Rebuild the TLS acceptor from the current config. If the certificate files
+/// are invalid, the previous acceptor is kept and clients keep using it.
+pub fn reload_tls() -> Result<(), Error> {
+    let config = get_config();
+
+    if config.general.tls_certificate.is_none() {
+        TLS.store(None);
+        TLS_FILES.lock().clear();
+        return Ok(());
+    }
+
+    // Don't retry invalid files until they change again.
+    let modified = tls_files_modified();
+    let changed = *TLS_FILES.lock() != modified;
+    *TLS_FILES.lock() = modified;
+
+    match Tls::new() {
+        Ok(tls) => {
+            if TLS.swap(Some(Arc::new(tls))).is_some() && changed {
+                info!("Reloaded TLS certificate");
+            }
+            Ok(())
+        }
+
+        Err(err) => {
+            if TLS.load().is_some() {
+                error!("Could not reload the TLS certificate, keeping the previous one");
+            }
+            Err(err)
+        }
+    }
+}
+
+/// How often to look at `tls_certificate_check_interval` while it's unset.
+static TLS_WATCH_IDLE_RATE: u64 = 1_000; // 1 second
+
+/// Reload the TLS acceptor when the certificate files change on disk.
+/// The check interval is read from the config every time, so it can be set,
+/// changed or unset with a config reload.
+pub async fn watch_tls_files() {
+    loop {
+        let interval = get_config().general.tls_certificate_check_interval;
+
+        tokio::time::sleep(tokio::time::Duration::from_millis(match interval {
+            Some(interval) if interval > 0 => interval,
+            _ => TLS_WATCH_IDLE_RATE,
+        }))
+        .await;
+
+        let config = get_config();
+
+        if !matches!(config.general.tls_certificate_check_interval, Some(interval) if interval > 0)
+            || config.general.tls_certificate.is_none()
+        {
+            continue;
+        }
+
+        if tls_files_modified() != *TLS_FILES.lock() {
+            info!("TLS certificate files changed, reloading");
+            let _ = reload_tls();
+        }
+    }
+}
+
+fn tls_files_modified() -> Vec<Option<SystemTime>> {
+    let config = get_config();
+
+    [
+        &config.general.tls_certificate,
+        &config.general.tls_private_key,
+        &config.general.tls_client_ca_certificate,
+    ]
+    .iter()
+    .map(|path| match path {
+        Some(path) => std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok(),
+        None => None,
+    })
+    .collect()
+}
+
+/// Names a client certificate identifies its owner with: the subject CN,
+/// and the DNS, email and URI subject alternative names.
+pub fn certificate_names(cert: &Certificate) -> Vec<String> {
+    let mut names = Vec::new();
+
+    // Certificate, then TBSCertificate.
+    let tbs = match der_read(&cert.0).and_then(|(_, cert, _)| der_read(cert)) {
+        Some((0x30, tbs, _)) => tbs,
+        _ => return names,
+    };
+
+    // TBSCertificate fields, the version is optional.
+    let mut fields = Vec::new();
+    let mut rest = tbs;
+    while let Some((tag, value, next)) = der_read(rest) {
+        if tag != 0xa0 {
+            fields.push((tag, value));
+        }
+        rest = next;
+    }
+
+    // serialNumber, signature, issuer, validity, subject.
+    if let Some((0x30, subject)) = fields.get(4) {
+        let mut rdns = *subject;
+        while let Some((_, rdn, next)) = der_read(rdns) {
+            if let Some((_, attribute, _)) = der_read(rdn) {
+                if let Some((0x06, OID_COMMON_NAME, value)) = der_read(attribute) {
+                    if let Some((_, name, _)) = der_read(value) {
+                        names.push(String::from_utf8_lossy(name).to_string());
+                    }
+                }
+            }
+            rdns = next;
+        }
+    }
+
+    // Extensions are explicitly tagged [3].
+    let extensions = match fields.iter().find(|(tag, _)| *tag == 0xa3) {
+        Some((_, extensions)) => match der_read(extensions) {
+            Some((_, extensions, _)) => extensions,
+            None => return names,
+        },
+        None => return names,
+    };
+
+    let mut rest = extensions;
+    while let Some((_, extension, next)) = der_read(rest) {
+        if let Some((0x06, OID_SUBJECT_ALT_NAME, value)) = der_read(extension) {
+            // Skip the critical flag.
+            let value = match der_read(value) {
+                Some((0x01, _, value)) => value,
+                _ => value,
+            };
+
+            if let Some((0x04, san, _)) = der_read(value) {
+                if let Some((0x30, mut general_names, _)) = der_read(san) {
+                    while let Some((tag, name, next)) = der_read(general_names) {
+                        // rfc822Name, dNSName, uniformResourceIdentifier.
+                        if tag == 0x81 || tag == 0x82 || tag == 0x86 {
+                            names.push(String::from_utf8_lossy(name).to_string());
+                        }
+                        general_names = next;
+                    }
+                }
+            }
+        }
+        rest = next;
+    }
+
+    names
+}
+
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+/// Read a DER element: its tag, its value and what comes after it.
+fn der_read(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *data.first()?;
+    let first = *data.get(1)? as usize;
+
+    let (len, header) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let bytes = first & 0x7f;
+        if bytes == 0 || bytes > 4 {
+            return None;
+        }
+
+        let mut len = 0usize;
+        for byte in data.get(2..2 + bytes)? {
+            len = (len << 8) | *byte as usize;
+        }
+
+        (len, 2 + bytes)
+    };
+
+    let value = data.get(header..header.checked_add(len)?)?;
+    Some((tag, value, &data[header + len..]))
+}
+
+/// Build the connector for TLS connections to servers.
+pub fn server_tls_connector(tls: &ServerTls) -> Result<TlsConnector, Error> {
+    let mut roots = RootCertStore::empty();
+
+    match tls.ca_certificate {
+        Some(ref ca) => match load_certs(Path::new(ca)) {
+            Ok(certs) => {
+                for cert in certs.iter() {
+                    if let Err(err) = roots.add(cert) {
+                        error!("Invalid server CA certificate in {}: {:?}", ca, err);
+                        return Err(Error::TlsError);
+                    }
+                }
+            }
+            Err(err) => {
+                error!("Could not load server CA certificates {}: {:?}", ca, err);
+                return Err(Error::TlsError);
+            }
+        },
+
+        None => roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        })),
+    };
+
+    // Like libpq, require verifies the certificate if we have a CA for it.
+    let verifier: Arc<dyn ServerCertVerifier> = match tls.mode {
+        ServerTlsMode::VerifyFull => Arc::new(WebPkiVerifier::new(roots, None)),
+        ServerTlsMode::VerifyCa => {
+            Arc::new(NoHostnameVerification(WebPkiVerifier::new(roots, None)))
+        }
+        ServerTlsMode::Require if tls.ca_certificate.is_some() => {
+            Arc::new(NoHostnameVerification(WebPkiVerifier::new(roots, None)))
+        }
+        _ => Arc::new(NoCertificateVerification),
+    };
+
+    let builder = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(verifier);
+
+    let config = match (&tls.certificate, &tls.private_key) {
+        (Some(certificate), Some(private_key)) => {
+            let certs = match load_certs(Path::new(certificate)) {
+                Ok(certs) => certs,
+                Err(err) => {
+                    error!(
+                        "Could not load server TLS certificate {}: {:?}",
+                        certificate, err
+                    );
+                    return Err(Error::TlsError);
+                }
+            };
+
+            let mut keys = match load_keys(Path::new(private_key)) {
+                Ok(keys) if !keys.is_empty() => keys,
+                result => {
+                    error!(
+                        "Could not load server TLS private key {}: {:?}",
+                        private_key, result
+                    );
+                    return Err(Error::TlsError);
+                }
+            };
+
+            match builder.with_single_cert(certs, keys.remove(0)) {
+                Ok(config) => config,
+                Err(err) => {
+                    error!("Invalid server TLS certificate {}: {:?}", certificate, err);
+                    return Err(Error::TlsError);
+                }
+            }
+        }
+
+        _ => builder.with_no_client_auth(),
+    };
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Verifies the certificate was issued by a trusted CA, for any host.
+pub struct NoHostnameVerification(WebPkiVerifier);
+
+impl ServerCertVerifier for NoHostnameVerification {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match self.0.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        ) {
+            // The chain is verified before the name.
+            Err(rustls::Error::InvalidCertificate(CertificateError::NotValidForName)) => {
+                Ok(ServerCertVerified::assertion())
+            }
+            result => result,
+        }
+    }
+}
+
 pub struct NoCertificateVerification;
 
 impl ServerCertVerifier for NoCertificateVerification {
@@ -85,3 +450,39 @@ impl ServerCertVerifier for NoCertificateVerification {
         Ok(ServerCertVerified::assertion())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Self-signed, CN=app with DNS, URI and email subject alternative names.
+    const CERT: &str = "-----BEGIN CERTIFICATE-----
+MIIBiDCCAS6gAwIBAgIUU93P8vnW6OOhBWrrhzI0oKOW8J8wCgYIKoZIzj0EAwIw
+DjEMMAoGA1UEAwwDYXBwMCAXDTI2MTAxNTE1MjQyMloYDzIxMjYwOTIxMTUyNDIy
+WjAOMQwwCgYDVQQDDANhcHAwWTATBgcqhkjOPQIBBggqhkjOPQMBBwNCAATIXb2Y
+wG2KjmOYhr2aLiUgr1nq+OGNH8wGG+HPrANTr7+9O2eN1h0KBTYZHJluFPlpcbF1
+YqBMaiBx7tp5Os+5o2gwZjBFBgNVHREEPjA8gg9hcHAuZXhhbXBsZS5jb22GGHNw
+aWZmZTovL2V4YW1wbGUuY29tL2FwcIEPYXBwQGV4YW1wbGUuY29tMB0GA1UdDgQW
+BBRij6JeXw/7XZ7iGLMEB97mETKoOjAKBggqhkjOPQQDAgNIADBFAiATyf+S/CLe
+hBoCPpBO9feRqvTYDJ1HbbdwVoETOCSmlQIhAJtVNHk0jnBUmfFQ362h0nXc/G3D
++isObwH/SYykhyqM
+-----END CERTIFICATE-----
+";
+
+    #[test]
+    fn test_certificate_names() {
+        let cert = Certificate(certs(&mut CERT.as_bytes()).unwrap().remove(0));
+
+        assert_eq!(
+            certificate_names(&cert),
+            vec![
+                "app",
+                "app.example.com",
+                "spiffe://example.com/app",
+                "app@example.com"
+            ]
+        );
+
+        assert!(certificate_names(&Certificate(vec![0x30, 0x82, 0xff])).is_empty());
+    }
+}
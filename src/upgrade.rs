@@ -0,0 +1,290 @@
+// Online upgrades. A new pgcat process takes over the listening sockets of the
+// running one through the upgrade socket, with SCM_RIGHTS, and the old one
+// stops accepting clients and drains the connected ones.
+
+use log::{error, info, warn};
+use nix::sys::socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags};
+use std::io::{IoSlice, IoSliceMut};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, Interest};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc;
+
+use crate::errors::Error;
+
+/// How long the running pgcat waits for the new one to be ready.
+const TAKEOVER_TIMEOUT: u64 = 30_000; // 30 seconds
+
+/// Sent by the new pgcat when it's ready to accept clients.
+const READY: u8 = 1;
+
+/// Which listening sockets are sent, after the TCP listener that always is.
+const UNIX_LISTENER: u8 = 1;
+const HTTP_LISTENER: u8 = 2;
+
+/// The listening sockets of the running pgcat.
+pub struct Listeners {
+    pub tcp: RawFd,
+    pub unix: Option<RawFd>,
+    pub http: Option<RawFd>,
+}
+
+/// The listening sockets taken over from the running pgcat.
+pub struct Takeover {
+    stream: UnixStream,
+    pub tcp_listener: std::net::TcpListener,
+    pub unix_listener: Option<std::os::unix::net::UnixListener>,
+    pub http_listener: Option<std::net::TcpListener>,
+}
+
+impl Takeover {
+    /// Connect to the running pgcat and receive its listening sockets.
+    /// Returns None if there is no pgcat listening on the upgrade socket.
+    pub async fn connect(path: &str) -> Result<Option<Takeover>, Error> {
+        match UnixStream::connect(path).await {
+            Ok(stream) => Ok(Some(Takeover::receive(stream).await?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Receive the listening sockets sent by `hand_over`.
+    async fn receive(stream: UnixStream) -> Result<Takeover, Error> {
+        let mut fds = Vec::new();
+        let mut listeners = 0;
+
+        loop {
+            stream.readable().await.map_err(socket_error)?;
+
+            let received = stream.try_io(Interest::READABLE, || {
+                let mut buffer = [0u8; 1];
+                let mut iov = [IoSliceMut::new(&mut buffer)];
+                let mut cmsg_buffer = nix::cmsg_space!([RawFd; 3]);
+
+                let message = recvmsg::<()>(
+                    stream.as_raw_fd(),
+                    &mut iov,
+                    Some(&mut cmsg_buffer),
+                    MsgFlags::MSG_CMSG_CLOEXEC,
+                )
+                .map_err(std::io::Error::from)?;
+
+                for cmsg in message.cmsgs() {
+                    if let ControlMessageOwned::ScmRights(received) = cmsg {
+                        fds.extend(received);
+                    }
+                }
+
+                let bytes = message.bytes;
+                listeners = buffer[0];
+
+                Ok(bytes)
+            });
+
+            match received {
+                Ok(_) => break,
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => continue,
+                Err(err) => return Err(socket_error(err)),
+            }
+        }
+
+        // The TCP listener comes first, then the Unix socket and HTTP ones if they were sent.
+        // The file descriptors were just received and nothing else owns them.
+        let mut fds = fds.into_iter();
+
+        let tcp_listener = match fds.next() {
+            Some(fd) => unsafe { std::net::TcpListener::from_raw_fd(fd) },
+            None => {
+                error!("The running pgcat didn't send its listening socket");
+                return Err(Error::SocketError("No listening socket received".into()));
+            }
+        };
+
+        let unix_listener = match listeners & UNIX_LISTENER {
+            0 => None,
+            _ => fds
+                .next()
+                .map(|fd| unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) }),
+        };
+
+        let http_listener = match listeners & HTTP_LISTENER {
+            0 => None,
+            _ => fds
+                .next()
+                .map(|fd| unsafe { std::net::TcpListener::from_raw_fd(fd) }),
+        };
+
+        tcp_listener.set_nonblocking(true).map_err(socket_error)?;
+
+        if let Some(ref unix_listener) = unix_listener {
+            unix_listener.set_nonblocking(true).map_err(socket_error)?;
+        }
+
+        if let Some(ref http_listener) = http_listener {
+            http_listener.set_nonblocking(true).map_err(socket_error)?;
+        }
+
+        Ok(Takeover {
+            stream,
+            tcp_listener,
+            unix_listener,
+            http_listener,
+        })
+    }
+
+    /// Tell the running pgcat we're ready, so it stops accepting clients and drains them.
+    pub async fn complete(mut self) -> Result<(), Error> {
+        self.stream.write_u8(READY).await.map_err(socket_error)
+    }
+}
+
+/// Listen on the upgrade socket and hand the listening sockets over to the new pgcat
+/// connecting to it. `handoff` is notified once the new pgcat is ready.
+pub fn serve(path: &str, listeners: Listeners, handoff: mpsc::Sender<()>) -> Result<(), Error> {
+    // Left over by a previous run, or by the pgcat we took over from.
+    let _ = std::fs::remove_file(path);
+
+    let listener = match UnixListener::bind(path) {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("Upgrade socket {} error: {:?}", path, err);
+            return Err(socket_error(err));
+        }
+    };
+
+    info!("Upgrade socket: {}", path);
+
+    tokio::task::spawn(async move {
+        loop {
+            let mut stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    error!("Upgrade socket error: {:?}", err);
+                    continue;
+                }
+            };
+
+            info!("A new pgcat is taking over the listening sockets");
+
+            match hand_over(&mut stream, &listeners).await {
+                Ok(()) => {
+                    let _ = handoff.send(()).await;
+                    break;
+                }
+
+                Err(err) => warn!("Takeover failed, still accepting clients: {:?}", err),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Send the listening sockets and wait for the new pgcat to be ready.
+async fn hand_over(stream: &mut UnixStream, listeners: &Listeners) -> Result<(), Error> {
+    let mut fds = vec![listeners.tcp];
+    let mut sent_listeners = 0;
+
+    if let Some(fd) = listeners.unix {
+        fds.push(fd);
+        sent_listeners |= UNIX_LISTENER;
+    }
+
+    if let Some(fd) = listeners.http {
+        fds.push(fd);
+        sent_listeners |= HTTP_LISTENER;
+    }
+
+    loop {
+        stream.writable().await.map_err(socket_error)?;
+
+        let sent = stream.try_io(Interest::WRITABLE, || {
+            sendmsg::<()>(
+                stream.as_raw_fd(),
+                &[IoSlice::new(&[sent_listeners])],
+                &[ControlMessage::ScmRights(&fds)],
+                MsgFlags::empty(),
+                None,
+            )
+            .map_err(std::io::Error::from)
+        });
+
+        match sent {
+            Ok(_) => break,
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => continue,
+            Err(err) => return Err(socket_error(err)),
+        }
+    }
+
+    match tokio::time::timeout(
+        tokio::time::Duration::from_millis(TAKEOVER_TIMEOUT),
+        stream.read_u8(),
+    )
+    .await
+    {
+        Ok(Ok(READY)) => Ok(()),
+        Ok(Ok(_)) => Err(Error::SocketError("Unexpected takeover message".into())),
+        Ok(Err(err)) => Err(socket_error(err)),
+        Err(_) => Err(Error::SocketError("Timed out".into())),
+    }
+}
+
+fn socket_error(err: std::io::Error) -> Error {
+    Error::SocketError(format!("Upgrade socket error: {:?}", err))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_hand_over() {
+        let path = std::env::temp_dir().join(format!("pgcat_upgrade_{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let unix = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let http = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+        for unix_fd in [None, Some(unix.as_raw_fd())] {
+            let listeners = Listeners {
+                tcp: tcp.as_raw_fd(),
+                unix: unix_fd,
+                http: Some(http.as_raw_fd()),
+            };
+
+            let (mut running, new) = UnixStream::pair().unwrap();
+            let handed_over =
+                tokio::task::spawn(async move { hand_over(&mut running, &listeners).await });
+
+            let takeover = Takeover::receive(new).await.unwrap();
+
+            assert_eq!(
+                takeover.tcp_listener.local_addr().unwrap(),
+                tcp.local_addr().unwrap()
+            );
+            assert_eq!(
+                takeover.unix_listener.as_ref().map(|listener| listener
+                    .local_addr()
+                    .unwrap()
+                    .as_pathname()
+                    .unwrap()
+                    .to_owned()),
+                unix_fd.map(|_| path.clone())
+            );
+            assert_eq!(
+                takeover
+                    .http_listener
+                    .as_ref()
+                    .unwrap()
+                    .local_addr()
+                    .unwrap(),
+                http.local_addr().unwrap()
+            );
+
+            takeover.complete().await.unwrap();
+            assert_eq!(handed_over.await.unwrap(), Ok(()));
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}